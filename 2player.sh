@@ -1,9 +1,16 @@
 #!/bin/bash
 
+# Emulate a real network with NET_CONDITIONS (both directions), NET_UP (client
+# to server) or NET_DOWN (server to client). Each takes a preset (lan,
+# broadband, wifi, mobile, terrible) or settings like
+# "latency=80,jitter=20,loss=0.02,duplicate=0.01,reorder=0.01"
+# e.g. NET_CONDITIONS=mobile ./2player.sh
+#
+# Only the client applies them, so they aren't applied twice.
+
 main() {
   cargo build \
   && env CLIENT_DELAY="0.5" \
-    concurrently --kill-others ./server.sh ./client.sh
+    concurrently --kill-others "env -u NET_CONDITIONS -u NET_UP -u NET_DOWN ./server.sh" ./client.sh
 }
 main "$@"
-
@@ -1,4 +1,4 @@
 #!/bin/bash
 
 
-env ENABLE_PHYSICS='false' SERVE_ON="127.0.0.1:3002" CONNECT_TO="127.0.0.1:3001" WINDOW_OFFSET="1500" cargo run
+env RELAY_ON="127.0.0.1:3002" RELAY_TO="127.0.0.1:3001" WINDOW_OFFSET="1500" cargo run
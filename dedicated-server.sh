@@ -0,0 +1,3 @@
+#!/bin/bash
+
+env SERVE_ON="0.0.0.0:3000" ADMIN_ON="${ADMIN_ON:-127.0.0.1:3100}" cargo run --bin dedicated_server
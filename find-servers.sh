@@ -0,0 +1,5 @@
+#!/bin/bash
+
+# Lists the servers on the LAN, including ones on this machine, and connects
+# to whichever one gets clicked
+env FIND_SERVERS="true" WINDOW_OFFSET="1000" cargo run
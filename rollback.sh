@@ -0,0 +1,13 @@
+#!/bin/bash
+
+# Plays a rollback match between two peers, without a server. NET_UP and
+# NET_DOWN apply to what each peer sends and receives.
+# e.g. NET_CONDITIONS=wifi ./rollback.sh
+
+main() {
+  cargo build \
+  && concurrently --kill-others \
+    "env ROLLBACK_HOST=127.0.0.1:3002 PLAYER_NAME=host cargo run" \
+    "env ROLLBACK_JOIN=127.0.0.1:3002 PLAYER_NAME=guest WINDOW_OFFSET=1000 cargo run"
+}
+main "$@"
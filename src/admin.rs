@@ -0,0 +1,304 @@
+use std::{
+    fmt::Display,
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    str::FromStr,
+    thread,
+};
+
+use bevy::reflect::{Reflect, Struct};
+use crossbeam_channel::{Receiver, Sender};
+use serde_json::{json, Value};
+
+use crate::{round::MatchFormat, AppConfig};
+
+const HELP: &str = "clients | rooms | config | kick <client-id> | restart [room-id] | level <path> [room-id] | set <field> <value> | help";
+
+/// The config fields `set` may change while the server runs. The others are
+/// only read at startup, or are the client's own and would go out of step with it.
+const RUNTIME_FIELDS: &[&str] = &[
+    "fudge_factor",
+    "bullet_speed",
+    "player_move_speed",
+    "reload_timeout",
+    "jump_amount",
+    "shield_timeout",
+    "shield_duration",
+    "player_max_move_speed",
+    "player_health",
+    "starting_ammo",
+    "max_lag_compensation",
+    "resume_grace_period",
+    "results_duration",
+    "pick_card_duration",
+    "match_format",
+];
+
+/// Something the host asked the server to do, one per line on stdin or on the admin port
+pub(crate) enum AdminCommand {
+    /// Every client with the room it is in and its round trip time
+    Clients,
+    Rooms,
+    /// Every config field along with its current value
+    Config,
+    Kick {
+        client_id: String,
+    },
+    /// Restarts the round in a room, the lobby if none is given
+    Restart {
+        room_id: Option<String>,
+    },
+    /// Restarts the round in a room on another level
+    Level {
+        level: String,
+        room_id: Option<String>,
+    },
+    /// Sets a config field on the server and in every room, if it can change at runtime
+    Set {
+        field: String,
+        value: String,
+    },
+    Help,
+}
+
+impl FromStr for AdminCommand {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let room_id = |index: usize| words.get(index).map(|word| word.to_string());
+
+        match words.as_slice() {
+            ["clients"] => Ok(AdminCommand::Clients),
+            ["rooms"] => Ok(AdminCommand::Rooms),
+            ["config"] => Ok(AdminCommand::Config),
+            ["kick", client_id] => Ok(AdminCommand::Kick {
+                client_id: client_id.to_string(),
+            }),
+            ["restart"] | ["restart", _] => Ok(AdminCommand::Restart {
+                room_id: room_id(1),
+            }),
+            ["level", level] | ["level", level, _] => Ok(AdminCommand::Level {
+                level: level.to_string(),
+                room_id: room_id(2),
+            }),
+            ["set", field, value] => Ok(AdminCommand::Set {
+                field: field.to_string(),
+                value: value.to_string(),
+            }),
+            ["help"] => Ok(AdminCommand::Help),
+            _ => Err(format!(
+                "Unknown command: {}. Expected one of: {}",
+                line, HELP
+            )),
+        }
+    }
+}
+
+/// A command along with where to send the server's answer to it
+pub(crate) struct AdminRequest {
+    pub(crate) command: AdminCommand,
+    pub(crate) reply: Sender<Value>,
+}
+
+/// An answer to a command that went through. Every answer is a single line of JSON.
+pub(crate) fn ok(mut value: Value) -> Value {
+    value["ok"] = json!(true);
+    value
+}
+
+pub(crate) fn error(message: impl Display) -> Value {
+    json!({ "ok": false, "error": message.to_string() })
+}
+
+pub(crate) fn help() -> Value {
+    ok(json!({ "commands": HELP }))
+}
+
+/// Reads commands from stdin, and from the admin port if there is one. The
+/// admin port only listens on localhost, since anyone who can reach it can
+/// kick players and change the rules.
+pub(crate) fn listen(admin_hostname: Option<String>) -> Receiver<AdminRequest> {
+    let (tx_request, rx_request) = crossbeam_channel::unbounded::<AdminRequest>();
+
+    let tx_stdin = tx_request.clone();
+    thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let line = match line {
+                Err(_) => return,
+                Ok(line) => line,
+            };
+
+            if let Some(reply) = answer(&line, &tx_stdin) {
+                println!("{}", reply);
+            }
+        }
+    });
+
+    let hostname = match admin_hostname {
+        None => return rx_request,
+        Some(hostname) => hostname,
+    };
+
+    let address: SocketAddr = hostname
+        .parse()
+        .expect("Failed to parse ADMIN_ON. Expected an address like 127.0.0.1:3001");
+    if !address.ip().is_loopback() {
+        panic!(
+            "Refusing to open the admin port on {}. It may only listen on localhost",
+            address
+        );
+    }
+
+    let listener = TcpListener::bind(address).unwrap();
+    println!("Listening for admin commands on {}", address);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let tx_request = tx_request.clone();
+                    thread::spawn(move || handle_admin_connection(stream, tx_request));
+                }
+                Err(e) => println!("Failed to accept admin connection: {}", e),
+            };
+        }
+    });
+
+    rx_request
+}
+
+fn handle_admin_connection(mut stream: TcpStream, tx_request: Sender<AdminRequest>) {
+    let reader = match stream.try_clone() {
+        Ok(reader) => BufReader::new(reader),
+        Err(e) => {
+            println!("Failed to clone stream for admin connection: {}", e);
+            return;
+        }
+    };
+
+    for line in reader.lines() {
+        let line = match line {
+            Err(_) => return,
+            Ok(line) => line,
+        };
+
+        let reply = match answer(&line, &tx_request) {
+            None => continue,
+            Some(reply) => reply,
+        };
+
+        if writeln!(stream, "{}", reply).is_err() {
+            return;
+        }
+    }
+}
+
+/// Hands the command on a line to the server and waits for its answer. Blank lines get none.
+fn answer(line: &str, tx_request: &Sender<AdminRequest>) -> Option<Value> {
+    if line.trim().is_empty() {
+        return None;
+    }
+
+    let command = match line.parse::<AdminCommand>() {
+        Err(e) => return Some(error(e)),
+        Ok(command) => command,
+    };
+
+    let (tx_reply, rx_reply) = crossbeam_channel::bounded::<Value>(1);
+    tx_request
+        .send(AdminRequest {
+            command,
+            reply: tx_reply,
+        })
+        .ok()?;
+
+    Some(
+        rx_reply
+            .recv()
+            .unwrap_or_else(|_| error("The server shut down")),
+    )
+}
+
+/// Every config field along with its current value
+pub(crate) fn config_to_json(config: &AppConfig) -> Value {
+    let mut fields = serde_json::Map::new();
+
+    for (index, field) in config.iter_fields().enumerate() {
+        let name = match config.name_at(index) {
+            None => continue,
+            Some(name) => name,
+        };
+
+        let value = field_to_json::<f32>(field)
+            .or_else(|| field_to_json::<f64>(field))
+            .or_else(|| field_to_json::<u64>(field))
+            .or_else(|| field_to_json::<u32>(field))
+            .or_else(|| field_to_json::<i32>(field))
+            .or_else(|| field_to_json::<String>(field))
+            .or_else(|| display_to_json::<MatchFormat>(field))
+            .unwrap_or(Value::Null);
+
+        fields.insert(name.to_string(), value);
+    }
+
+    Value::Object(fields)
+}
+
+fn field_to_json<T: Reflect + Clone + Into<Value>>(field: &dyn Reflect) -> Option<Value> {
+    field.downcast_ref::<T>().map(|value| value.clone().into())
+}
+
+fn display_to_json<T: Reflect + Display>(field: &dyn Reflect) -> Option<Value> {
+    field
+        .downcast_ref::<T>()
+        .map(|value| value.to_string().into())
+}
+
+/// Parses the value as whatever type the config field has and sets it
+pub(crate) fn set_config_field(
+    config: &mut AppConfig,
+    field: &str,
+    value: &str,
+) -> Result<(), String> {
+    let target = match config.field_mut(field) {
+        None => return Err(format!("No config field named {}", field)),
+        Some(target) => target,
+    };
+
+    if field == "level" {
+        return Err("Use the level command to switch levels".to_string());
+    }
+
+    if !RUNTIME_FIELDS.contains(&field) {
+        return Err(format!("Config field {} can only be set at startup", field));
+    }
+
+    let parsed = parse_into::<f32>(target, value)
+        .or_else(|| parse_into::<f64>(target, value))
+        .or_else(|| parse_into::<u64>(target, value))
+        .or_else(|| parse_into::<u32>(target, value))
+        .or_else(|| parse_into::<i32>(target, value))
+        .or_else(|| parse_into::<String>(target, value))
+        .or_else(|| parse_into::<MatchFormat>(target, value));
+
+    match parsed {
+        None => Err(format!("Config field {} can't be set", field)),
+        Some(parsed) => parsed.map_err(|e| format!("Invalid value for {}: {}", field, e)),
+    }
+}
+
+/// Nothing if the target is not a T
+fn parse_into<T: Reflect + FromStr>(
+    target: &mut dyn Reflect,
+    value: &str,
+) -> Option<Result<(), String>>
+where
+    T::Err: Display,
+{
+    let target = target.downcast_mut::<T>()?;
+
+    Some(value.parse::<T>().map_err(|e| e.to_string()).map(|value| {
+        *target = value;
+    }))
+}
@@ -0,0 +1,37 @@
+use std::time::Duration;
+
+use bevy::{app::ScheduleRunnerPlugin, prelude::*};
+
+use animated_couscous::{AppConfig, GameState, ManageStatePlugin, NetworkConditions, ServerPlugin};
+
+/// Hosts a match without a window, so it can run on a machine with no display
+fn main() {
+    let hostname = std::env::var("SERVE_ON").unwrap_or("0.0.0.0:3000".to_string());
+
+    let mut server = ServerPlugin::serve_on(hostname);
+    if let Ok(name) = std::env::var("SERVER_NAME") {
+        server = server.named(name);
+    }
+    if let Ok(admin_hostname) = std::env::var("ADMIN_ON") {
+        server = server.admin_on(admin_hostname);
+    }
+
+    let config = AppConfig::from_env();
+    let tick_rate = config.tick_rate;
+
+    App::new()
+        .add_plugins(
+            MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
+                1. / tick_rate,
+            ))),
+        )
+        .add_plugins((TransformPlugin, HierarchyPlugin))
+        .insert_resource(config)
+        .insert_resource(Time::<Fixed>::from_hz(tick_rate))
+        .insert_resource(NetworkConditions::from_env())
+        .register_type::<AppConfig>()
+        .insert_state(GameState::Round)
+        .add_plugins(ManageStatePlugin::with_physics(true))
+        .add_plugins(server)
+        .run();
+}
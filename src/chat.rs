@@ -0,0 +1,164 @@
+use bevy::{prelude::*, window::ReceivedCharacter};
+
+use crate::{
+    events::{ChatMessageEvent, SendChatEvent},
+    protos::MAX_CHAT_LENGTH,
+};
+
+/// How many of the latest chat messages stay on screen
+const VISIBLE_MESSAGES: usize = 8;
+
+/// Opens the chat box. Enter sends what we typed, Escape throws it away.
+const CHAT_KEY: KeyCode = KeyCode::KeyT;
+
+/// Shows what everyone in our room says and lets us say something back.
+/// While we type, the gameplay bindings in `InputPlugin` are let go.
+pub struct ChatPlugin;
+
+impl Plugin for ChatPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<SendChatEvent>()
+            .add_event::<ChatMessageEvent>()
+            .init_resource::<ChatInput>()
+            .init_resource::<ChatLog>()
+            .add_systems(Startup, setup)
+            .add_systems(
+                Update,
+                (type_chat_message, record_chat_messages, render_chat).chain(),
+            );
+    }
+}
+
+/// What we are typing into the chat box, while it is open
+#[derive(Resource, Default)]
+pub(crate) struct ChatInput {
+    pub(crate) open: bool,
+    text: String,
+}
+
+/// Whether the gameplay bindings should be listened to, which they shouldn't
+/// while we are typing a chat message
+pub(crate) fn not_typing(chat_input: Option<Res<ChatInput>>) -> bool {
+    !chat_input.is_some_and(|chat_input| chat_input.open)
+}
+
+/// The latest chat messages, oldest first
+#[derive(Resource, Default, Deref, DerefMut)]
+struct ChatLog(Vec<ChatMessageEvent>);
+
+#[derive(Component)]
+struct ChatLogDisplay;
+
+#[derive(Component)]
+struct ChatInputDisplay;
+
+fn setup(mut commands: Commands) {
+    let style = TextStyle {
+        font_size: 18.,
+        color: Color::WHITE,
+        ..default()
+    };
+
+    commands
+        .spawn(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                left: Val::Px(10.),
+                bottom: Val::Px(10.),
+                width: Val::Px(400.),
+                flex_direction: FlexDirection::Column,
+                ..default()
+            },
+            ..default()
+        })
+        .with_children(|parent| {
+            parent.spawn((ChatLogDisplay, TextBundle::from_section("", style.clone())));
+            parent.spawn((ChatInputDisplay, TextBundle::from_section("", style)));
+        });
+}
+
+fn type_chat_message(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut characters: EventReader<ReceivedCharacter>,
+    mut chat_input: ResMut<ChatInput>,
+    mut events: EventWriter<SendChatEvent>,
+) {
+    if !chat_input.open {
+        // the key that opens the chat box shouldn't end up in it
+        characters.clear();
+
+        if keyboard_input.just_pressed(CHAT_KEY) {
+            chat_input.open = true;
+        }
+        return;
+    }
+
+    if keyboard_input.just_pressed(KeyCode::Escape) {
+        *chat_input = ChatInput::default();
+        characters.clear();
+        return;
+    }
+
+    if keyboard_input.just_pressed(KeyCode::Enter) {
+        let text = chat_input.text.trim().to_string();
+        if !text.is_empty() {
+            events.send(SendChatEvent { text });
+        }
+
+        *chat_input = ChatInput::default();
+        characters.clear();
+        return;
+    }
+
+    if keyboard_input.just_pressed(KeyCode::Backspace) {
+        chat_input.text.pop();
+    }
+
+    for character in characters.read() {
+        for c in character.char.chars().filter(|c| !c.is_control()) {
+            if chat_input.text.chars().count() < MAX_CHAT_LENGTH {
+                chat_input.text.push(c);
+            }
+        }
+    }
+}
+
+fn record_chat_messages(mut events: EventReader<ChatMessageEvent>, mut log: ResMut<ChatLog>) {
+    for event in events.read() {
+        log.push(event.clone());
+    }
+
+    if log.len() > VISIBLE_MESSAGES {
+        let overflow = log.len() - VISIBLE_MESSAGES;
+        log.drain(..overflow);
+    }
+}
+
+fn render_chat(
+    chat_input: Res<ChatInput>,
+    log: Res<ChatLog>,
+    mut log_displays: Query<&mut Text, (With<ChatLogDisplay>, Without<ChatInputDisplay>)>,
+    mut input_displays: Query<&mut Text, (With<ChatInputDisplay>, Without<ChatLogDisplay>)>,
+) {
+    if log.is_changed() {
+        for mut text in log_displays.iter_mut() {
+            text.sections[0].value = log
+                .iter()
+                .map(|message| match message.notice {
+                    true => format!("[{}] {}", message.author(), message.text),
+                    false => format!("{}: {}", message.author(), message.text),
+                })
+                .collect::<Vec<String>>()
+                .join("\n");
+        }
+    }
+
+    if chat_input.is_changed() {
+        for mut text in input_displays.iter_mut() {
+            text.sections[0].value = match chat_input.open {
+                true => format!("> {}_", chat_input.text),
+                false => "".to_string(),
+            };
+        }
+    }
+}
@@ -1,26 +1,81 @@
-use std::{net::TcpStream, thread};
+use std::{
+    net::{Shutdown, TcpStream},
+    thread,
+    time::Duration,
+};
 
-use bevy::prelude::*;
+use bevy::{app::AppExit, prelude::*, utils::HashMap};
 use crossbeam_channel::{Receiver, Sender};
 use protobuf::{CodedInputStream, Message};
 
 use crate::{
+    conditioner::{self, NetworkConditions},
     events::{
-        PlayerBlockEvent, PlayerJumpEvent, PlayerMoveLeftEvent, PlayerMoveRightEvent,
-        PlayerShootEvent, PlayerSpawnEvent,
+        CardPickedEvent, ChatMessageEvent, ConnectToServerEvent, GameEvent, LevelEvent,
+        PickedCardsEvent, PlayerInputEvent, PlayerSpawnEvent, RematchEvent, RoomJoinedEvent,
+        RoomListEvent, RoomRequestEvent, RoundStatusEvent, SendChatEvent,
     },
-    manage_state::GameStateEvent,
-    protos::generated::applesauce,
+    manage_state::{GameStateEvent, NetworkId},
+    protos::{generated::applesauce, BUILD_ID, PROTOCOL_VERSION},
     AppConfig,
 };
 
-pub(crate) struct ClientPlugin {
-    hostname: String,
+/// How long to wait before trying to reach the server again
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+pub struct ClientPlugin {
+    /// None until a server gets picked from the server list
+    hostname: Option<String>,
+    /// Which room to go to once we are connected. Without one we stay in the lobby.
+    room_request: Option<RoomRequestEvent>,
+    spectator: bool,
 }
 
 impl ClientPlugin {
-    pub(crate) fn connect_to(hostname: String) -> Self {
-        Self { hostname }
+    pub fn connect_to(hostname: String) -> Self {
+        Self {
+            hostname: Some(hostname),
+            room_request: None,
+            spectator: false,
+        }
+    }
+
+    /// Waits for a server to be picked, like from the `ServerListPlugin`, before connecting
+    pub fn connect_to_picked_server() -> Self {
+        Self {
+            hostname: None,
+            room_request: None,
+            spectator: false,
+        }
+    }
+
+    /// Watches the game instead of playing in it. The server ignores our inputs.
+    pub fn spectate(mut self) -> Self {
+        self.spectator = true;
+        self
+    }
+
+    /// Opens a new room on the server and plays in it, on one of the server's
+    /// levels if given one
+    pub fn create_room(mut self, name: String, private: bool, level: Option<String>) -> Self {
+        self.room_request = Some(RoomRequestEvent::Create {
+            name,
+            private,
+            level,
+        });
+        self
+    }
+
+    /// Plays in the public room with this id
+    pub fn join_room(mut self, room_id: String) -> Self {
+        self.room_request = Some(RoomRequestEvent::JoinById(room_id));
+        self
+    }
+
+    /// Plays in the room, public or private, with this join code
+    pub fn join_code(mut self, join_code: String) -> Self {
+        self.room_request = Some(RoomRequestEvent::JoinByCode(join_code));
+        self
     }
 }
 
@@ -28,17 +83,40 @@ impl Plugin for ClientPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(ClientConfig {
             hostname: self.hostname.clone(),
+            room_request: self.room_request.clone(),
+            spectator: self.spectator,
         })
         .insert_resource(LatestEventTime(None))
-        .add_systems(Startup, connect_to_server)
+        .init_resource::<ReportedRoundTripTimes>()
+        .init_resource::<Roster>()
+        .init_resource::<NetworkConditions>()
         .add_event::<GameStateEvent>()
+        .add_event::<RoomRequestEvent>()
+        .add_event::<RoomListEvent>()
+        .add_event::<RoomJoinedEvent>()
+        .add_event::<GameEvent>()
+        .add_event::<SendChatEvent>()
+        .add_event::<ChatMessageEvent>()
+        .add_event::<RoundStatusEvent>()
+        .add_event::<RematchEvent>()
+        .add_event::<CardPickedEvent>()
+        .add_event::<PickedCardsEvent>()
+        .add_event::<ConnectToServerEvent>()
+        .add_systems(Startup, connect_to_configured_server)
+        .add_systems(PreUpdate, connect_to_server)
         .add_systems(
             Update,
             (
-                proxy_game_state_from_network,
+                proxy_messages_from_network,
                 write_inputs_to_network,
+                write_room_requests_to_network,
+                write_chat_to_network,
                 update_client_id_from_identity,
-            ),
+                exit_on_reject,
+                log_rooms,
+                record_round_trip_times,
+            )
+                .run_if(resource_exists::<SendClientMessage>),
         );
     }
 }
@@ -48,63 +126,309 @@ struct Despawn;
 
 #[derive(Resource)]
 struct ClientConfig {
-    hostname: String,
+    hostname: Option<String>,
+    room_request: Option<RoomRequestEvent>,
+    spectator: bool,
 }
 
 #[derive(Resource, Deref, DerefMut)]
 struct LatestEventTime(Option<u64>);
 
+/// The round trip time the server measured to each player's client in
+/// milliseconds, as of the latest game state. Keyed by the player's network id.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct ReportedRoundTripTimes(HashMap<NetworkId, u32>);
+
+/// Whose each player in our room is, keyed by the player's network id. Game
+/// states only carry the network ids.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct Roster(pub(crate) HashMap<NetworkId, RosterEntry>);
+
+pub(crate) struct RosterEntry {
+    pub(crate) client_id: String,
+    /// Empty for players whose client never told the server its name
+    pub(crate) display_name: String,
+    pub(crate) spawn_id: String,
+}
+
+impl Roster {
+    /// What the player of the client goes by, if it has a name and is in our room
+    pub(crate) fn display_name(&self, client_id: &str) -> Option<&str> {
+        self.values()
+            .find(|entry| entry.client_id == client_id)
+            .map(|entry| entry.display_name.as_str())
+            .filter(|display_name| !display_name.is_empty())
+    }
+}
+
+/// Everything the server sends after the handshake, in the order it was sent
 #[derive(Resource, Deref)]
-struct ReceiveGameState(Receiver<applesauce::GameState>);
+struct ReceiveServerMessage(Receiver<applesauce::server_message::Inner>);
 
 #[derive(Resource, Deref)]
 struct ReceiveIdentity(Receiver<applesauce::Identity>);
 
 #[derive(Resource, Deref)]
-struct SendInput(Sender<applesauce::Input>);
+struct ReceiveReject(Receiver<String>);
+
+#[derive(Resource, Deref)]
+struct SendClientMessage(Sender<applesauce::client_message::Inner>);
+
+/// The ends of the channels the connection thread uses to talk to the rest of the client
+struct ConnectionChannels {
+    tx_identity: Sender<applesauce::Identity>,
+    tx_reject: Sender<String>,
+    tx_server_message: Sender<applesauce::server_message::Inner>,
+    /// Lets the connection answer pings without waiting for a frame
+    tx_client_message: Sender<applesauce::client_message::Inner>,
+    rx_client_message: Receiver<applesauce::client_message::Inner>,
+}
+
+fn connect_to_configured_server(
+    config: Res<ClientConfig>,
+    mut events: EventWriter<ConnectToServerEvent>,
+) {
+    if let Some(hostname) = &config.hostname {
+        events.send(ConnectToServerEvent {
+            hostname: hostname.to_string(),
+        });
+    }
+}
+
+fn connect_to_server(
+    mut commands: Commands,
+    mut events: EventReader<ConnectToServerEvent>,
+    config: Res<ClientConfig>,
+    app_config: Res<AppConfig>,
+    conditions: Res<NetworkConditions>,
+    connected: Option<Res<SendClientMessage>>,
+    mut room_requests: EventWriter<RoomRequestEvent>,
+) {
+    let hostname = match events.read().last() {
+        None => return,
+        Some(event) => event.hostname.to_string(),
+    };
+
+    if connected.is_some() {
+        println!("Already connected. Not connecting to {}", hostname);
+        return;
+    }
 
-fn connect_to_server(mut commands: Commands, config: Res<ClientConfig>) {
-    let stream = TcpStream::connect(config.hostname.clone()).unwrap();
     let (tx_identity, rx_identity) = crossbeam_channel::unbounded::<applesauce::Identity>();
-    let (tx_game_state, rx_game_state) = crossbeam_channel::unbounded::<applesauce::GameState>();
-    let (tx_input, rx_input) = crossbeam_channel::unbounded::<applesauce::Input>();
+    let (tx_reject, rx_reject) = crossbeam_channel::unbounded::<String>();
+
+    // snapshots and input states are superseded by the next one, so they are
+    // the messages we could afford to send over an unreliable transport
+    let (tx_server_message, rx_server_message) =
+        crossbeam_channel::unbounded::<applesauce::server_message::Inner>();
+    let rx_server_message =
+        conditioner::condition(rx_server_message, conditions.downstream, |inner| {
+            matches!(inner, applesauce::server_message::Inner::GameState(_))
+        });
+
+    let (tx_client_message, rx_client_message) =
+        crossbeam_channel::unbounded::<applesauce::client_message::Inner>();
+    let rx_client_message =
+        conditioner::condition(rx_client_message, conditions.upstream, |inner| {
+            matches!(
+                inner,
+                applesauce::client_message::Inner::Input(applesauce::Input {
+                    inner: Some(applesauce::input::Inner::State(_)),
+                    ..
+                })
+            )
+        });
 
     commands.insert_resource(ReceiveIdentity(rx_identity));
-    commands.insert_resource(ReceiveGameState(rx_game_state));
-    commands.insert_resource(SendInput(tx_input));
+    commands.insert_resource(ReceiveReject(rx_reject));
+    commands.insert_resource(ReceiveServerMessage(rx_server_message));
+    commands.insert_resource(SendClientMessage(tx_client_message.clone()));
 
-    let mut recv_stream = stream.try_clone().unwrap();
-    thread::spawn(move || {
-        let mut coded_stream = CodedInputStream::new(&mut recv_stream);
+    let channels = ConnectionChannels {
+        tx_identity,
+        tx_reject,
+        tx_server_message,
+        tx_client_message,
+        rx_client_message,
+    };
+    let display_name = app_config.display_name.to_string();
+    let spectator = config.spectator;
 
-        // the first message is an Identity message letting us know what our client id is
-        let identity: applesauce::Identity = coded_stream.read_message().unwrap();
-        tx_identity.send(identity).unwrap();
+    thread::spawn(move || {
+        // empty until the server gives us an identity we can come back to
+        let mut resume_token = String::new();
 
         loop {
-            if coded_stream.eof().unwrap() {
-                break;
+            if let Err(reason) = run_connection(
+                &hostname,
+                &display_name,
+                spectator,
+                &mut resume_token,
+                &channels,
+            ) {
+                channels.tx_reject.send(reason).ok();
+                return;
             }
 
-            let game_state: applesauce::GameState = coded_stream.read_message().unwrap();
-            tx_game_state.send(game_state).unwrap();
+            println!("Lost the connection to the server. Reconnecting");
+            thread::sleep(RECONNECT_DELAY);
         }
     });
 
-    let mut send_stream = stream.try_clone().unwrap();
-    thread::spawn(move || loop {
-        let input = rx_input.recv().unwrap();
-        input
+    room_requests.send(RoomRequestEvent::List);
+    if let Some(request) = &config.room_request {
+        room_requests.send(request.clone());
+    }
+}
+
+/// Talks to the server until the connection is lost, which is worth trying
+/// again. Fails if the server turned us away, which is not.
+fn run_connection(
+    hostname: &str,
+    display_name: &str,
+    spectator: bool,
+    resume_token: &mut String,
+    channels: &ConnectionChannels,
+) -> Result<(), String> {
+    let reconnecting = !resume_token.is_empty();
+
+    let mut stream = match TcpStream::connect(hostname) {
+        Ok(stream) => stream,
+        Err(e) => {
+            println!("Failed to connect to {}: {}", hostname, e);
+            return Ok(());
+        }
+    };
+
+    let hello = applesauce::ClientMessage {
+        inner: Some(applesauce::client_message::Inner::Hello(
+            applesauce::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                build_id: BUILD_ID.to_string(),
+                display_name: display_name.to_string(),
+                resume_token: resume_token.to_string(),
+                spectator,
+                special_fields: default(),
+            },
+        )),
+        special_fields: default(),
+    };
+
+    let (mut recv_stream, mut send_stream) = match (stream.try_clone(), stream.try_clone()) {
+        (Ok(recv_stream), Ok(send_stream)) => (recv_stream, send_stream),
+        _ => return Ok(()),
+    };
+
+    if let Err(e) = hello.write_length_delimited_to_writer(&mut stream) {
+        println!("Failed to say hello to the server: {}", e);
+        return Ok(());
+    }
+
+    let mut coded_stream = CodedInputStream::new(&mut recv_stream);
+
+    // the first message is the server's answer to our Hello. If it welcomes
+    // us, it lets us know what our client id is
+    let message: applesauce::ServerMessage = match coded_stream.read_message() {
+        Ok(message) => message,
+        Err(e) => {
+            println!("Failed to read the server's answer to our hello: {}", e);
+            return Ok(());
+        }
+    };
+
+    match message.inner {
+        Some(applesauce::server_message::Inner::Welcome(welcome)) => {
+            let identity = welcome.identity.unwrap_or_default();
+            *resume_token = identity.resume_token.to_string();
+            channels.tx_identity.send(identity).ok();
+        }
+        Some(applesauce::server_message::Inner::Reject(reject)) => return Err(reject.reason),
+        _ => return Err("Server did not answer the handshake".to_string()),
+    }
+
+    // whatever piled up while we were away is stale by now
+    if reconnecting {
+        while channels.rx_client_message.try_recv().is_ok() {}
+    }
+
+    // The next connection's send thread has to be the only one taking our
+    // messages, so this one gets told to stop and is waited for before we return
+    let (tx_stop, rx_stop) = crossbeam_channel::bounded::<()>(0);
+    let rx_client_message = channels.rx_client_message.clone();
+    let send_thread = thread::spawn(move || loop {
+        let inner = crossbeam_channel::select! {
+            recv(rx_client_message) -> inner => match inner {
+                Err(_) => break,
+                Ok(inner) => inner,
+            },
+            recv(rx_stop) -> _ => break,
+        };
+
+        let message = applesauce::ClientMessage {
+            inner: Some(inner),
+            special_fields: default(),
+        };
+
+        if message
             .write_length_delimited_to_writer(&mut send_stream)
-            .unwrap();
+            .is_err()
+        {
+            break;
+        }
     });
+
+    let result = loop {
+        match coded_stream.eof() {
+            Ok(false) => {}
+            Ok(true) => break Ok(()),
+            Err(e) => {
+                println!("Failed to read from the server: {}", e);
+                break Ok(());
+            }
+        }
+
+        let message: applesauce::ServerMessage = match coded_stream.read_message() {
+            Ok(message) => message,
+            Err(e) => {
+                println!("Failed to read message from the server: {}", e);
+                break Ok(());
+            }
+        };
+
+        let sent = match message.inner {
+            None => continue,
+            // being turned away after the handshake means we were kicked
+            Some(applesauce::server_message::Inner::Reject(reject)) => break Err(reject.reason),
+            Some(applesauce::server_message::Inner::Ping(ping)) => channels
+                .tx_client_message
+                .send(applesauce::client_message::Inner::Pong(applesauce::Pong {
+                    sent_at: ping.sent_at,
+                    special_fields: default(),
+                }))
+                .is_ok(),
+            Some(inner) => channels.tx_server_message.send(inner).is_ok(),
+        };
+
+        if !sent {
+            break Ok(());
+        }
+    };
+
+    drop(coded_stream);
+    // make sure the send thread notices as well, even while it's writing
+    stream.shutdown(Shutdown::Both).ok();
+    drop(tx_stop);
+    send_thread.join().ok();
+    result
 }
 
 fn update_client_id_from_identity(
     identity: Res<ReceiveIdentity>,
     mut app_config: ResMut<AppConfig>,
 ) {
-    match identity.try_iter().next() {
+    // we get a new identity whenever we reconnect, which is the same one
+    // unless the server already gave up on us
+    match identity.try_iter().last() {
         None => return,
         Some(identity) => {
             app_config.client_id = identity.client_id;
@@ -112,56 +436,181 @@ fn update_client_id_from_identity(
     }
 }
 
-fn proxy_game_state_from_network(
-    receiver: Res<ReceiveGameState>,
+fn exit_on_reject(reject: Res<ReceiveReject>, mut exit: EventWriter<AppExit>) {
+    if let Some(reason) = reject.try_iter().next() {
+        println!("The server rejected us: {}", reason);
+        exit.send(AppExit);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn proxy_messages_from_network(
+    receiver: Res<ReceiveServerMessage>,
     mut events: EventWriter<GameStateEvent>,
+    mut room_list_events: EventWriter<RoomListEvent>,
+    mut room_joined_events: EventWriter<RoomJoinedEvent>,
+    mut game_events: EventWriter<GameEvent>,
+    mut chat_events: EventWriter<ChatMessageEvent>,
+    mut round_status_events: EventWriter<RoundStatusEvent>,
+    mut picked_cards_events: EventWriter<PickedCardsEvent>,
+    mut level_events: EventWriter<LevelEvent>,
     mut latest_event_time: ResMut<LatestEventTime>,
+    mut roster: ResMut<Roster>,
 ) {
-    match receiver
-        .try_iter()
-        .max_by(|a, b| a.timestamp.cmp(&b.timestamp))
-    {
-        None => return,
-        Some(game_state) => {
-            if game_state.timestamp <= *latest_event_time.get_or_insert(0) {
-                return;
+    let mut game_states: Vec<applesauce::GameState> = vec![];
+
+    for message in receiver.try_iter() {
+        match message {
+            applesauce::server_message::Inner::GameState(game_state) => {
+                game_states.push(game_state);
+            }
+            applesauce::server_message::Inner::RoomJoined(room_joined) => {
+                // whatever came before is from the room we just left, and the
+                // new room's timestamps start over
+                game_states.clear();
+                latest_event_time.take();
+                roster.clear();
+
+                room_joined_events.send(RoomJoinedEvent {
+                    room: room_joined.room.unwrap_or_default().into(),
+                    join_code: Some(room_joined.join_code).filter(|code| !code.is_empty()),
+                });
+            }
+            applesauce::server_message::Inner::RoomList(room_list) => {
+                room_list_events.send(RoomListEvent {
+                    rooms: room_list.rooms.into_iter().map(Into::into).collect(),
+                });
+            }
+            applesauce::server_message::Inner::JoinRoomFailed(failed) => {
+                println!("Could not join room: {}", failed.reason);
+            }
+            applesauce::server_message::Inner::GameEvent(game_event) => {
+                if let Some(inner) = game_event.inner {
+                    game_events.send(inner.into());
+                }
+            }
+            applesauce::server_message::Inner::Roster(new_roster) => {
+                *roster = new_roster.into();
+            }
+            applesauce::server_message::Inner::RoundStatus(round_status) => {
+                round_status_events.send(round_status.into());
+            }
+            applesauce::server_message::Inner::PickedCards(picked_cards) => {
+                picked_cards_events.send(picked_cards.into());
+            }
+            applesauce::server_message::Inner::Level(level) => {
+                level_events.send(LevelEvent { path: level.path });
+            }
+            applesauce::server_message::Inner::ChatMessage(chat_message) => {
+                chat_events.send(ChatMessageEvent {
+                    client_id: chat_message.client_id,
+                    display_name: chat_message.display_name,
+                    text: chat_message.text,
+                    notice: chat_message.notice,
+                });
             }
-            latest_event_time.replace(game_state.timestamp);
-            events.send(game_state.into());
+            applesauce::server_message::Inner::Welcome(_)
+            | applesauce::server_message::Inner::Reject(_)
+            | applesauce::server_message::Inner::Ping(_) => {}
         }
     }
+
+    game_states.sort_by_key(|game_state| game_state.timestamp);
+
+    for game_state in game_states {
+        if game_state.timestamp <= *latest_event_time.get_or_insert(0) {
+            continue;
+        }
+        latest_event_time.replace(game_state.timestamp);
+        events.send(GameStateEvent::from_proto(game_state, &roster));
+    }
 }
 
 fn write_inputs_to_network(
-    sender: Res<SendInput>,
+    sender: Res<SendClientMessage>,
     mut spawn_events: EventReader<PlayerSpawnEvent>,
-    mut move_left_events: EventReader<PlayerMoveLeftEvent>,
-    mut move_right_events: EventReader<PlayerMoveRightEvent>,
-    mut jump_events: EventReader<PlayerJumpEvent>,
-    mut shoot_events: EventReader<PlayerShootEvent>,
-    mut block_events: EventReader<PlayerBlockEvent>,
+    mut input_events: EventReader<PlayerInputEvent>,
+    mut rematch_events: EventReader<RematchEvent>,
+    mut card_events: EventReader<CardPickedEvent>,
 ) {
+    let send = |input: applesauce::Input| {
+        sender
+            .send(applesauce::client_message::Inner::Input(input))
+            .unwrap();
+    };
+
     for event in spawn_events.read() {
-        sender.send(event.into()).unwrap();
+        send(event.into());
     }
 
-    for event in move_left_events.read() {
-        sender.send(event.into()).unwrap();
+    for event in input_events.read() {
+        send(event.into());
     }
 
-    for event in move_right_events.read() {
-        sender.send(event.into()).unwrap();
+    for event in rematch_events.read() {
+        send(event.into());
     }
 
-    for event in jump_events.read() {
-        sender.send(event.into()).unwrap();
+    for event in card_events.read() {
+        send(event.into());
     }
+}
 
-    for event in shoot_events.read() {
-        sender.send(event.into()).unwrap();
+fn write_chat_to_network(sender: Res<SendClientMessage>, mut events: EventReader<SendChatEvent>) {
+    for event in events.read() {
+        sender
+            .send(applesauce::client_message::Inner::ChatMessage(
+                applesauce::ChatMessage {
+                    text: event.text.to_string(),
+                    ..default()
+                },
+            ))
+            .unwrap();
     }
+}
 
-    for event in block_events.read() {
+fn write_room_requests_to_network(
+    sender: Res<SendClientMessage>,
+    mut events: EventReader<RoomRequestEvent>,
+) {
+    for event in events.read() {
         sender.send(event.into()).unwrap();
     }
 }
+
+fn log_rooms(
+    mut room_list_events: EventReader<RoomListEvent>,
+    mut room_joined_events: EventReader<RoomJoinedEvent>,
+) {
+    for event in room_list_events.read() {
+        println!("Open rooms:");
+        for room in event.rooms.iter() {
+            println!(
+                "  {} ({} players). room-id: {}",
+                room.name, room.player_count, room.id
+            );
+        }
+    }
+
+    for event in room_joined_events.read() {
+        match &event.join_code {
+            None => println!(
+                "Joined room {}. room-id: {}",
+                event.room.name, event.room.id
+            ),
+            Some(join_code) => println!(
+                "Joined private room {}. join-code: {}",
+                event.room.name, join_code
+            ),
+        }
+    }
+}
+
+fn record_round_trip_times(
+    mut events: EventReader<GameStateEvent>,
+    mut round_trip_times: ResMut<ReportedRoundTripTimes>,
+) {
+    if let Some(latest) = events.read().max_by_key(|game_state| game_state.timestamp) {
+        round_trip_times.0 = latest.round_trip_times.clone();
+    }
+}
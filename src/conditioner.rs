@@ -0,0 +1,360 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+
+/// How long an unreliable message waits for the next one to overtake it.
+/// It goes on its way alone when nothing else is sent in the meantime.
+const MAX_HOLD_BACK: Duration = Duration::from_millis(100);
+
+/// How messages going one way over a connection get mistreated on purpose
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkConditions {
+    /// How long every message takes to arrive, in milliseconds
+    pub latency: u64,
+    /// How much the latency varies either way, in milliseconds
+    pub jitter: u64,
+    /// The chance an unreliable message never arrives
+    pub loss: f64,
+    /// The chance an unreliable message arrives twice
+    pub duplicate: f64,
+    /// The chance an unreliable message is overtaken by the one sent after it
+    pub reorder: f64,
+}
+
+impl LinkConditions {
+    fn is_perfect(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Either the name of a preset, or settings like `latency=80,jitter=20,loss=0.02`
+impl FromStr for LinkConditions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let preset = |latency, jitter, loss, duplicate, reorder| {
+            Ok(Self {
+                latency,
+                jitter,
+                loss,
+                duplicate,
+                reorder,
+            })
+        };
+
+        match s.trim() {
+            "" | "perfect" => return Ok(Self::default()),
+            "lan" => return preset(1, 1, 0., 0., 0.),
+            "broadband" => return preset(20, 5, 0.005, 0., 0.),
+            "wifi" => return preset(40, 15, 0.01, 0., 0.005),
+            "mobile" => return preset(80, 40, 0.03, 0.01, 0.02),
+            "terrible" => return preset(200, 100, 0.1, 0.05, 0.05),
+            _ => {}
+        }
+
+        let mut conditions = Self::default();
+
+        for setting in s.split(',') {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or(format!("Expected key=value, got '{}'", setting))?;
+
+            let value: f64 = value
+                .trim()
+                .parse()
+                .or(Err(format!("Invalid value for {}: '{}'", key, value)))?;
+
+            match key.trim() {
+                "latency" => conditions.latency = value as u64,
+                "jitter" => conditions.jitter = value as u64,
+                "loss" => conditions.loss = value,
+                "duplicate" => conditions.duplicate = value,
+                "reorder" => conditions.reorder = value,
+                key => return Err(format!("Unknown setting '{}'", key)),
+            }
+        }
+
+        Ok(conditions)
+    }
+}
+
+/// Makes the network look worse than it is, so laggy matches can be played
+/// over loopback. Upstream is from clients to the server, downstream the other way.
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct NetworkConditions {
+    pub upstream: LinkConditions,
+    pub downstream: LinkConditions,
+}
+
+impl NetworkConditions {
+    /// NET_CONDITIONS sets both directions, NET_UP and NET_DOWN override one of them
+    pub fn from_env() -> Self {
+        let both = std::env::var("NET_CONDITIONS").unwrap_or_default();
+
+        let parse = |name: &str| -> LinkConditions {
+            std::env::var(name)
+                .unwrap_or(both.clone())
+                .parse()
+                .unwrap_or_else(|e| panic!("Failed to parse {}: {}", name, e))
+        };
+
+        Self {
+            upstream: parse("NET_UP"),
+            downstream: parse("NET_DOWN"),
+        }
+    }
+}
+
+/// Passes messages from `input` on to the returned receiver as if they went
+/// over a network with the given conditions. Only messages `unreliable`
+/// returns true for may be lost, duplicated or reordered. The others keep
+/// their order, like they would on a TCP stream.
+pub(crate) fn condition<T: Clone + Send + 'static>(
+    input: Receiver<T>,
+    conditions: LinkConditions,
+    unreliable: fn(&T) -> bool,
+) -> Receiver<T> {
+    if conditions.is_perfect() {
+        return input;
+    }
+
+    let (tx_output, rx_output) = crossbeam_channel::unbounded::<T>();
+
+    thread::spawn(move || {
+        let mut link = Link::new(conditions);
+        let mut disconnected = false;
+
+        loop {
+            let received = match (disconnected, link.next_due()) {
+                (true, None) => return,
+                (true, Some(due)) => {
+                    thread::sleep(due.saturating_duration_since(Instant::now()));
+                    Err(RecvTimeoutError::Timeout)
+                }
+                (false, None) => input.recv().or(Err(RecvTimeoutError::Disconnected)),
+                (false, Some(due)) => input.recv_deadline(due),
+            };
+
+            match received {
+                Ok(message) => {
+                    let unreliable = unreliable(&message);
+                    link.schedule(message, unreliable);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                // deliver what is still on its way before going away
+                Err(RecvTimeoutError::Disconnected) => disconnected = true,
+            }
+
+            for message in link.take_due(Instant::now()) {
+                if tx_output.send(message).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    rx_output
+}
+
+/// The messages that are on their way, ordered by when they arrive
+struct Link<T> {
+    conditions: LinkConditions,
+    in_flight: BinaryHeap<Reverse<InFlight<T>>>,
+    /// Breaks ties between messages arriving at the same instant
+    sent: u64,
+    /// When the latest reliable message arrives. No reliable message may arrive before it.
+    last_reliable: Instant,
+    /// An unreliable message waiting for the next one to overtake it, along
+    /// with when it arrives if nothing does
+    held_back: Option<(Instant, T)>,
+}
+
+struct InFlight<T> {
+    arrives_at: Instant,
+    sent: u64,
+    message: T,
+}
+
+impl<T> PartialEq for InFlight<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for InFlight<T> {}
+
+impl<T> PartialOrd for InFlight<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for InFlight<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.arrives_at, self.sent).cmp(&(other.arrives_at, other.sent))
+    }
+}
+
+impl<T: Clone> Link<T> {
+    fn new(conditions: LinkConditions) -> Self {
+        Self {
+            conditions,
+            in_flight: BinaryHeap::new(),
+            sent: 0,
+            last_reliable: Instant::now(),
+            held_back: None,
+        }
+    }
+
+    fn schedule(&mut self, message: T, unreliable: bool) {
+        let mut arrives_at = Instant::now() + self.delay();
+
+        if unreliable {
+            if fastrand::f64() < self.conditions.loss {
+                return;
+            }
+
+            if fastrand::f64() < self.conditions.duplicate {
+                self.send(arrives_at, message.clone());
+            }
+
+            if self.held_back.is_none() && fastrand::f64() < self.conditions.reorder {
+                self.held_back = Some((arrives_at + MAX_HOLD_BACK, message));
+                return;
+            }
+        } else {
+            arrives_at = arrives_at.max(self.last_reliable);
+            self.last_reliable = arrives_at;
+        }
+
+        self.send(arrives_at, message);
+
+        // the message we held back arrives right after the one that overtook it
+        if let Some((_, held_back)) = self.held_back.take() {
+            self.send(arrives_at, held_back);
+        }
+    }
+
+    fn send(&mut self, arrives_at: Instant, message: T) {
+        self.sent += 1;
+        self.in_flight.push(Reverse(InFlight {
+            arrives_at,
+            sent: self.sent,
+            message,
+        }));
+    }
+
+    fn delay(&self) -> Duration {
+        let jitter = self.conditions.jitter as i64;
+        let offset = match jitter {
+            0 => 0,
+            jitter => fastrand::i64(-jitter..=jitter),
+        };
+
+        Duration::from_millis((self.conditions.latency as i64 + offset).max(0) as u64)
+    }
+
+    fn next_due(&self) -> Option<Instant> {
+        let in_flight = self.in_flight.peek().map(|Reverse(next)| next.arrives_at);
+        let held_back = self.held_back.as_ref().map(|(arrives_at, _)| *arrives_at);
+
+        match (in_flight, held_back) {
+            (Some(in_flight), Some(held_back)) => Some(in_flight.min(held_back)),
+            (in_flight, held_back) => in_flight.or(held_back),
+        }
+    }
+
+    fn take_due(&mut self, now: Instant) -> Vec<T> {
+        let mut due = vec![];
+
+        // nothing came along to overtake it in time
+        if self
+            .held_back
+            .as_ref()
+            .is_some_and(|(arrives_at, _)| *arrives_at <= now)
+        {
+            if let Some((arrives_at, held_back)) = self.held_back.take() {
+                self.send(arrives_at, held_back);
+            }
+        }
+
+        while self.next_due().is_some_and(|arrives_at| arrives_at <= now) {
+            if let Some(Reverse(in_flight)) = self.in_flight.pop() {
+                due.push(in_flight.message);
+            }
+        }
+
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_presets() {
+        let preset = |s: &str| s.parse::<LinkConditions>().unwrap();
+
+        assert_eq!(preset(""), LinkConditions::default());
+        assert_eq!(preset("perfect"), LinkConditions::default());
+        assert_eq!(preset(" lan ").latency, 1);
+        assert_eq!(preset("broadband").latency, 20);
+        assert_eq!(preset("wifi").reorder, 0.005);
+        assert_eq!(preset("mobile").duplicate, 0.01);
+        assert_eq!(
+            preset("terrible"),
+            LinkConditions {
+                latency: 200,
+                jitter: 100,
+                loss: 0.1,
+                duplicate: 0.05,
+                reorder: 0.05,
+            }
+        );
+    }
+
+    #[test]
+    fn parses_custom_settings() {
+        assert_eq!(
+            "latency=80, jitter=20,loss=0.02".parse(),
+            Ok(LinkConditions {
+                latency: 80,
+                jitter: 20,
+                loss: 0.02,
+                ..default()
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_settings() {
+        assert!("latency".parse::<LinkConditions>().is_err());
+        assert!("latency=fast".parse::<LinkConditions>().is_err());
+        assert!("bandwidth=10".parse::<LinkConditions>().is_err());
+        assert!("awful".parse::<LinkConditions>().is_err());
+    }
+
+    #[test]
+    fn reliable_messages_keep_their_order_under_jitter() {
+        let mut link = Link::new("terrible".parse().unwrap());
+
+        for i in 0..1000 {
+            // every other message may be lost, duplicated or reordered
+            link.schedule(i, i % 2 == 1);
+        }
+
+        let arrived = link.take_due(Instant::now() + Duration::from_secs(1));
+        let reliable: Vec<_> = arrived.into_iter().filter(|i| i % 2 == 0).collect();
+
+        assert_eq!(reliable, (0..1000).step_by(2).collect::<Vec<_>>());
+        assert_eq!(link.next_due(), None);
+    }
+}
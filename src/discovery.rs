@@ -0,0 +1,395 @@
+use std::{
+    io::ErrorKind,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bevy::{input::common_conditions::input_just_pressed, prelude::*};
+use crossbeam_channel::Receiver;
+use protobuf::Message;
+
+use crate::{
+    chat::not_typing,
+    events::ConnectToServerEvent,
+    protos::{generated::applesauce, PROTOCOL_VERSION},
+};
+
+/// The UDP port servers listen for discovery queries on
+pub const DISCOVERY_PORT: u16 = 3030;
+
+/// Discovery messages are tiny, anything bigger than this is not one of them
+const MAX_DATAGRAM_SIZE: usize = 1024;
+
+/// How long the server list waits for answers every time it searches
+const SEARCH_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long the server waits before reading from the discovery socket again
+/// after it failed to
+const READ_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How many reads in a row may fail before the server stops listening for queries
+const MAX_READ_FAILURES: u32 = 10;
+
+/// Searches the LAN again
+const SEARCH_KEY: KeyCode = KeyCode::KeyR;
+
+/// A server that answered our discovery query
+#[derive(Clone, Debug)]
+pub struct DiscoveredServer {
+    /// Where to connect to, like 192.168.1.20:3000
+    pub address: String,
+    pub name: String,
+    pub player_count: u32,
+    pub max_players: u32,
+    pub level: String,
+    pub protocol_version: u32,
+    server_id: String,
+}
+
+impl DiscoveredServer {
+    /// Servers on another protocol version would turn us away
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+    }
+}
+
+/// Asks every server on the LAN, and on this machine, who they are. Blocks
+/// for the whole timeout, collecting answers as they come in.
+pub fn discover(timeout: Duration) -> std::io::Result<Vec<DiscoveredServer>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+
+    let query = applesauce::DiscoveryQuery {
+        protocol_version: PROTOCOL_VERSION,
+        special_fields: default(),
+    }
+    .write_to_bytes()
+    .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+
+    // broadcasts don't make it back to this machine on every system, so
+    // servers on it get asked directly as well
+    for address in [Ipv4Addr::BROADCAST, Ipv4Addr::LOCALHOST] {
+        if let Err(e) = socket.send_to(&query, (address, DISCOVERY_PORT)) {
+            println!("Failed to send a discovery query to {}: {}", address, e);
+        }
+    }
+
+    let deadline = Instant::now() + timeout;
+    let mut buffer = [0; MAX_DATAGRAM_SIZE];
+    let mut servers: Vec<DiscoveredServer> = vec![];
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+
+        let (length, from) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+            Err(e) => return Err(e),
+        };
+
+        let answer = match applesauce::DiscoveryAnswer::parse_from_bytes(&buffer[..length]) {
+            Err(_) => continue,
+            Ok(answer) => answer,
+        };
+
+        let server = DiscoveredServer {
+            address: SocketAddr::new(from.ip(), answer.port as u16).to_string(),
+            name: answer.name,
+            player_count: answer.player_count,
+            max_players: answer.max_players,
+            level: answer.level,
+            protocol_version: answer.protocol_version,
+            server_id: answer.server_id,
+        };
+
+        // a server on this machine answers both queries, and the loopback
+        // address is the one that works even if it only listens on loopback
+        match servers.iter_mut().find(|s| s.server_id == server.server_id) {
+            None => servers.push(server),
+            Some(existing) => {
+                if from.ip().is_loopback() {
+                    *existing = server;
+                }
+            }
+        }
+    }
+
+    Ok(servers)
+}
+
+/// Where a server hears discovery queries, and answers them
+pub(crate) struct DiscoveryListener {
+    socket: UdpSocket,
+    /// Where each query came from
+    queries: Receiver<SocketAddr>,
+}
+
+impl DiscoveryListener {
+    /// Fails if something else, like another server on this machine, already listens on the port
+    pub(crate) fn bind() -> std::io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT))?;
+        let reader = socket.try_clone()?;
+        let (tx_query, rx_query) = crossbeam_channel::unbounded::<SocketAddr>();
+
+        thread::spawn(move || {
+            let mut buffer = [0; MAX_DATAGRAM_SIZE];
+            let mut failures = 0;
+
+            loop {
+                let (length, from) = match reader.recv_from(&mut buffer) {
+                    Ok(received) => received,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    // some systems report a client that was gone before our
+                    // answer reached it on the next read
+                    Err(e) if e.kind() == ErrorKind::ConnectionReset => continue,
+                    Err(e) => {
+                        failures += 1;
+                        if failures >= MAX_READ_FAILURES {
+                            println!("Giving up on discovery queries: {}", e);
+                            return;
+                        }
+
+                        // whatever went wrong is unlikely to be over right away
+                        println!("Failed to read a discovery query: {}", e);
+                        thread::sleep(READ_RETRY_DELAY);
+                        continue;
+                    }
+                };
+                failures = 0;
+
+                // we answer whatever protocol version is asking, so the
+                // client can tell it is on the wrong one
+                if applesauce::DiscoveryQuery::parse_from_bytes(&buffer[..length]).is_err() {
+                    continue;
+                }
+
+                if tx_query.send(from).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Self {
+            socket,
+            queries: rx_query,
+        })
+    }
+
+    /// Where every query that arrived since we last looked came from
+    pub(crate) fn queries(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.queries.try_iter()
+    }
+
+    pub(crate) fn answer(&self, to: SocketAddr, answer: &applesauce::DiscoveryAnswer) {
+        let bytes = match answer.write_to_bytes() {
+            Err(e) => {
+                println!("Failed to encode a discovery answer: {}", e);
+                return;
+            }
+            Ok(bytes) => bytes,
+        };
+
+        if let Err(e) = self.socket.send_to(&bytes, to) {
+            println!("Failed to answer the discovery query from {}: {}", to, e);
+        }
+    }
+}
+
+/// Lists the servers on the LAN and connects to whichever one gets clicked.
+/// Goes with a `ClientPlugin` that waits for a server to be picked.
+pub struct ServerListPlugin;
+
+impl Plugin for ServerListPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<ConnectToServerEvent>()
+            .add_systems(Startup, (setup, search))
+            .add_systems(
+                Update,
+                (
+                    search
+                        .run_if(input_just_pressed(SEARCH_KEY))
+                        .run_if(not_typing),
+                    show_servers,
+                    pick_server,
+                )
+                    .chain()
+                    .run_if(any_with_component::<ServerList>),
+            );
+    }
+}
+
+#[derive(Component)]
+struct ServerList;
+
+/// Where the servers get listed, below the heading
+#[derive(Component)]
+struct ServerRows;
+
+#[derive(Component)]
+struct ServerRow {
+    address: String,
+}
+
+/// The answers of the latest search, once it is done
+#[derive(Resource, Deref)]
+struct ServerSearch(Receiver<Vec<DiscoveredServer>>);
+
+const ROW_COLOR: Color = Color::rgb(0.15, 0.15, 0.15);
+const HOVERED_ROW_COLOR: Color = Color::rgb(0.25, 0.25, 0.25);
+
+fn setup(mut commands: Commands) {
+    commands
+        .spawn((
+            ServerList,
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    width: Val::Percent(100.),
+                    height: Val::Percent(100.),
+                    flex_direction: FlexDirection::Column,
+                    align_items: AlignItems::Center,
+                    padding: UiRect::all(Val::Px(20.)),
+                    row_gap: Val::Px(10.),
+                    ..default()
+                },
+                background_color: Color::rgba(0., 0., 0., 0.9).into(),
+                z_index: ZIndex::Global(10),
+                ..default()
+            },
+        ))
+        .with_children(|parent| {
+            parent.spawn(TextBundle::from_section(
+                format!(
+                    "Servers on the LAN. Click one to join, {:?} to search again",
+                    SEARCH_KEY
+                ),
+                TextStyle {
+                    font_size: 24.,
+                    color: Color::WHITE,
+                    ..default()
+                },
+            ));
+
+            parent.spawn((
+                ServerRows,
+                NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::Column,
+                        row_gap: Val::Px(5.),
+                        ..default()
+                    },
+                    ..default()
+                },
+            ));
+        });
+}
+
+fn search(mut commands: Commands) {
+    let (tx_servers, rx_servers) = crossbeam_channel::bounded::<Vec<DiscoveredServer>>(1);
+
+    thread::spawn(move || match discover(SEARCH_TIMEOUT) {
+        Ok(servers) => {
+            tx_servers.send(servers).ok();
+        }
+        Err(e) => println!("Failed to search the LAN for servers: {}", e),
+    });
+
+    commands.insert_resource(ServerSearch(rx_servers));
+}
+
+fn show_servers(
+    mut commands: Commands,
+    search: Option<Res<ServerSearch>>,
+    rows: Query<Entity, With<ServerRows>>,
+) {
+    let servers = match search.and_then(|search| search.try_recv().ok()) {
+        None => return,
+        Some(servers) => servers,
+    };
+
+    let rows = match rows.get_single() {
+        Err(_) => return,
+        Ok(rows) => rows,
+    };
+
+    let style = TextStyle {
+        font_size: 20.,
+        color: Color::WHITE,
+        ..default()
+    };
+
+    commands.entity(rows).despawn_descendants();
+    commands.entity(rows).with_children(|parent| {
+        if servers.is_empty() {
+            parent.spawn(TextBundle::from_section("No servers found", style.clone()));
+        }
+
+        for server in servers {
+            let mut label = format!(
+                "{}   {}/{} players   {}   {}",
+                server.name, server.player_count, server.max_players, server.level, server.address
+            );
+
+            // servers we can't play on get listed, but can't be picked
+            if !server.is_compatible() {
+                label = format!("{}   (protocol {})", label, server.protocol_version);
+                parent.spawn(TextBundle::from_section(
+                    label,
+                    TextStyle {
+                        color: Color::GRAY,
+                        ..style.clone()
+                    },
+                ));
+                continue;
+            }
+
+            parent
+                .spawn((
+                    ServerRow {
+                        address: server.address.to_string(),
+                    },
+                    ButtonBundle {
+                        style: Style {
+                            padding: UiRect::all(Val::Px(10.)),
+                            ..default()
+                        },
+                        background_color: ROW_COLOR.into(),
+                        ..default()
+                    },
+                ))
+                .with_children(|row| {
+                    row.spawn(TextBundle::from_section(label, style.clone()));
+                });
+        }
+    });
+}
+
+fn pick_server(
+    mut commands: Commands,
+    mut rows: Query<(&Interaction, &ServerRow, &mut BackgroundColor), Changed<Interaction>>,
+    server_lists: Query<Entity, With<ServerList>>,
+    mut events: EventWriter<ConnectToServerEvent>,
+) {
+    for (interaction, row, mut color) in rows.iter_mut() {
+        match interaction {
+            Interaction::Hovered => *color = HOVERED_ROW_COLOR.into(),
+            Interaction::None => *color = ROW_COLOR.into(),
+            Interaction::Pressed => {
+                println!("Connecting to {}", row.address);
+                events.send(ConnectToServerEvent {
+                    hostname: row.address.to_string(),
+                });
+
+                for server_list in server_lists.iter() {
+                    commands.entity(server_list).despawn_recursive();
+                }
+                return;
+            }
+        }
+    }
+}
@@ -1,38 +1,177 @@
-use bevy::prelude::*;
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::round::{RoundPhase, Scoreboard};
 
 #[derive(Event)]
 pub(crate) struct PlayerSpawnEvent {
-    pub(crate) id: String,
     pub(crate) client_id: String,
 }
 
+/// Everything a player is doing with their controls during one tick
+#[derive(Clone, Copy, Default, PartialEq, Reflect)]
+pub(crate) struct InputState {
+    pub(crate) tick: u64,
+    /// -1 is all the way left, 1 is all the way right
+    pub(crate) move_axis: f32,
+    pub(crate) jump: bool,
+    pub(crate) shoot: bool,
+    pub(crate) block: bool,
+    pub(crate) aim: Vec2,
+}
+
 #[derive(Event)]
-pub(crate) struct PlayerMoveLeftEvent {
-    pub(crate) id: String,
+pub(crate) struct PlayerInputEvent {
     pub(crate) client_id: String,
+    pub(crate) state: InputState,
 }
 
 #[derive(Event)]
-pub(crate) struct PlayerMoveRightEvent {
-    pub(crate) id: String,
+pub(crate) struct PlayerDisconnectedEvent {
     pub(crate) client_id: String,
 }
 
-#[derive(Event)]
-pub(crate) struct PlayerJumpEvent {
+/// A room on the server, as the server describes it to clients
+#[derive(Clone, Debug)]
+pub(crate) struct RoomInfo {
     pub(crate) id: String,
-    pub(crate) client_id: String,
+    pub(crate) name: String,
+    pub(crate) player_count: u32,
+}
+
+/// Something we are asking the server to do about which room we are in
+#[derive(Event, Clone)]
+pub(crate) enum RoomRequestEvent {
+    List,
+    Create {
+        name: String,
+        private: bool,
+        /// The file name of one of the server's levels. The server picks when unset.
+        level: Option<String>,
+    },
+    JoinById(String),
+    JoinByCode(String),
 }
 
 #[derive(Event)]
-pub(crate) struct PlayerShootEvent {
-    pub(crate) id: String,
+pub(crate) struct RoomListEvent {
+    pub(crate) rooms: Vec<RoomInfo>,
+}
+
+/// We ended up in a room. Game states from the room we were in before stop
+/// arriving, and the new room's clock has nothing to do with the old one's.
+#[derive(Event)]
+pub(crate) struct RoomJoinedEvent {
+    pub(crate) room: RoomInfo,
+    /// Only set for private rooms
+    pub(crate) join_code: Option<String>,
+}
+
+/// Something that happened in the game that can't be told from game states
+/// alone. Only the server's simulation reports these, clients hear about them
+/// from the server. Players are referred to by their client id.
+#[derive(Event, Clone, Debug)]
+pub(crate) enum GameEvent {
+    BulletFired {
+        /// The network id of the bullet
+        bullet_id: u32,
+        client_id: String,
+    },
+    PlayerHit {
+        client_id: String,
+        attacker: String,
+        damage: i32,
+    },
+    ShieldBlocked {
+        client_id: String,
+        attacker: String,
+    },
+    PlayerDied {
+        client_id: String,
+    },
+    PlayerSpawned {
+        client_id: String,
+        spawn_id: String,
+    },
+}
+
+/// Connects the client to the server at this address, unless it already is connected
+#[derive(Event)]
+pub(crate) struct ConnectToServerEvent {
+    pub(crate) hostname: String,
+}
+
+/// Something we want to say to everyone in our room
+#[derive(Event)]
+pub(crate) struct SendChatEvent {
+    pub(crate) text: String,
+}
+
+/// Someone in our room said something, or the server has something to tell us
+#[derive(Event, Clone)]
+pub(crate) struct ChatMessageEvent {
+    /// Empty for notices from the server itself
     pub(crate) client_id: String,
-    pub(crate) aim: Vec2,
+    pub(crate) display_name: String,
+    pub(crate) text: String,
+    /// From the server itself rather than a player
+    pub(crate) notice: bool,
+}
+
+impl ChatMessageEvent {
+    /// Who said it, by name when they have one
+    pub(crate) fn author(&self) -> &str {
+        match (self.notice, self.display_name.is_empty()) {
+            (true, _) => "server",
+            (false, true) => &self.client_id,
+            (false, false) => &self.display_name,
+        }
+    }
 }
 
+/// Where the room is in its rounds, as the server tells it
+#[derive(Event, Clone)]
+pub(crate) struct RoundStatusEvent {
+    pub(crate) scoreboard: Scoreboard,
+    pub(crate) phase: RoundPhase,
+}
+
+/// The level our room is playing on, as the server tells it
 #[derive(Event)]
-pub(crate) struct PlayerBlockEvent {
-    pub(crate) id: String,
+pub(crate) struct LevelEvent {
+    pub(crate) path: String,
+}
+
+/// The client wants another match with the same players, once this one is over
+#[derive(Event)]
+pub(crate) struct RematchEvent {
     pub(crate) client_id: String,
 }
+
+/// Changes how a player plays. Everyone picks one between rounds, and keeps
+/// the ones they picked for as long as they stay in the room.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Reflect)]
+pub(crate) enum Card {
+    MoveFaster,
+    MoreDamage,
+}
+
+impl Card {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Card::MoveFaster => "Move Faster",
+            Card::MoreDamage => "More Damage",
+        }
+    }
+}
+
+/// We picked a card
+#[derive(Event)]
+pub(crate) struct CardPickedEvent {
+    pub(crate) card: Card,
+}
+
+/// The cards every player in our room has picked, keyed by client id, as the server tells it
+#[derive(Event)]
+pub(crate) struct PickedCardsEvent {
+    pub(crate) cards: HashMap<String, Vec<Card>>,
+}
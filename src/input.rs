@@ -1,35 +1,28 @@
 use bevy::{prelude::*, window::PrimaryWindow};
 
 use crate::{
-    events::{
-        PlayerBlockEvent, PlayerJumpEvent, PlayerMoveLeftEvent, PlayerMoveRightEvent,
-        PlayerShootEvent, PlayerSpawnEvent,
-    },
-    manage_state::Player,
+    chat::{not_typing, ChatInput},
+    events::{InputState, PlayerInputEvent, PlayerSpawnEvent},
+    manage_state::{InputTick, Player},
     AppConfig, GameState,
 };
 
-pub(crate) struct InputPlugin;
+pub struct InputPlugin;
 
 impl Plugin for InputPlugin {
     fn build(&self, app: &mut App) {
         app.add_event::<PlayerSpawnEvent>()
-            .add_event::<PlayerMoveLeftEvent>()
-            .add_event::<PlayerMoveRightEvent>()
-            .add_event::<PlayerJumpEvent>()
-            .add_event::<PlayerShootEvent>()
-            .add_event::<PlayerBlockEvent>()
+            .add_event::<PlayerInputEvent>()
+            .init_resource::<InputTick>()
             .add_systems(
                 PreUpdate,
-                (
-                    on_enter_send_player_spawn,
-                    on_a_send_player_move_left,
-                    on_d_send_player_move_right,
-                    on_space_send_player_jump,
-                    on_left_click_send_player_shoot_event,
-                    on_right_click_send_player_block,
-                )
-                    .run_if(in_state(GameState::Round)),
+                on_enter_send_player_spawn
+                    .run_if(in_state(GameState::Round))
+                    .run_if(not_typing),
+            )
+            .add_systems(
+                FixedPreUpdate,
+                send_player_input_state.run_if(in_state(GameState::Round)),
             );
     }
 }
@@ -41,81 +34,66 @@ fn on_enter_send_player_spawn(
 ) {
     if keyboard_input.just_pressed(KeyCode::Enter) {
         events.send(PlayerSpawnEvent {
-            id: uuid::Uuid::new_v4().to_string(),
             client_id: config.client_id.to_string(),
         });
     }
 }
 
-fn on_a_send_player_move_left(
+#[allow(clippy::too_many_arguments)]
+fn send_player_input_state(
     config: Res<AppConfig>,
+    mut tick: ResMut<InputTick>,
     keyboard_input: Res<ButtonInput<KeyCode>>,
-    mut events: EventWriter<PlayerMoveLeftEvent>,
+    mouse_button_input: Res<ButtonInput<MouseButton>>,
+    mut events: EventWriter<PlayerInputEvent>,
+    windows: Query<&Window, With<PrimaryWindow>>,
+    cameras: Query<(&Camera, &GlobalTransform)>,
+    players: Query<(&Player, &Transform)>,
+    chat_input: Option<Res<ChatInput>>,
 ) {
-    if keyboard_input.pressed(KeyCode::KeyA) {
-        events.send(PlayerMoveLeftEvent {
-            id: uuid::Uuid::new_v4().to_string(),
-            client_id: config.client_id.to_string(),
-        });
-    }
-}
+    **tick += 1;
 
-fn on_d_send_player_move_right(
-    config: Res<AppConfig>,
-    keyboard_input: Res<ButtonInput<KeyCode>>,
-    mut events: EventWriter<PlayerMoveRightEvent>,
-) {
-    if keyboard_input.pressed(KeyCode::KeyD) {
-        events.send(PlayerMoveRightEvent {
-            id: uuid::Uuid::new_v4().to_string(),
+    // keep telling the server we let go of everything while we type, or our
+    // player would keep doing whatever it did when we started typing
+    if chat_input.is_some_and(|chat_input| chat_input.open) {
+        events.send(PlayerInputEvent {
             client_id: config.client_id.to_string(),
+            state: InputState {
+                tick: **tick,
+                ..default()
+            },
         });
+        return;
     }
-}
 
-fn on_space_send_player_jump(
-    config: Res<AppConfig>,
-    keyboard_input: Res<ButtonInput<KeyCode>>,
-    mut events: EventWriter<PlayerJumpEvent>,
-) {
-    if keyboard_input.just_pressed(KeyCode::Space) {
-        events.send(PlayerJumpEvent {
-            id: uuid::Uuid::new_v4().to_string(),
-            client_id: config.client_id.to_string(),
-        });
+    let mut move_axis = 0.;
+    if keyboard_input.pressed(KeyCode::KeyA) {
+        move_axis -= 1.;
+    }
+    if keyboard_input.pressed(KeyCode::KeyD) {
+        move_axis += 1.;
     }
-}
 
-fn on_left_click_send_player_shoot_event(
-    config: Res<AppConfig>,
-    mouse_button_input: Res<ButtonInput<MouseButton>>,
-    events: EventWriter<PlayerShootEvent>,
-    windows: Query<&Window, With<PrimaryWindow>>,
-    cameras: Query<(&Camera, &GlobalTransform)>,
-    players: Query<(&Player, &Transform)>,
-) {
-    on_left_click_send_player_shoot_event_fallible(
-        config,
-        mouse_button_input,
-        events,
-        windows,
-        cameras,
-        players,
-    );
+    events.send(PlayerInputEvent {
+        client_id: config.client_id.to_string(),
+        state: InputState {
+            tick: **tick,
+            move_axis,
+            jump: keyboard_input.pressed(KeyCode::Space),
+            shoot: mouse_button_input.pressed(MouseButton::Left),
+            block: mouse_button_input.pressed(MouseButton::Right),
+            aim: aim(&config, windows, cameras, players).unwrap_or_default(),
+        },
+    });
 }
 
-fn on_left_click_send_player_shoot_event_fallible(
-    config: Res<AppConfig>,
-    mouse_button_input: Res<ButtonInput<MouseButton>>,
-    mut events: EventWriter<PlayerShootEvent>,
+/// The direction from our player to the mouse cursor
+fn aim(
+    config: &AppConfig,
     windows: Query<&Window, With<PrimaryWindow>>,
     cameras: Query<(&Camera, &GlobalTransform)>,
     players: Query<(&Player, &Transform)>,
-) -> Option<()> {
-    if !mouse_button_input.just_pressed(MouseButton::Left) {
-        return None;
-    };
-
+) -> Option<Vec2> {
     let cursor_position = windows.get_single().ok()?.cursor_position()?;
     let (camera, camera_transform) = cameras.get_single().ok()?;
 
@@ -126,30 +104,10 @@ fn on_left_click_send_player_shoot_event_fallible(
     let relative_cursor_position = camera
         .viewport_to_world(camera_transform, cursor_position)?
         .origin;
-    let aim = (relative_cursor_position - player.1.translation)
-        .normalize()
-        .xy();
 
-    events.send(PlayerShootEvent {
-        id: uuid::Uuid::new_v4().to_string(),
-        client_id: config.client_id.to_string(),
-        aim,
-    });
-
-    None
-}
-
-fn on_right_click_send_player_block(
-    config: Res<AppConfig>,
-    mouse_button_input: Res<ButtonInput<MouseButton>>,
-    mut events: EventWriter<PlayerBlockEvent>,
-) {
-    if !mouse_button_input.just_pressed(MouseButton::Right) {
-        return;
-    };
-
-    events.send(PlayerBlockEvent {
-        id: uuid::Uuid::new_v4().to_string(),
-        client_id: config.client_id.to_string(),
-    });
+    Some(
+        (relative_cursor_position - player.1.translation)
+            .normalize()
+            .xy(),
+    )
 }
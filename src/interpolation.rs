@@ -0,0 +1,227 @@
+use std::collections::VecDeque;
+
+use bevy::{prelude::*, transform::TransformSystem};
+use bevy_rapier2d::dynamics::Velocity;
+
+use crate::{events::RoomJoinedEvent, manage_state::GameStateEvent, AppConfig, GameState};
+
+/// Snapshots older than the render time that we hang on to. We only ever need
+/// one of them to interpolate from, the rest is slack for late packets.
+const MAX_SNAPSHOTS: usize = 32;
+
+/// Renders remote players and bullets slightly in the past so there is always
+/// a pair of server snapshots to blend between. When the next snapshot is late,
+/// entities keep moving along their last known velocity for a little while.
+pub struct InterpolationPlugin;
+
+impl Plugin for InterpolationPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ServerClock>()
+            .add_event::<RoomJoinedEvent>()
+            .add_systems(
+                First,
+                (reset_server_clock_on_room_change, track_server_clock).chain(),
+            )
+            .add_systems(
+                PostUpdate,
+                interpolate_snapshots
+                    .before(TransformSystem::TransformPropagate)
+                    .run_if(in_state(GameState::Round)),
+            );
+    }
+}
+
+/// Where an entity was according to one server snapshot
+#[derive(Clone, Copy)]
+pub(crate) struct Snapshot {
+    pub(crate) timestamp: u64,
+    pub(crate) translation: Vec3,
+    pub(crate) rotation: Quat,
+    pub(crate) velocity: Vec2,
+}
+
+/// The snapshots we have received for a remote entity, oldest first
+#[derive(Component, Default)]
+pub(crate) struct SnapshotBuffer(VecDeque<Snapshot>);
+
+impl SnapshotBuffer {
+    pub(crate) fn new(snapshot: Snapshot) -> Self {
+        Self(VecDeque::from([snapshot]))
+    }
+
+    /// Adds a snapshot to the buffer, ignoring any that arrive out of order
+    pub(crate) fn push(&mut self, snapshot: Snapshot) {
+        if self
+            .0
+            .back()
+            .is_some_and(|latest| latest.timestamp >= snapshot.timestamp)
+        {
+            return;
+        }
+
+        self.0.push_back(snapshot);
+        while self.0.len() > MAX_SNAPSHOTS {
+            self.0.pop_front();
+        }
+    }
+
+    /// Where the entity was at `render_time`, extrapolating at most
+    /// `max_extrapolation` milliseconds past the latest snapshot
+    fn sample(&mut self, render_time: f64, max_extrapolation: f64) -> Option<Snapshot> {
+        // drop every snapshot we no longer need to interpolate from
+        while self.0.len() > 2 && self.0[1].timestamp as f64 <= render_time {
+            self.0.pop_front();
+        }
+
+        let from = *self.0.front()?;
+        if render_time <= from.timestamp as f64 {
+            return Some(from);
+        }
+
+        match self.0.iter().find(|s| s.timestamp as f64 >= render_time) {
+            Some(to) => {
+                let t = (render_time - from.timestamp as f64)
+                    / (to.timestamp - from.timestamp).max(1) as f64;
+                let t = t as f32;
+
+                Some(Snapshot {
+                    timestamp: render_time as u64,
+                    translation: from.translation.lerp(to.translation, t),
+                    rotation: from.rotation.slerp(to.rotation, t),
+                    velocity: from.velocity.lerp(to.velocity, t),
+                })
+            }
+            None => {
+                let latest = *self.0.back()?;
+                let elapsed_seconds =
+                    (render_time - latest.timestamp as f64).min(max_extrapolation) / 1000.;
+
+                Some(Snapshot {
+                    timestamp: render_time as u64,
+                    translation: latest.translation
+                        + (latest.velocity * elapsed_seconds as f32).extend(0.),
+                    ..latest
+                })
+            }
+        }
+    }
+}
+
+/// Our best guess of the difference between the server's clock and ours, in milliseconds
+#[derive(Resource, Default)]
+struct ServerClock {
+    offset: Option<f64>,
+}
+
+impl ServerClock {
+    fn now(&self, time: &Time) -> Option<f64> {
+        Some(time.elapsed_seconds_f64() * 1000. + self.offset?)
+    }
+}
+
+/// Every room keeps its own clock, so what we know about the last one is useless
+fn reset_server_clock_on_room_change(
+    mut clock: ResMut<ServerClock>,
+    mut events: EventReader<RoomJoinedEvent>,
+) {
+    if events.read().count() > 0 {
+        clock.offset = None;
+    }
+}
+
+fn track_server_clock(
+    mut clock: ResMut<ServerClock>,
+    mut events: EventReader<GameStateEvent>,
+    time: Res<Time>,
+) {
+    let now = time.elapsed_seconds_f64() * 1000.;
+
+    for game_state in events.read() {
+        let sample = game_state.timestamp as f64 - now;
+
+        // a snapshot that arrives quickly tells us more about the clock than
+        // one that was held up along the way, so jump forward but drift back
+        clock.offset = Some(match clock.offset {
+            Some(offset) if sample < offset => offset + (sample - offset) * 0.01,
+            _ => sample,
+        });
+    }
+}
+
+fn interpolate_snapshots(
+    config: Res<AppConfig>,
+    clock: Res<ServerClock>,
+    time: Res<Time>,
+    mut entities: Query<(&mut SnapshotBuffer, &mut Transform, &mut Velocity)>,
+) {
+    let render_time = match clock.now(&time) {
+        None => return,
+        Some(now) => now - config.interpolation_delay as f64,
+    };
+
+    for (mut buffer, mut transform, mut velocity) in entities.iter_mut() {
+        let snapshot = match buffer.sample(render_time, config.max_extrapolation as f64) {
+            None => continue,
+            Some(snapshot) => snapshot,
+        };
+
+        transform.translation = snapshot.translation;
+        transform.rotation = snapshot.rotation;
+        velocity.linvel = snapshot.velocity;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_2;
+
+    use super::*;
+
+    fn snapshot(timestamp: u64, x: f32, angle: f32) -> Snapshot {
+        Snapshot {
+            timestamp,
+            translation: Vec3::new(x, 0., 0.),
+            rotation: Quat::from_rotation_z(angle),
+            velocity: Vec2::new(100., 0.),
+        }
+    }
+
+    fn buffer() -> SnapshotBuffer {
+        let mut buffer = SnapshotBuffer::new(snapshot(100, 0., 0.));
+        buffer.push(snapshot(200, 10., FRAC_PI_2));
+        buffer
+    }
+
+    #[test]
+    fn samples_between_snapshots_are_blended() {
+        let sample = buffer().sample(150., 250.).unwrap();
+
+        assert_eq!(sample.timestamp, 150);
+        assert!(sample.translation.abs_diff_eq(Vec3::new(5., 0., 0.), 1e-4));
+        assert!(sample
+            .rotation
+            .abs_diff_eq(Quat::from_rotation_z(FRAC_PI_2 / 2.), 1e-4));
+    }
+
+    #[test]
+    fn samples_past_the_latest_snapshot_are_extrapolated_up_to_the_limit() {
+        let mut buffer = buffer();
+
+        let sample = buffer.sample(300., 250.).unwrap();
+        assert!(sample.translation.abs_diff_eq(Vec3::new(20., 0., 0.), 1e-4));
+
+        let sample = buffer.sample(1000., 250.).unwrap();
+        assert!(sample.translation.abs_diff_eq(Vec3::new(35., 0., 0.), 1e-4));
+        assert!(sample
+            .rotation
+            .abs_diff_eq(Quat::from_rotation_z(FRAC_PI_2), 1e-4));
+    }
+
+    #[test]
+    fn samples_before_the_oldest_snapshot_return_it() {
+        let sample = buffer().sample(50., 250.).unwrap();
+
+        assert_eq!(sample.timestamp, 100);
+        assert_eq!(sample.translation, Vec3::ZERO);
+    }
+}
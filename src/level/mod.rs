@@ -3,20 +3,19 @@ mod view_box;
 use std::collections::HashMap;
 use std::num::ParseFloatError;
 
+use bevy::ecs::system::CommandQueue;
 use bevy::prelude::*;
 use bevy::sprite::MaterialMesh2dBundle;
-use bevy_inspector_egui::egui::TextBuffer;
 use bevy_rapier2d::prelude::*;
 
 use self::view_box::ViewBox;
 
-const LEVEL_PATH: &str = "assets/level.svg";
-// const LEVEL_PATH: &str = "assets/plain.svg";
-// const LEVEL_PATH: &str = "assets/half-plain.svg";
-// const LEVEL_PATH: &str = "assets/offset-plain.svg";
-
 const Z_SEPARATION: f32 = 0.01;
 
+/// Everything a level spawns, so it can all be despawned to load another one
+#[derive(Component)]
+pub(crate) struct PartOfLevel;
+
 #[derive(Component, Reflect)]
 pub(crate) struct PlayerSpawn {
     pub id: String,
@@ -33,25 +32,45 @@ pub(crate) struct ColliderBundle {
 
 #[derive(Debug, Error)]
 pub(crate) enum LoadLevelError {
+    /// The level file could not be read
+    Unreadable(std::io::Error),
+    /// The level file is not valid SVG
+    InvalidSvg(svg::parser::Error),
     HandleEmptyTagError(HandleEmptyTagError),
     HandleStartTagError(HandleStartTagError),
 }
 
 pub(crate) fn load_level<'a>(
     commands: Commands<'a, 'a>,
-    meshes: ResMut<'a, Assets<Mesh>>,
-    materials: ResMut<'a, Assets<ColorMaterial>>,
+    meshes: Option<ResMut<'a, Assets<Mesh>>>,
+    materials: Option<ResMut<'a, Assets<ColorMaterial>>>,
+    path: &str,
     window_width: f32,
     window_height: f32,
 ) -> Result<(), LoadLevelError> {
     let mut loader = Loader::new(commands, meshes, materials, window_width, window_height);
-    loader.load_level(LEVEL_PATH.as_str())
+    loader.load_level(path)
+}
+
+/// Goes through the whole level without spawning any of it, to find out
+/// whether it would load
+pub(crate) fn check_level(
+    path: &str,
+    window_width: f32,
+    window_height: f32,
+) -> Result<(), LoadLevelError> {
+    let world = World::new();
+    let mut queue = CommandQueue::default();
+    let commands = Commands::new(&mut queue, &world);
+
+    load_level(commands, None, None, path, window_width, window_height)
 }
 
 struct Loader<'a> {
     commands: Commands<'a, 'a>,
-    meshes: ResMut<'a, Assets<Mesh>>,
-    materials: ResMut<'a, Assets<ColorMaterial>>,
+    /// Missing when running headless, in which case shapes only get a transform
+    meshes: Option<ResMut<'a, Assets<Mesh>>>,
+    materials: Option<ResMut<'a, Assets<ColorMaterial>>>,
     window_width: f32,
     window_height: f32,
     current_z: f32,
@@ -61,8 +80,8 @@ struct Loader<'a> {
 impl<'a> Loader<'a> {
     fn new(
         commands: Commands<'a, 'a>,
-        meshes: ResMut<'a, Assets<Mesh>>,
-        materials: ResMut<'a, Assets<ColorMaterial>>,
+        meshes: Option<ResMut<'a, Assets<Mesh>>>,
+        materials: Option<ResMut<'a, Assets<ColorMaterial>>>,
         window_width: f32,
         window_height: f32,
     ) -> Self {
@@ -79,10 +98,10 @@ impl<'a> Loader<'a> {
 
     fn load_level(&mut self, path: &str) -> Result<(), LoadLevelError> {
         let mut content = String::new();
-        let parser = svg::open(path, &mut content).unwrap();
+        let parser = svg::open(path, &mut content)?;
         for event in parser {
             match event {
-                svg::parser::Event::Error(e) => panic!("Error parsing SVG: {:?}", e),
+                svg::parser::Event::Error(e) => return Err(e.into()),
                 svg::parser::Event::Tag(path, tag_type, attributes) => match tag_type {
                     svg::node::element::tag::Type::Start => {
                         self.handle_start_tag(path, &attributes)?
@@ -180,18 +199,12 @@ impl<'a> Loader<'a> {
 
         let fill = parse_color(&fill_string)?;
 
-        let entity = self
-            .commands
-            .spawn((
-                MaterialMesh2dBundle {
-                    mesh: self.meshes.add(Rectangle::new(width, height)).into(),
-                    material: self.materials.add(ColorMaterial::from(fill)),
-                    transform: Transform::from_translation(Vec3::new(x, y, z)),
-                    ..default()
-                },
-                Name::new("rect"),
-            ))
-            .id();
+        let entity = self.spawn_shape(
+            Rectangle::new(width, height),
+            fill,
+            Transform::from_translation(Vec3::new(x, y, z)),
+            "rect",
+        );
 
         if has_class(attributes, "collider") {
             self.commands
@@ -206,6 +219,38 @@ impl<'a> Loader<'a> {
         Ok(())
     }
 
+    fn spawn_shape(
+        self: &mut Self,
+        mesh: impl Into<Mesh>,
+        color: Color,
+        transform: Transform,
+        name: &'static str,
+    ) -> Entity {
+        match (&mut self.meshes, &mut self.materials) {
+            (Some(meshes), Some(materials)) => self
+                .commands
+                .spawn((
+                    MaterialMesh2dBundle {
+                        mesh: meshes.add(mesh).into(),
+                        material: materials.add(ColorMaterial::from(color)),
+                        transform,
+                        ..default()
+                    },
+                    Name::new(name),
+                    PartOfLevel,
+                ))
+                .id(),
+            _ => self
+                .commands
+                .spawn((
+                    TransformBundle::from_transform(transform),
+                    Name::new(name),
+                    PartOfLevel,
+                ))
+                .id(),
+        }
+    }
+
     fn handle_circle(
         self: &mut Self,
         attributes: &HashMap<String, svg::node::Value>,
@@ -248,18 +293,12 @@ impl<'a> Loader<'a> {
 
         let color = parse_color(&color_string)?;
 
-        let entity = self
-            .commands
-            .spawn((
-                MaterialMesh2dBundle {
-                    mesh: self.meshes.add(Circle::new(radius)).into(),
-                    material: self.materials.add(ColorMaterial::from(color)),
-                    transform: Transform::from_translation(position),
-                    ..default()
-                },
-                Name::new("circle"),
-            ))
-            .id();
+        let entity = self.spawn_shape(
+            Circle::new(radius),
+            color,
+            Transform::from_translation(position),
+            "circle",
+        );
 
         if has_class(attributes, "collider") {
             self.commands.entity(entity).insert(ColliderBundle {
@@ -315,6 +354,7 @@ impl<'a> Loader<'a> {
 
         self.commands.spawn((
             Name::new(format!("PlayerSpawn: {}", id)),
+            PartOfLevel,
             PlayerSpawn {
                 id,
                 position,
@@ -0,0 +1,179 @@
+#[macro_use]
+extern crate derive_error;
+
+mod chat;
+mod events;
+mod input;
+mod interpolation;
+mod level;
+mod manage_state;
+mod prediction;
+mod render;
+mod round;
+mod select_card_plugin;
+mod spectator;
+
+mod admin;
+mod client;
+mod conditioner;
+mod discovery;
+mod protos;
+mod relay;
+mod rollback;
+mod room;
+mod server;
+
+use bevy::prelude::*;
+
+pub use chat::ChatPlugin;
+pub use client::ClientPlugin;
+pub use conditioner::{LinkConditions, NetworkConditions};
+pub use discovery::{discover, DiscoveredServer, ServerListPlugin, DISCOVERY_PORT};
+pub use input::InputPlugin;
+pub use interpolation::InterpolationPlugin;
+pub use manage_state::ManageStatePlugin;
+pub use prediction::PredictionPlugin;
+pub use relay::RelayPlugin;
+pub use render::RenderPlugin;
+pub use rollback::RollbackPlugin;
+pub use round::{MatchFormat, ScoreboardPlugin};
+pub use select_card_plugin::SelectCardPlugin;
+pub use server::ServerPlugin;
+pub use spectator::SpectatorPlugin;
+
+#[derive(Clone, Copy, Default, Eq, PartialEq, Debug, Hash, States)]
+pub enum GameState {
+    #[default]
+    PickCard,
+    Round,
+}
+
+#[derive(Resource, Reflect, Clone)]
+pub struct AppConfig {
+    pub width: f32,
+    pub height: f32,
+
+    pub client_id: String,
+    /// The name other players see for us. Sent to the server in the handshake
+    pub display_name: String,
+    /// How much to displace the bullet from the player so
+    /// they don't shoot themselves if they're running towards
+    /// where they're shooting
+    pub fudge_factor: f32,
+
+    pub bullet_speed: f32,
+    /// How fast a player speeds up while holding a direction, in pixels per second squared
+    pub player_move_speed: f32,
+    pub reload_timeout: u64,
+    pub jump_amount: f32,
+    pub gravity: f32,
+
+    pub shield_timeout: u64,
+    pub shield_duration: u64,
+    pub player_max_move_speed: f32,
+    pub player_health: i32,
+    /// How many bullets a player's gun holds, and starts out with
+    pub starting_ammo: u32,
+
+    /// The SVG file the level is loaded from, like assets/plain.svg
+    pub level: String,
+
+    /// How far in the past, in milliseconds, remote players and bullets are rendered
+    pub interpolation_delay: u64,
+    /// How long, in milliseconds, remote entities keep moving on their own
+    /// when snapshots stop arriving
+    pub max_extrapolation: u64,
+    /// The furthest back, in milliseconds, the server rewinds players to check
+    /// whether a laggy client's shot hit
+    pub max_lag_compensation: u64,
+
+    /// How long, in milliseconds, the server holds on to the player of a client
+    /// that lost its connection, in case it comes back
+    pub resume_grace_period: u64,
+
+    /// How many rounds it takes to win a match
+    pub match_format: MatchFormat,
+    /// How long, in milliseconds, the results are shown after a round ends
+    pub results_duration: u64,
+    /// How long, in milliseconds, everyone gets to pick a card between rounds
+    pub pick_card_duration: u64,
+
+    /// How many times per second the simulation advances
+    pub tick_rate: f64,
+    /// How many times per second the server sends a game state to its clients
+    pub snapshot_rate: f64,
+}
+
+impl AppConfig {
+    /// The default config, with whatever the environment overrides
+    pub fn from_env() -> Self {
+        let tick_rate: f64 = std::env::var("TICK_RATE")
+            .unwrap_or("60".to_string())
+            .parse()
+            .expect(
+                "Failed to parse TICK_RATE. Expected the number of simulation ticks per second",
+            );
+
+        let snapshot_rate: f64 = std::env::var("SNAPSHOT_RATE")
+            .unwrap_or("30".to_string())
+            .parse()
+            .expect("Failed to parse SNAPSHOT_RATE. Expected the number of snapshots per second");
+
+        let starting_ammo: u32 = std::env::var("STARTING_AMMO")
+            .unwrap_or("3".to_string())
+            .parse()
+            .expect("Failed to parse STARTING_AMMO. Expected the number of bullets a gun holds");
+
+        let display_name = std::env::var("PLAYER_NAME").unwrap_or("anonymous".to_string());
+
+        let level = std::env::var("LEVEL").unwrap_or("assets/level.svg".to_string());
+
+        let match_format: MatchFormat = std::env::var("MATCH_FORMAT")
+            .unwrap_or("first-to-3".to_string())
+            .parse()
+            .expect(
+                "Failed to parse MATCH_FORMAT. Expected first-to-N or best-of-N, like best-of-5",
+            );
+
+        AppConfig {
+            width: 1000.,
+            height: 400.,
+
+            client_id: "unknown".to_string(),
+            display_name,
+            // not implemented yet
+            fudge_factor: 11.,
+            bullet_speed: 1000.,
+            player_move_speed: 4800.,
+            reload_timeout: 1000,
+            jump_amount: 400.,
+            gravity: 2000.,
+            player_max_move_speed: 500.,
+            player_health: 10,
+            starting_ammo,
+
+            level,
+
+            shield_timeout: 1000,
+            shield_duration: 500,
+
+            interpolation_delay: 100,
+            max_extrapolation: 250,
+            max_lag_compensation: 200,
+
+            resume_grace_period: 10000,
+
+            match_format,
+            results_duration: 3000,
+            pick_card_duration: 5000,
+
+            tick_rate,
+            snapshot_rate,
+        }
+    }
+}
+
+#[derive(Component, Reflect)]
+pub(crate) struct Player {
+    client_id: String,
+}
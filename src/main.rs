@@ -1,94 +1,146 @@
-#[macro_use]
-extern crate derive_error;
-
-mod events;
-mod input;
-mod level;
-mod manage_state;
-mod render;
-mod select_card_plugin;
-
-mod client;
-mod protos;
-mod server;
-
 use bevy::prelude::*;
 use bevy::window::WindowPlugin;
 use bevy::window::WindowResolution;
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
 
-use client::ClientPlugin;
-use input::InputPlugin;
-use manage_state::ManageStatePlugin;
+use animated_couscous::{
+    AppConfig, ChatPlugin, ClientPlugin, GameState, InputPlugin, InterpolationPlugin,
+    ManageStatePlugin, NetworkConditions, PredictionPlugin, RelayPlugin, RenderPlugin,
+    RollbackPlugin, ScoreboardPlugin, SelectCardPlugin, ServerListPlugin, ServerPlugin,
+    SpectatorPlugin,
+};
 
-use render::RenderPlugin;
-use select_card_plugin::SelectCardPlugin;
-use server::ServerPlugin;
-
-#[derive(Clone, Copy, Default, Eq, PartialEq, Debug, Hash, States)]
-pub(crate) enum GameState {
-    #[default]
-    PickCard,
-    Round,
-}
 fn main() {
     let window_offset: i32 = std::env::var("WINDOW_OFFSET")
         .unwrap_or("0".to_string())
         .parse()
         .unwrap();
 
-    let width: f32 = 1000.;
-    let height: f32 = 400.;
+    // a relay only passes messages along, so it neither plays nor simulates
+    let relay_on = std::env::var("RELAY_ON").ok();
+    let relaying = relay_on.is_some();
 
     let enable_physics: bool = std::env::var("ENABLE_PHYSICS")
             .unwrap_or("true".to_string())
             .parse()
-            .expect("Failed to parse boolean value for ENABLE_PHYSICS. Accepted values are 'true' or 'false'");
+            .expect("Failed to parse boolean value for ENABLE_PHYSICS. Accepted values are 'true' or 'false'")
+            && !relaying;
+
+    let spectate: bool = std::env::var("SPECTATE")
+        .unwrap_or("false".to_string())
+        .parse()
+        .expect(
+            "Failed to parse boolean value for SPECTATE. Accepted values are 'true' or 'false'",
+        );
+
+    let find_servers: bool = std::env::var("FIND_SERVERS")
+        .unwrap_or("false".to_string())
+        .parse()
+        .expect(
+            "Failed to parse boolean value for FIND_SERVERS. Accepted values are 'true' or 'false'",
+        );
+
+    let config = AppConfig::from_env();
+    let (width, height, tick_rate) = (config.width, config.height, config.tick_rate);
 
     let mut app = App::new();
-    app.insert_resource(AppConfig {
-        width,
-        height,
-
-        client_id: "unknown".to_string(),
-        // not implemented yet
-        fudge_factor: 11.,
-        bullet_speed: 1000.,
-        player_move_speed: 80.,
-        reload_timeout: 1000,
-        jump_amount: 400.,
-        gravity: 2000.,
-        player_max_move_speed: 500.,
-        player_health: 10,
-
-        shield_timeout: 1000,
-        shield_duration: 500,
-    })
-    .register_type::<AppConfig>()
-    .init_state::<GameState>()
-    .add_plugins(DefaultPlugins.set(WindowPlugin {
-        primary_window: Some(Window {
-            resolution: WindowResolution::new(width, height),
-            position: WindowPosition::new(IVec2 {
-                x: 0,
-                y: window_offset,
+    app.insert_resource(config)
+        .insert_resource(Time::<Fixed>::from_hz(tick_rate))
+        .insert_resource(NetworkConditions::from_env())
+        .register_type::<AppConfig>()
+        .init_state::<GameState>()
+        .add_plugins(DefaultPlugins.set(WindowPlugin {
+            primary_window: Some(Window {
+                resolution: WindowResolution::new(width, height),
+                position: WindowPosition::new(IVec2 {
+                    x: 0,
+                    y: window_offset,
+                }),
+                ..Default::default()
             }),
             ..Default::default()
-        }),
-        ..Default::default()
-    }))
-    .add_plugins(WorldInspectorPlugin::new())
-    .add_plugins(RenderPlugin)
-    .add_plugins(InputPlugin)
-    .add_plugins(SelectCardPlugin)
-    .add_plugins(ManageStatePlugin::with_physics(enable_physics));
+        }))
+        .add_plugins(WorldInspectorPlugin::new())
+        .add_plugins(RenderPlugin)
+        .add_plugins(ScoreboardPlugin)
+        .add_plugins(ManageStatePlugin::with_physics(enable_physics));
+
+    // spectators have no cards to pick, and nothing to control but the camera
+    match spectate {
+        true => app
+            .insert_state(GameState::Round)
+            .add_plugins(SpectatorPlugin),
+        false if relaying => &mut app,
+        false => app.add_plugins(InputPlugin).add_plugins(SelectCardPlugin),
+    };
 
     if let Ok(hostname) = std::env::var("SERVE_ON") {
-        app.add_plugins(ServerPlugin::serve_on(hostname));
+        let mut server = ServerPlugin::serve_on(hostname);
+
+        if let Ok(name) = std::env::var("SERVER_NAME") {
+            server = server.named(name);
+        }
+
+        if let Ok(admin_hostname) = std::env::var("ADMIN_ON") {
+            server = server.admin_on(admin_hostname);
+        }
+
+        app.add_plugins(server);
+    }
+
+    if let Some(hostname) = relay_on {
+        let upstream =
+            std::env::var("RELAY_TO").expect("RELAY_ON needs RELAY_TO to know where to relay to");
+        app.add_plugins(RelayPlugin::relay(hostname, upstream));
     }
 
-    if let Ok(hostname) = std::env::var("CONNECT_TO") {
-        app.add_plugins(ClientPlugin::connect_to(hostname));
+    // rollback matches are between two peers, with no server in between
+    if let Ok(hostname) = std::env::var("ROLLBACK_HOST") {
+        app.add_plugins(RollbackPlugin::host(hostname));
+    }
+
+    if let Ok(hostname) = std::env::var("ROLLBACK_JOIN") {
+        app.add_plugins(RollbackPlugin::join(hostname));
+    }
+
+    // without an address to connect to, we can look for a server on the LAN
+    let client = match (std::env::var("CONNECT_TO"), find_servers) {
+        _ if relaying => None,
+        (Ok(hostname), _) => Some(ClientPlugin::connect_to(hostname)),
+        (Err(_), true) => {
+            app.add_plugins(ServerListPlugin);
+            Some(ClientPlugin::connect_to_picked_server())
+        }
+        (Err(_), false) => None,
+    };
+
+    if let Some(mut client) = client {
+        if let Ok(name) = std::env::var("CREATE_ROOM") {
+            let private: bool = std::env::var("PRIVATE_ROOM")
+                .unwrap_or("false".to_string())
+                .parse()
+                .expect("Failed to parse boolean value for PRIVATE_ROOM. Accepted values are 'true' or 'false'");
+            let level = std::env::var("ROOM_LEVEL").ok();
+            client = client.create_room(name, private, level);
+        }
+
+        if let Ok(room_id) = std::env::var("JOIN_ROOM") {
+            client = client.join_room(room_id);
+        }
+
+        if let Ok(join_code) = std::env::var("JOIN_CODE") {
+            client = client.join_code(join_code);
+        }
+
+        if spectate {
+            client = client.spectate();
+        } else {
+            app.add_plugins(PredictionPlugin);
+        }
+
+        app.add_plugins(client)
+            .add_plugins(InterpolationPlugin)
+            .add_plugins(ChatPlugin);
     }
     app.add_systems(Startup, setup);
 
@@ -98,31 +150,3 @@ fn main() {
 fn setup(mut commands: Commands) {
     commands.spawn(Camera2dBundle::default());
 }
-
-#[derive(Resource, Reflect)]
-struct AppConfig {
-    width: f32,
-    height: f32,
-
-    client_id: String,
-    /// How much to displace the bullet from the player so
-    /// they don't shoot themselves if they're running towards
-    /// where they're shooting
-    fudge_factor: f32,
-
-    bullet_speed: f32,
-    player_move_speed: f32,
-    reload_timeout: u64,
-    jump_amount: f32,
-    gravity: f32,
-
-    shield_timeout: u64,
-    shield_duration: u64,
-    player_max_move_speed: f32,
-    player_health: i32,
-}
-
-#[derive(Component, Reflect)]
-pub(crate) struct Player {
-    client_id: String,
-}
@@ -1,27 +1,28 @@
-use std::{f32::consts::PI, time::Duration};
+use std::{collections::VecDeque, f32::consts::PI, hash::Hash, time::Duration};
 
 use bevy::{
+    ecs::{schedule::SystemConfigs, system::SystemParam},
     prelude::*,
-    utils::{hashbrown::HashMap, HashSet, Instant},
+    utils::{hashbrown::HashMap, HashSet},
 };
 use bevy_rapier2d::prelude::*;
-use uuid::Uuid;
 
 use crate::{
     events::{
-        PlayerBlockEvent, PlayerJumpEvent, PlayerMoveLeftEvent, PlayerMoveRightEvent,
-        PlayerShootEvent, PlayerSpawnEvent,
+        GameEvent, InputState, LevelEvent, PlayerDisconnectedEvent, PlayerInputEvent,
+        PlayerSpawnEvent,
     },
-    level::{self, PlayerSpawn},
+    interpolation::{Snapshot, SnapshotBuffer},
+    level::{self, PartOfLevel, PlayerSpawn},
     AppConfig, GameState,
 };
 
-pub(crate) struct ManageStatePlugin {
+pub struct ManageStatePlugin {
     enable_physics: bool,
 }
 
 impl ManageStatePlugin {
-    pub(crate) fn with_physics(enable_physics: bool) -> Self {
+    pub fn with_physics(enable_physics: bool) -> Self {
         Self { enable_physics }
     }
 }
@@ -29,124 +30,445 @@ impl ManageStatePlugin {
 impl Plugin for ManageStatePlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         if self.enable_physics {
-            app.add_plugins(RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(100.0));
+            app.add_plugins(
+                RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(100.0).in_fixed_schedule(),
+            );
             // .add_plugins(RapierDebugRenderPlugin::default());
         }
 
         app.add_event::<GameStateEvent>()
+            .add_event::<LevelEvent>()
             .add_event::<PlayerSpawnEvent>()
-            .add_event::<PlayerMoveLeftEvent>()
-            .add_event::<PlayerMoveRightEvent>()
-            .add_event::<PlayerJumpEvent>()
-            .add_event::<PlayerShootEvent>()
-            .add_event::<PlayerBlockEvent>()
+            .add_event::<PlayerInputEvent>()
+            .add_event::<PlayerDisconnectedEvent>()
             .add_event::<CollisionEvent>()
+            .add_event::<GameEvent>()
+            .add_event::<RestartRoundEvent>()
             .register_type::<Player>()
             .register_type::<Gun>()
-            .add_systems(OnEnter(GameState::Round), (load_level, configure_gravity))
+            .register_type::<PlayerInput>()
+            .register_type::<NetworkId>()
+            .init_resource::<SimulationTick>()
+            .init_resource::<Latencies>()
+            .init_resource::<EntityIndex>()
+            .add_systems(OnEnter(GameState::Round), (load_level, configure_physics))
+            .add_systems(OnExit(GameState::Round), unload_round)
+            .add_systems(
+                PreUpdate,
+                (
+                    handle_player_disconnected_event,
+                    switch_level_from_level_event,
+                ),
+            )
             .add_systems(
                 First,
                 (
-                    // reset_player_horizontal_velocity,
-                    reset_vertical_impulse,
                     update_players_from_game_state_event,
-                    update_bullets_from_game_state_event,
-                    auto_reload_gun,
-                    advance_shield_timeout,
+                    index_entities(),
+                    (
+                        update_player_stats_from_game_state_event,
+                        update_bullets_from_game_state_event,
+                    ),
+                    index_entities(),
                 )
+                    .chain()
                     .run_if(in_state(GameState::Round)),
             )
             .add_systems(
                 PreUpdate,
                 (
                     handle_player_spawn_event,
-                    handle_player_move_left_event,
-                    handle_player_move_right_event,
-                    handle_player_jump_event,
-                    handle_player_shoot_event,
-                    handle_player_block_event,
+                    index_entities(),
+                    handle_player_input_event,
                 )
-                    .run_if(in_state(GameState::Round)),
+                    .chain()
+                    .run_if(in_state(GameState::Round))
+                    .run_if(not(resource_exists::<InputsAppliedElsewhere>)),
             )
+            .add_systems(Update, restart_round.run_if(in_state(GameState::Round)))
+            .add_systems(PreUpdate, pause_physics)
             .add_systems(
-                Update,
+                FixedFirst,
+                advance_simulation_tick
+                    .run_if(in_state(GameState::Round))
+                    .run_if(not(resource_exists::<Paused>)),
+            )
+            .add_systems(
+                FixedPreUpdate,
+                apply_queued_inputs
+                    .run_if(in_state(GameState::Round))
+                    .run_if(not(resource_exists::<Paused>)),
+            )
+            .add_systems(
+                FixedUpdate,
                 (
-                    arc_bullets,
-                    bullets_despawn_on_collision_with_anything,
-                    health_decreases_on_collision_with_bullets,
-                    despawn_things_with_0_or_less_health,
-                    shields_despawn_on_timeout,
-                    enable_or_disable_player_jumping,
+                    (
+                        reset_vertical_impulse,
+                        (
+                            auto_reload_gun,
+                            advance_shield_timeout,
+                            move_players,
+                            handle_player_jump,
+                            handle_player_shoot,
+                            handle_player_block,
+                            arc_bullets,
+                        ),
+                    )
+                        .chain()
+                        .before(PhysicsSet::SyncBackend),
+                    (
+                        bullets_despawn_on_collision_with_anything,
+                        health_decreases_on_collision_with_bullets,
+                        despawn_things_with_0_or_less_health,
+                        shields_despawn_on_timeout,
+                        enable_or_disable_player_jumping,
+                        // only the server compensates shots for lag
+                        record_position_history.run_if(resource_exists::<Authoritative>),
+                    )
+                        .after(PhysicsSet::Writeback),
                 )
-                    .run_if(in_state(GameState::Round)),
+                    .run_if(in_state(GameState::Round))
+                    .run_if(not(resource_exists::<Paused>)),
             )
             .add_systems(
                 PostUpdate,
                 despawn_things_that_need_despawning.run_if(in_state(GameState::Round)),
-            );
+            )
+            .add_systems(Last, index_entities());
+    }
+}
+
+/// The number of fixed timesteps the simulation has run for
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct SimulationTick(u64);
+
+/// Counts the input states a client has sent so the receiving end can tell
+/// them apart. The server acknowledges them by this tick once it simulated them.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct InputTick(u64);
+
+/// The round trip time to each client, keyed by client id. Only the server
+/// knows these, so only the server compensates shots for lag.
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct Latencies(HashMap<String, Duration>);
+
+const BULLET_DAMAGE: i32 = 3;
+
+/// Half the size of a bullet along and across the direction it flies, in pixels
+pub(crate) const BULLET_HALF_LENGTH: f32 = 20.;
+pub(crate) const BULLET_HALF_WIDTH: f32 = 5.;
+
+/// Present in worlds where something else applies inputs and spawns on the
+/// frame they are for, like rollback sessions do. Their events are left alone then.
+#[derive(Resource)]
+pub(crate) struct InputsAppliedElsewhere;
+
+/// Present while the simulation stands still, like once a match is over
+#[derive(Resource)]
+pub(crate) struct Paused;
+
+/// Present in worlds whose simulation is the one that counts, which are the
+/// server's rooms. Clients simulate too, but only to guess ahead of the server.
+#[derive(Resource)]
+pub(crate) struct Authoritative;
+
+/// Tells players and bullets apart over the network. Game states are full of
+/// these, so they are small numbers the server hands out rather than uuids.
+#[derive(Component, Reflect, Clone, Copy, PartialEq, Eq, Hash, Debug, Deref)]
+pub(crate) struct NetworkId(pub(crate) u32);
+
+/// Finds players and bullets by their network id, and players by the client
+/// they belong to, without going through all of them. It is brought up to date
+/// right after the systems that spawn players and bullets from events and game
+/// states, and at the end of every frame. In between, it can be stale: entities
+/// that other systems spawn or despawn, like shots, rollback or leaving clients,
+/// only show up in or drop out of it once the frame is over.
+#[derive(Resource, Default)]
+pub(crate) struct EntityIndex {
+    network_ids: KeyIndex<NetworkId>,
+    client_ids: KeyIndex<String>,
+    /// The last network id handed out. Only the authoritative world hands them out.
+    last_network_id: u32,
+}
+
+impl EntityIndex {
+    pub(crate) fn entity(&self, id: NetworkId) -> Option<Entity> {
+        self.network_ids.entities.get(&id).copied()
+    }
+
+    /// The player of the client, if it has one
+    pub(crate) fn player(&self, client_id: &str) -> Option<Entity> {
+        self.client_ids.entities.get(client_id).copied()
+    }
+}
+
+/// Which entity each key belongs to, and the other way around
+struct KeyIndex<K> {
+    entities: HashMap<K, Entity>,
+    keys: HashMap<Entity, K>,
+}
+
+impl<K> Default for KeyIndex<K> {
+    fn default() -> Self {
+        Self {
+            entities: default(),
+            keys: default(),
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash> KeyIndex<K> {
+    fn insert(&mut self, key: K, entity: Entity) {
+        self.entities.insert(key.clone(), entity);
+        self.keys.insert(entity, key);
+    }
+
+    fn remove(&mut self, entity: Entity) {
+        let key = match self.keys.remove(&entity) {
+            None => return,
+            Some(key) => key,
+        };
+
+        // the key may already belong to an entity spawned in its place
+        if self.entities.get(&key) == Some(&entity) {
+            self.entities.remove(&key);
+        }
+    }
+}
+
+/// Hands out network ids, but only in the simulation that counts. Whatever
+/// clients spawn on their own is a guess that the server's game states replace.
+#[derive(SystemParam)]
+pub(crate) struct NetworkIdAllocator<'w> {
+    index: ResMut<'w, EntityIndex>,
+    authoritative: Option<Res<'w, Authoritative>>,
+}
+
+impl NetworkIdAllocator<'_> {
+    pub(crate) fn allocate(&mut self) -> Option<NetworkId> {
+        self.authoritative.as_ref()?;
+
+        self.index.last_network_id = self.index.last_network_id.wrapping_add(1);
+        Some(NetworkId(self.index.last_network_id))
+    }
+}
+
+/// Reports game events, but only from the simulation that counts, so clients
+/// don't hear about things that only happened in their own guesses
+#[derive(SystemParam)]
+pub(crate) struct GameEvents<'w> {
+    writer: EventWriter<'w, GameEvent>,
+    authoritative: Option<Res<'w, Authoritative>>,
+}
+
+impl GameEvents<'_> {
+    pub(crate) fn send(&mut self, event: GameEvent) {
+        if self.authoritative.is_some() {
+            self.writer.send(event);
+        }
     }
 }
+
 #[derive(Event)]
 pub(crate) struct GameStateEvent {
     pub(crate) timestamp: u64,
     pub(crate) players: Vec<PlayerState>,
     pub(crate) bullets: Vec<BulletState>,
+    /// The tick of the last input state the server simulated each player
+    /// with, keyed by network id
+    pub(crate) input_acks: HashMap<NetworkId, u64>,
+    /// The round trip time to each player's client in milliseconds, keyed by network id
+    pub(crate) round_trip_times: HashMap<NetworkId, u32>,
 }
 
+/// Starts the round over: the level gets loaded again from the AppConfig, and
+/// every player in it starts over at a spawn
+#[derive(Event)]
+pub(crate) struct RestartRoundEvent;
+
 pub(crate) struct PlayerState {
-    pub(crate) id: String,
+    /// Unset for players the server doesn't know about, like the ones rollback peers spawn
+    pub(crate) id: Option<NetworkId>,
     pub(crate) client_id: String,
     pub(crate) spawn_id: String,
     pub(crate) radius: f32,
     pub(crate) color: Color,
     pub(crate) position: Vec3,
     pub(crate) velocity: Vec2,
+    pub(crate) health: i32,
+    pub(crate) shield: Option<ShieldState>,
+    /// How long ago the player last raised a shield, up to the shield timeout
+    pub(crate) shield_cooldown_elapsed: Duration,
+    pub(crate) bullet_count: u32,
+    pub(crate) bullet_capacity: u32,
+}
+
+pub(crate) struct ShieldState {
+    pub(crate) radius: f32,
+    pub(crate) remaining: Duration,
 }
 
 pub(crate) struct BulletState {
-    pub(crate) id: String,
+    pub(crate) id: NetworkId,
     pub(crate) transform: Transform,
     pub(crate) velocity: Vec2,
 }
 
-#[derive(Component, Reflect)]
+#[derive(Component, Reflect, Clone)]
 pub(crate) struct Shield {
-    ttl: Timer,
+    pub(crate) ttl: Timer,
     pub(crate) radius: f32,
 }
 
-#[derive(Component, Reflect)]
+#[derive(Component, Reflect, Clone)]
 pub(crate) struct Gun {
     pub(crate) bullet_count: u32,
     pub(crate) bullet_capacity: u32,
-    pub(crate) last_shot: Option<Instant>,
+    /// When the gun was last shot, in simulation time
+    pub(crate) last_shot: Option<Duration>,
 }
 
 #[derive(Bundle)]
-struct ShieldBundle {
+pub(crate) struct ShieldBundle {
     shield: Shield,
     collider: Collider,
     transform: TransformBundle,
 }
 
+impl ShieldBundle {
+    pub(crate) fn new(radius: f32, ttl: Timer) -> Self {
+        Self {
+            shield: Shield { radius, ttl },
+            collider: Collider::ball(radius),
+            transform: TransformBundle::from_transform(Transform::from_translation(Vec3::new(
+                0., 0., 0.2,
+            ))),
+        }
+    }
+}
+
 #[derive(Component, Reflect)]
-struct Despawn;
+pub(crate) struct Despawn;
 
+/// Marks the player this client predicts locally. Its transform and velocity
+/// get reconciled against game states from the server instead of interpolated.
 #[derive(Component, Reflect)]
+pub(crate) struct Predicted;
+
+#[derive(Component, Reflect, Clone)]
 pub(crate) struct Player {
-    pub(crate) id: String,
     pub(crate) spawn_id: String,
     pub(crate) client_id: String,
     pub(crate) radius: f32,
     pub(crate) color: Color,
 }
 
-#[derive(Component, Reflect, Deref, DerefMut)]
+/// How many input states a player may have waiting before the oldest ones get
+/// applied all at once. Keeps a player whose inputs arrived in a burst from
+/// lagging behind what they are doing for good.
+const MAX_QUEUED_INPUTS: usize = 3;
+
+/// The controls a player is holding, as of the latest input state the
+/// simulation applied for them
+#[derive(Component, Reflect, Default, Clone)]
+pub(crate) struct PlayerInput {
+    state: InputState,
+    /// Input states that arrived but haven't been applied yet, one of which
+    /// gets applied every tick
+    queued: VecDeque<InputState>,
+    /// Buttons that went down since the systems acting on them last looked
+    jump_pressed: bool,
+    shoot_pressed: bool,
+    block_pressed: bool,
+    /// Where the player was aiming when they pressed shoot
+    shoot_aim: Vec2,
+}
+
+impl PlayerInput {
+    /// The controls as they are once the systems acting on button presses had their look
+    pub(crate) fn holding(state: InputState) -> Self {
+        Self { state, ..default() }
+    }
+
+    pub(crate) fn state(&self) -> InputState {
+        self.state
+    }
+
+    /// Keeps the state until the simulation gets to it. States that aren't
+    /// newer than the ones we already have are ignored.
+    pub(crate) fn queue(&mut self, state: InputState) {
+        let latest = self.queued.back().unwrap_or(&self.state).tick;
+        if state.tick > latest {
+            self.queued.push_back(state);
+        }
+    }
+
+    fn apply_queued(&mut self) {
+        while self.queued.len() > MAX_QUEUED_INPUTS {
+            if let Some(state) = self.queued.pop_front() {
+                self.apply(state);
+            }
+        }
+
+        if let Some(state) = self.queued.pop_front() {
+            self.apply(state);
+        }
+    }
+
+    pub(crate) fn apply(&mut self, state: InputState) {
+        if state.tick <= self.state.tick {
+            return;
+        }
+
+        self.jump_pressed |= state.jump && !self.state.jump;
+        self.block_pressed |= state.block && !self.state.block;
+        if state.shoot && !self.state.shoot {
+            self.shoot_pressed = true;
+            self.shoot_aim = state.aim;
+        }
+
+        self.state = state;
+    }
+
+    /// Lets go of every control, as if the player took their hands off the keyboard
+    pub(crate) fn release(&mut self) {
+        self.queued.clear();
+        self.state = InputState {
+            tick: self.state.tick,
+            ..default()
+        };
+    }
+}
+
+#[derive(Component, Reflect, Clone, Deref, DerefMut)]
 pub(crate) struct ShieldTimeout(Timer);
 
-#[derive(Component, Reflect, Deref)]
+#[derive(Component, Reflect, Clone, Deref)]
 pub(crate) struct Health(pub(crate) i32);
 
+/// Where the player was at the end of each of the last few ticks, oldest first
+#[derive(Component, Default, Clone, Deref, DerefMut)]
+pub(crate) struct PositionHistory(VecDeque<PastPosition>);
+
+#[derive(Clone, Copy)]
+pub(crate) struct PastPosition {
+    tick: u64,
+    position: Vec2,
+    /// Whether the player had its shield up
+    shielded: bool,
+}
+
+impl PositionHistory {
+    /// Where the player was at the end of the given tick, or as close to it as we remember
+    fn at(&self, tick: u64) -> Option<PastPosition> {
+        self.iter()
+            .rev()
+            .find(|past| past.tick <= tick)
+            .or(self.front())
+            .copied()
+    }
+}
+
 #[derive(Bundle)]
 struct PlayerBundle {
     name: Name,
@@ -160,6 +482,8 @@ struct PlayerBundle {
     locked_axes: LockedAxes,
     active_events: ActiveEvents,
     health: Health,
+    input: PlayerInput,
+    position_history: PositionHistory,
 }
 
 impl PlayerBundle {
@@ -185,17 +509,20 @@ impl PlayerBundle {
             velocity,
             external_impulse: Default::default(),
             health: Health(health),
+            input: default(),
+            position_history: default(),
         }
     }
 }
 
-#[derive(Component, Reflect)]
+#[derive(Component, Reflect, Clone)]
 pub(crate) struct Bullet {
-    pub(crate) id: String,
+    /// The client whose player fired it. Empty for bullets we only know from game states.
+    pub(crate) shooter: String,
 }
 
 #[derive(Bundle)]
-struct BulletBundle {
+pub(crate) struct BulletBundle {
     bullet: Bullet,
     rigid_body: RigidBody,
     collider: Collider,
@@ -211,7 +538,7 @@ impl BulletBundle {
             transform: TransformBundle::from_transform(transform),
             velocity: Velocity::linear(velocity),
             rigid_body: RigidBody::Dynamic,
-            collider: Collider::cuboid(20., 5.),
+            collider: Collider::cuboid(BULLET_HALF_LENGTH, BULLET_HALF_WIDTH),
             active_events: ActiveEvents::COLLISION_EVENTS,
         }
     }
@@ -223,21 +550,141 @@ struct CanJump;
 fn load_level(
     commands: Commands,
     config: Res<AppConfig>,
-    meshes: ResMut<Assets<Mesh>>,
-    materials: ResMut<Assets<ColorMaterial>>,
+    meshes: Option<ResMut<Assets<Mesh>>>,
+    materials: Option<ResMut<Assets<ColorMaterial>>>,
+) {
+    if let Err(e) = level::load_level(
+        commands,
+        meshes,
+        materials,
+        &config.level,
+        config.width,
+        config.height,
+    ) {
+        println!("Failed to load level {}: {}", config.level, e);
+    }
+}
+
+/// Clears away the level and everything on it, so the next round starts on a
+/// fresh copy with everyone at full health and ammo
+fn unload_round(
+    mut commands: Commands,
+    players: Query<Entity, With<Player>>,
+    bullets: Query<Entity, With<Bullet>>,
+    level_entities: Query<Entity, With<PartOfLevel>>,
 ) {
-    level::load_level(commands, meshes, materials, config.width, config.height)
-        .expect("Failed to load level");
+    for entity in players
+        .iter()
+        .chain(bullets.iter())
+        .chain(level_entities.iter())
+    {
+        commands.entity(entity).despawn_recursive();
+    }
 }
 
-fn configure_gravity(mut commands: Commands, config: Res<AppConfig>) {
+#[allow(clippy::too_many_arguments)]
+fn restart_round(
+    mut commands: Commands,
+    mut events: EventReader<RestartRoundEvent>,
+    players: Query<(Entity, &Player)>,
+    bullets: Query<Entity, With<Bullet>>,
+    level_entities: Query<Entity, With<PartOfLevel>>,
+    mut spawn_events: EventWriter<PlayerSpawnEvent>,
+    config: Res<AppConfig>,
+    meshes: Option<ResMut<Assets<Mesh>>>,
+    materials: Option<ResMut<Assets<ColorMaterial>>>,
+) {
+    if events.read().count() == 0 {
+        return;
+    }
+
+    println!("Restarting the round on {}", config.level);
+
+    for (entity, player) in players.iter() {
+        commands.entity(entity).despawn_recursive();
+
+        // handled next frame, once the new level's spawns are in place
+        spawn_events.send(PlayerSpawnEvent {
+            client_id: player.client_id.to_string(),
+        });
+    }
+
+    for entity in bullets.iter().chain(level_entities.iter()) {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    load_level(commands, config, meshes, materials);
+}
+
+/// Loads whichever level the server is playing on, if it isn't the one we
+/// have. Outside of rounds, it gets loaded once the next round starts.
+fn switch_level_from_level_event(
+    mut commands: Commands,
+    mut events: EventReader<LevelEvent>,
+    level_entities: Query<Entity, With<PartOfLevel>>,
+    state: Res<State<GameState>>,
+    mut config: ResMut<AppConfig>,
+    meshes: Option<ResMut<Assets<Mesh>>>,
+    materials: Option<ResMut<Assets<ColorMaterial>>>,
+) {
+    let latest = match events.read().last() {
+        None => return,
+        Some(latest) => latest,
+    };
+
+    if latest.path.is_empty() || latest.path == config.level {
+        return;
+    }
+
+    // keep playing on the level we have rather than ending up with none
+    if let Err(e) = level::check_level(&latest.path, config.width, config.height) {
+        println!("Failed to switch to level {}: {}", latest.path, e);
+        return;
+    }
+
+    println!("Switching to level {}", latest.path);
+    config.level = latest.path.to_string();
+
+    if *state.get() != GameState::Round {
+        return;
+    }
+
+    for entity in level_entities.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    load_level(commands, config.into(), meshes, materials);
+}
+
+fn configure_physics(mut commands: Commands, config: Res<AppConfig>, fixed_time: Res<Time<Fixed>>) {
     commands.insert_resource(RapierConfiguration {
-        gravity: Vec2::new(0., -config.gravity).into(),
+        gravity: Vec2::new(0., -config.gravity),
+        timestep_mode: TimestepMode::Fixed {
+            dt: fixed_time.timestep().as_secs_f32(),
+            substeps: 1,
+        },
         ..default()
     });
 }
 
-fn reset_vertical_impulse(mut impulses: Query<&mut ExternalImpulse>) {
+/// Bodies would keep falling and flying otherwise
+fn pause_physics(paused: Option<Res<Paused>>, rapier: Option<ResMut<RapierConfiguration>>) {
+    let mut rapier = match rapier {
+        None => return,
+        Some(rapier) => rapier,
+    };
+
+    let active = paused.is_none();
+    if rapier.physics_pipeline_active != active {
+        rapier.physics_pipeline_active = active;
+    }
+}
+
+fn advance_simulation_tick(mut tick: ResMut<SimulationTick>) {
+    **tick += 1;
+}
+
+pub(crate) fn reset_vertical_impulse(mut impulses: Query<&mut ExternalImpulse>) {
     for mut impulse in impulses.iter_mut() {
         impulse.impulse.y = 0.;
     }
@@ -245,114 +692,275 @@ fn reset_vertical_impulse(mut impulses: Query<&mut ExternalImpulse>) {
 
 fn update_players_from_game_state_event(
     mut commands: Commands,
-    mut players: Query<(Entity, &Player, &mut Transform, &mut Velocity)>,
+    mut players: Query<(Entity, Option<&mut SnapshotBuffer>), With<Player>>,
+    index: Res<EntityIndex>,
     mut events: EventReader<GameStateEvent>,
     config: Res<AppConfig>,
 ) {
-    match events.read().max_by(|a, b| a.timestamp.cmp(&b.timestamp)) {
+    let mut game_states: Vec<&GameStateEvent> = events.read().collect();
+    game_states.sort_by_key(|game_state| game_state.timestamp);
+
+    match game_states.last() {
         None => return,
-        Some(game_state) => {
-            let mut player_entities_by_id: HashMap<String, Entity> = players
-                .iter_mut()
-                .map(|(entity, player, _, _)| (player.id.to_string(), entity))
-                .collect();
-
-            for player_state in game_state.players.iter() {
-                player_entities_by_id.remove(&player_state.id);
-
-                match players
-                    .iter_mut()
-                    .find(|(_, b, _, _)| b.id == player_state.id)
-                {
-                    Some((_, _, mut transform, mut velocity)) => {
-                        transform.translation = player_state.position.clone();
-                        velocity.linvel = player_state.velocity.clone();
-                    }
-                    None => {
-                        spawn_player(&mut commands, player_state, &config);
-                    }
+        Some(latest) => {
+            // players we spawned on our own have no network id, so they never show up here
+            let mut missing: HashSet<Entity> = players.iter().map(|(entity, _)| entity).collect();
+
+            for player_state in latest.players.iter() {
+                let entity = player_state.id.and_then(|id| index.entity(id));
+                if entity.is_some_and(|entity| missing.remove(&entity)) {
+                    continue;
+                }
+
+                let entity = spawn_player(&mut commands, player_state, &config);
+
+                // our own player gets predicted instead of interpolated
+                if player_state.client_id != config.client_id {
+                    commands
+                        .entity(entity)
+                        .insert(SnapshotBuffer::new(Snapshot {
+                            timestamp: latest.timestamp,
+                            translation: player_state.position,
+                            rotation: Quat::IDENTITY,
+                            velocity: player_state.velocity,
+                        }));
                 }
             }
 
-            for (_, entity) in player_entities_by_id {
+            for entity in missing {
                 commands.entity(entity).insert(Despawn);
             }
         }
     }
+
+    for game_state in game_states {
+        for player_state in game_state.players.iter() {
+            let entity = match player_state.id.and_then(|id| index.entity(id)) {
+                None => continue,
+                Some(entity) => entity,
+            };
+
+            if let Ok((_, Some(mut buffer))) = players.get_mut(entity) {
+                buffer.push(Snapshot {
+                    timestamp: game_state.timestamp,
+                    translation: player_state.position,
+                    rotation: Quat::IDENTITY,
+                    velocity: player_state.velocity,
+                });
+            }
+        }
+    }
 }
 
-fn spawn_player(
+pub(crate) fn spawn_player(
     commands: &mut Commands<'_, '_>,
     player_state: &PlayerState,
     config: &Res<'_, AppConfig>,
-) {
-    commands
-        .spawn(PlayerBundle::new(
-            Player {
-                id: player_state.id.clone(),
-                spawn_id: player_state.spawn_id.clone(),
-                client_id: player_state.client_id.clone(),
-                radius: player_state.radius,
-                color: player_state.color.clone(),
-            },
-            Transform::from_translation(player_state.position.clone()),
-            Velocity::linear(player_state.velocity.clone()),
-            config.shield_timeout,
-            config.player_health,
-        ))
+) -> Entity {
+    let mut entity = commands.spawn(PlayerBundle::new(
+        Player {
+            spawn_id: player_state.spawn_id.clone(),
+            client_id: player_state.client_id.clone(),
+            radius: player_state.radius,
+            color: player_state.color.clone(),
+        },
+        Transform::from_translation(player_state.position.clone()),
+        Velocity::linear(player_state.velocity.clone()),
+        config.shield_timeout,
+        player_state.health,
+    ));
+
+    entity
+        .insert(ShieldTimeout(timer_at(
+            Duration::from_millis(config.shield_timeout),
+            player_state.shield_cooldown_elapsed,
+        )))
         .with_children(|parent| {
             parent.spawn((
                 Gun {
-                    bullet_capacity: 3,
-                    bullet_count: 3,
+                    bullet_capacity: player_state.bullet_capacity,
+                    bullet_count: player_state.bullet_count,
                     last_shot: None,
                 },
                 Transform::from_translation(Vec3::new(0., 0., 0.1)),
             ));
+
+            if let Some(shield) = &player_state.shield {
+                parent.spawn(ShieldBundle::new(
+                    shield.radius,
+                    timer_at(
+                        Duration::from_millis(config.shield_duration),
+                        Duration::from_millis(config.shield_duration)
+                            .saturating_sub(shield.remaining),
+                    ),
+                ));
+            }
         });
+
+    if let Some(id) = player_state.id {
+        entity.insert(id);
+    }
+
+    entity.id()
+}
+
+/// Makes health, shields and ammo match the server's. Our own predicted shields
+/// and ammo are left alone until the server has caught up with all of our inputs,
+/// or they would flicker back to what they were a round trip ago.
+#[allow(clippy::too_many_arguments)]
+fn update_player_stats_from_game_state_event(
+    mut commands: Commands,
+    mut events: EventReader<GameStateEvent>,
+    mut players: Query<(&mut Health, &Children, Has<Predicted>), With<Player>>,
+    mut shield_timeouts: Query<&mut ShieldTimeout>,
+    mut guns: Query<&mut Gun>,
+    mut shields: Query<&mut Shield>,
+    index: Res<EntityIndex>,
+    input_tick: Option<Res<InputTick>>,
+    config: Res<AppConfig>,
+) {
+    let latest = match events.read().max_by_key(|game_state| game_state.timestamp) {
+        None => return,
+        Some(latest) => latest,
+    };
+
+    let caught_up = latest
+        .players
+        .iter()
+        .find(|player_state| player_state.client_id == config.client_id)
+        .and_then(|player_state| latest.input_acks.get(&player_state.id?))
+        .is_some_and(|ack| input_tick.is_some_and(|tick| *ack >= **tick));
+
+    for player_state in latest.players.iter() {
+        let entity = match player_state.id.and_then(|id| index.entity(id)) {
+            None => continue,
+            Some(entity) => entity,
+        };
+
+        let (mut health, children, predicted) = match players.get_mut(entity) {
+            Err(_) => continue,
+            Ok(player) => player,
+        };
+
+        health.0 = player_state.health;
+
+        if predicted && !caught_up {
+            continue;
+        }
+
+        if let Ok(mut shield_timeout) = shield_timeouts.get_mut(entity) {
+            **shield_timeout = timer_at(
+                Duration::from_millis(config.shield_timeout),
+                player_state.shield_cooldown_elapsed,
+            );
+        }
+
+        for child in children.iter() {
+            if let Ok(mut gun) = guns.get_mut(*child) {
+                gun.bullet_count = player_state.bullet_count;
+                gun.bullet_capacity = player_state.bullet_capacity;
+            }
+        }
+
+        let shield_duration = Duration::from_millis(config.shield_duration);
+        let shield = children.iter().find(|child| shields.contains(**child));
+
+        match (shield, &player_state.shield) {
+            (None, None) => {}
+            (Some(shield), None) => {
+                commands.entity(*shield).insert(Despawn);
+            }
+            (None, Some(shield_state)) => {
+                let shield = commands
+                    .spawn(ShieldBundle::new(
+                        shield_state.radius,
+                        timer_at(
+                            shield_duration,
+                            shield_duration.saturating_sub(shield_state.remaining),
+                        ),
+                    ))
+                    .id();
+                commands.entity(entity).add_child(shield);
+            }
+            (Some(shield), Some(shield_state)) => {
+                if let Ok(mut shield) = shields.get_mut(*shield) {
+                    shield.ttl = timer_at(
+                        shield_duration,
+                        shield_duration.saturating_sub(shield_state.remaining),
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// A one shot timer that has already run for `elapsed`
+fn timer_at(duration: Duration, elapsed: Duration) -> Timer {
+    let mut timer = Timer::new(duration, TimerMode::Once);
+    timer.tick(elapsed);
+    timer
 }
 
 fn update_bullets_from_game_state_event(
     mut commands: Commands,
-    mut bullets: Query<(Entity, &Bullet, &mut Transform, &mut Velocity)>,
+    mut bullets: Query<(Entity, Option<&mut SnapshotBuffer>), With<Bullet>>,
+    index: Res<EntityIndex>,
     mut events: EventReader<GameStateEvent>,
 ) {
-    match events.read().max_by(|a, b| a.timestamp.cmp(&b.timestamp)) {
+    let mut game_states: Vec<&GameStateEvent> = events.read().collect();
+    game_states.sort_by_key(|game_state| game_state.timestamp);
+
+    match game_states.last() {
         None => return,
-        Some(game_state) => {
-            let mut bullet_entities_by_id: HashMap<String, Entity> = bullets
-                .iter_mut()
-                .map(|(entity, bullet, _, _)| (bullet.id.to_string(), entity))
-                .collect();
-
-            for bullet_state in game_state.bullets.iter() {
-                bullet_entities_by_id.remove(&bullet_state.id);
-
-                match bullets
-                    .iter_mut()
-                    .find(|(_, b, _, _)| b.id == bullet_state.id)
-                {
-                    Some((_, _, mut transform, mut velocity)) => {
-                        transform.set_if_neq(bullet_state.transform);
-                        velocity.linvel = bullet_state.velocity.clone();
-                    }
-                    None => {
-                        commands.spawn(BulletBundle::new(
-                            Bullet {
-                                id: bullet_state.id.clone(),
-                            },
-                            bullet_state.transform.clone(),
-                            bullet_state.velocity.clone(),
-                        ));
-                    }
+        Some(latest) => {
+            // bullets we fired on our own have no network id, so they never show up here
+            let mut missing: HashSet<Entity> = bullets.iter().map(|(entity, _)| entity).collect();
+
+            for bullet_state in latest.bullets.iter() {
+                let entity = index.entity(bullet_state.id);
+                if entity.is_some_and(|entity| missing.remove(&entity)) {
+                    continue;
                 }
+
+                commands.spawn((
+                    BulletBundle::new(
+                        Bullet { shooter: default() },
+                        bullet_state.transform,
+                        bullet_state.velocity,
+                    ),
+                    bullet_state.id,
+                    SnapshotBuffer::new(Snapshot {
+                        timestamp: latest.timestamp,
+                        translation: bullet_state.transform.translation,
+                        rotation: bullet_state.transform.rotation,
+                        velocity: bullet_state.velocity,
+                    }),
+                ));
             }
 
-            for (_, entity) in bullet_entities_by_id {
+            for entity in missing {
                 commands.entity(entity).insert(Despawn);
             }
         }
     }
+
+    for game_state in game_states {
+        for bullet_state in game_state.bullets.iter() {
+            let entity = match index.entity(bullet_state.id) {
+                None => continue,
+                Some(entity) => entity,
+            };
+
+            if let Ok((_, Some(mut buffer))) = bullets.get_mut(entity) {
+                buffer.push(Snapshot {
+                    timestamp: game_state.timestamp,
+                    translation: bullet_state.transform.translation,
+                    rotation: bullet_state.transform.rotation,
+                    velocity: bullet_state.velocity,
+                });
+            }
+        }
+    }
 }
 
 fn handle_player_spawn_event(
@@ -361,7 +969,59 @@ fn handle_player_spawn_event(
     config: Res<AppConfig>,
     players: Query<&Player>,
     spawns: Query<&PlayerSpawn>,
+    mut network_ids: NetworkIdAllocator,
+    mut game_events: GameEvents,
 ) {
+    let client_ids: Vec<String> = events
+        .read()
+        .map(|event| event.client_id.to_string())
+        .collect();
+
+    for (client_id, spawn_id) in spawn_at_free_spawns(
+        &mut commands,
+        &mut network_ids,
+        &config,
+        &players,
+        &spawns,
+        client_ids,
+    ) {
+        game_events.send(GameEvent::PlayerSpawned {
+            client_id,
+            spawn_id,
+        });
+    }
+}
+
+/// Spawns the players of rollback sessions from inside a frame, so both
+/// peers spawn them on the same frame and at the same spawns
+pub(crate) fn spawn_players(
+    In(client_ids): In<Vec<String>>,
+    mut commands: Commands,
+    config: Res<AppConfig>,
+    players: Query<&Player>,
+    spawns: Query<&PlayerSpawn>,
+    mut network_ids: NetworkIdAllocator,
+) {
+    spawn_at_free_spawns(
+        &mut commands,
+        &mut network_ids,
+        &config,
+        &players,
+        &spawns,
+        client_ids,
+    );
+}
+
+/// Spawns a player for each client at the first spawns nobody is using, and
+/// tells which spawn each of them got. Clients that already have a player get none.
+fn spawn_at_free_spawns(
+    commands: &mut Commands,
+    network_ids: &mut NetworkIdAllocator,
+    config: &Res<AppConfig>,
+    players: &Query<&Player>,
+    spawns: &Query<&PlayerSpawn>,
+    client_ids: Vec<String>,
+) -> Vec<(String, String)> {
     let spawned_client_ids: HashSet<String> =
         players.iter().map(|p| p.client_id.to_string()).collect();
     let used_spawn_ids: HashSet<String> = players.iter().map(|p| p.spawn_id.to_string()).collect();
@@ -371,104 +1031,130 @@ fn handle_player_spawn_event(
         .collect::<Vec<&PlayerSpawn>>();
 
     let mut unused_spawns_iter = unused_spawns.iter();
+    let mut spawned = vec![];
 
-    for event in events.read() {
-        if spawned_client_ids.contains(&event.client_id.to_string()) {
+    for client_id in client_ids {
+        if spawned_client_ids.contains(&client_id) {
             println!(
                 "Ignoring spawn player event from client that already has a player. client-id: {}.",
-                event.client_id
+                client_id
             );
             continue;
         }
 
         match unused_spawns_iter.next() {
-            None => return,
-            Some(spawn) => spawn_player(
-                &mut commands,
-                &PlayerState {
-                    id: Uuid::new_v4().to_string(),
-                    spawn_id: spawn.id.to_string(),
-                    client_id: event.client_id.to_string(),
-                    radius: spawn.radius,
-                    color: spawn.color,
-                    position: spawn.position,
-                    velocity: Vec2::new(0., 0.),
-                },
-                &config,
-            ),
+            None => break,
+            Some(spawn) => {
+                spawn_player(
+                    commands,
+                    &PlayerState {
+                        id: network_ids.allocate(),
+                        spawn_id: spawn.id.to_string(),
+                        client_id: client_id.to_string(),
+                        radius: spawn.radius,
+                        color: spawn.color,
+                        position: spawn.position,
+                        velocity: Vec2::new(0., 0.),
+                        health: config.player_health,
+                        shield: None,
+                        shield_cooldown_elapsed: Duration::ZERO,
+                        bullet_count: config.starting_ammo,
+                        bullet_capacity: config.starting_ammo,
+                    },
+                    config,
+                );
+
+                spawned.push((client_id, spawn.id.to_string()));
+            }
         }
     }
+
+    spawned
 }
 
-fn handle_player_move_left_event(
-    config: Res<AppConfig>,
-    mut players: Query<(&Player, &mut Velocity)>,
-    mut events: EventReader<PlayerMoveLeftEvent>,
+fn handle_player_disconnected_event(
+    mut commands: Commands,
+    mut events: EventReader<PlayerDisconnectedEvent>,
+    index: Res<EntityIndex>,
 ) {
     for event in events.read() {
-        match players
-            .iter_mut()
-            .find(|(p, _)| p.client_id == event.client_id)
-        {
+        let player = match index.player(&event.client_id) {
             None => continue,
-            Some((_, mut velocity)) => {
-                if velocity.linvel.x < -config.player_max_move_speed {
-                    continue;
-                }
-                velocity.linvel.x += -config.player_move_speed;
-            }
+            Some(player) => player,
+        };
+
+        if let Some(player) = commands.get_entity(player) {
+            player.despawn_recursive();
         }
     }
 }
 
-fn handle_player_move_right_event(
-    config: Res<AppConfig>,
-    mut players: Query<(&Player, &mut Velocity)>,
-    mut events: EventReader<PlayerMoveRightEvent>,
+fn handle_player_input_event(
+    mut inputs: Query<&mut PlayerInput>,
+    mut events: EventReader<PlayerInputEvent>,
+    index: Res<EntityIndex>,
 ) {
     for event in events.read() {
-        match players
-            .iter_mut()
-            .find(|(p, _)| p.client_id == event.client_id)
-        {
+        let player = match index.player(&event.client_id) {
             None => continue,
-            Some((_, mut velocity)) => {
-                if velocity.linvel.x > config.player_max_move_speed {
-                    continue;
-                }
-                velocity.linvel.x += config.player_move_speed
-            }
+            Some(player) => player,
+        };
+
+        if let Ok(mut input) = inputs.get_mut(player) {
+            input.queue(event.state);
         }
     }
 }
 
-fn handle_player_jump_event(
+/// Runs before every tick, so each input state gets simulated for at least a tick
+fn apply_queued_inputs(mut inputs: Query<&mut PlayerInput>) {
+    for mut input in inputs.iter_mut() {
+        input.apply_queued();
+    }
+}
+
+pub(crate) fn move_players(
     config: Res<AppConfig>,
-    mut players: Query<(Entity, &Player, &mut ExternalImpulse)>,
-    mut events: EventReader<PlayerJumpEvent>,
-    rapier_context: Res<RapierContext>,
+    mut players: Query<(&PlayerInput, &mut Velocity)>,
+    time: Res<Time>,
 ) {
-    for event in events.read() {
-        match players
-            .iter_mut()
-            .find(|(_, p, _)| p.client_id == event.client_id)
-        {
-            None => continue,
-            Some((entity, _, mut impulse)) => {
-                if rapier_context.contact_pairs_with(entity).count() == 0 {
-                    continue;
-                };
+    for (input, mut velocity) in players.iter_mut() {
+        let move_axis = input.state.move_axis;
 
-                impulse.impulse.y += config.jump_amount
-            }
+        if move_axis < 0. && velocity.linvel.x < -config.player_max_move_speed {
+            continue;
+        }
+        if move_axis > 0. && velocity.linvel.x > config.player_max_move_speed {
+            continue;
         }
+
+        velocity.linvel.x += move_axis * config.player_move_speed * time.delta_seconds();
     }
 }
 
-fn auto_reload_gun(mut guns: Query<&mut Gun>) {
+pub(crate) fn handle_player_jump(
+    config: Res<AppConfig>,
+    mut players: Query<(Entity, &mut PlayerInput, &mut ExternalImpulse)>,
+    rapier_context: Res<RapierContext>,
+) {
+    for (entity, mut input, mut impulse) in players.iter_mut() {
+        if !input.jump_pressed {
+            continue;
+        }
+        input.jump_pressed = false;
+
+        if rapier_context.contact_pairs_with(entity).count() == 0 {
+            continue;
+        };
+
+        impulse.impulse.y += config.jump_amount
+    }
+}
+
+fn auto_reload_gun(mut guns: Query<&mut Gun>, config: Res<AppConfig>, time: Res<Time>) {
     for mut gun in guns.iter_mut() {
         if let Some(last_shot) = gun.last_shot {
-            if last_shot.elapsed().as_millis() > 1000 {
+            if time.elapsed() - last_shot > Duration::from_millis(config.reload_timeout) {
                 gun.bullet_count = gun.bullet_capacity;
             }
         }
@@ -481,100 +1167,240 @@ fn advance_shield_timeout(mut shield_timeouts: Query<&mut ShieldTimeout>, time:
     }
 }
 
-fn handle_player_shoot_event(
+#[allow(clippy::too_many_arguments)]
+fn handle_player_shoot(
     mut commands: Commands,
-    mut events: EventReader<PlayerShootEvent>,
     mut guns: Query<&mut Gun>,
-    mut players: Query<(&Player, &Children, &Transform)>,
+    mut players: Query<(Entity, &Player, &mut PlayerInput, &Children, &Transform)>,
+    targets: Query<(Entity, &Player, &PositionHistory)>,
+    mut healths: Query<&mut Health, With<Player>>,
+    rapier_context: Option<Res<RapierContext>>,
+    latencies: Res<Latencies>,
+    tick: Res<SimulationTick>,
     config: Res<AppConfig>,
+    time: Res<Time>,
+    fixed_time: Res<Time<Fixed>>,
+    mut network_ids: NetworkIdAllocator,
+    mut game_events: GameEvents,
 ) {
-    for event in events.read() {
-        match players
-            .iter_mut()
-            .find(|p| p.0.client_id == event.client_id)
-        {
-            None => continue,
-            Some((player, children, transform)) => {
-                for child in children.iter() {
-                    match guns.get_mut(*child) {
-                        Err(_) => continue,
-                        Ok(mut gun) => {
-                            if gun.bullet_count <= 0 {
-                                continue;
-                            }
-
-                            gun.bullet_count -= 1;
-                            gun.last_shot = Some(Instant::now());
-
-                            let bullet_half_length = 20.;
-                            let offset = player.radius + bullet_half_length + config.fudge_factor;
-                            let bullet_position =
-                                transform.translation.xy() + event.aim.clamp_length_min(offset);
-
-                            let velocity = Vec2::from(event.aim.normalize() * config.bullet_speed);
-                            let rotation = Quat::from_rotation_z(velocity.y.atan2(velocity.x));
-
-                            commands.spawn(BulletBundle::new(
-                                Bullet {
-                                    id: Uuid::new_v4().to_string(),
-                                },
-                                Transform {
-                                    translation: Vec3::new(
-                                        bullet_position.x,
-                                        bullet_position.y,
-                                        0.1,
-                                    ),
-                                    rotation,
-                                    ..default()
-                                },
-                                velocity,
-                            ));
+    for (entity, player, mut input, children, transform) in players.iter_mut() {
+        if !input.shoot_pressed {
+            continue;
+        }
+        input.shoot_pressed = false;
+
+        // only the direction counts, however far away the player aimed
+        let aim = input.shoot_aim.normalize_or_zero();
+        if aim == Vec2::ZERO {
+            continue;
+        }
+
+        for child in children.iter() {
+            match guns.get_mut(*child) {
+                Err(_) => continue,
+                Ok(mut gun) => {
+                    if gun.bullet_count <= 0 {
+                        continue;
+                    }
+
+                    gun.bullet_count -= 1;
+                    gun.last_shot = Some(time.elapsed());
+
+                    let offset = player.radius + BULLET_HALF_LENGTH + config.fudge_factor;
+                    let bullet_position = transform.translation.xy() + aim * offset;
+
+                    let velocity = aim * config.bullet_speed;
+                    let rotation = Quat::from_rotation_z(velocity.y.atan2(velocity.x));
+
+                    let bullet = Bullet {
+                        shooter: player.client_id.to_string(),
+                    };
+                    // The shooter saw everyone else as they were a round trip plus the
+                    // interpolation delay ago, and by now the bullet would have flown
+                    // that far in their eyes. Check that part of its path against
+                    // where everyone else was back then.
+                    if let Some(latency) = latencies.get(&player.client_id) {
+                        let (rewind, rewind_ticks) = lag_compensation_rewind(
+                            *latency + Duration::from_millis(config.interpolation_delay),
+                            Duration::from_millis(config.max_lag_compensation),
+                            fixed_time.timestep(),
+                        );
+
+                        let hit = lag_compensated_hit(
+                            bullet_position,
+                            aim,
+                            config.bullet_speed * rewind.as_secs_f32(),
+                            tick.saturating_sub(rewind_ticks),
+                            targets.iter().filter(|(target, _, _)| *target != entity),
+                            rapier_context.as_deref(),
+                        );
+
+                        if let Some((target, target_player, shielded)) = hit {
+                            hit_player(
+                                target,
+                                target_player,
+                                shielded,
+                                &bullet.shooter,
+                                &mut healths,
+                                &mut game_events,
+                            );
+
+                            // the bullet already hit, so it never gets to fly
+                            continue;
                         }
                     }
+
+                    let bullet_id = network_ids.allocate();
+                    if let Some(bullet_id) = bullet_id {
+                        game_events.send(GameEvent::BulletFired {
+                            bullet_id: *bullet_id,
+                            client_id: bullet.shooter.to_string(),
+                        });
+                    }
+
+                    let mut bullet = commands.spawn(BulletBundle::new(
+                        bullet,
+                        Transform {
+                            translation: Vec3::new(bullet_position.x, bullet_position.y, 0.1),
+                            rotation,
+                            ..default()
+                        },
+                        velocity,
+                    ));
+
+                    if let Some(bullet_id) = bullet_id {
+                        bullet.insert(bullet_id);
+                    }
                 }
             }
-        };
+        }
+    }
+}
+
+/// How far back in time a shooter who saw everyone else `behind` ago gets to
+/// shoot, and how many ticks that is. Never further back than we keep a
+/// history of positions for.
+fn lag_compensation_rewind(
+    behind: Duration,
+    max_lag_compensation: Duration,
+    timestep: Duration,
+) -> (Duration, u64) {
+    let rewind = behind.min(max_lag_compensation);
+    let ticks = (rewind.as_secs_f32() / timestep.as_secs_f32()).round() as u64;
+    (rewind, ticks)
+}
+
+/// The first of the targets, as they were at the given tick, that a bullet
+/// travelling `distance` from `origin` in `direction` would have hit before
+/// running into a wall, and whether its shield was up back then
+fn lag_compensated_hit<'a>(
+    origin: Vec2,
+    direction: Vec2,
+    distance: f32,
+    tick: u64,
+    targets: impl Iterator<Item = (Entity, &'a Player, &'a PositionHistory)>,
+    rapier_context: Option<&RapierContext>,
+) -> Option<(Entity, &'a Player, bool)> {
+    let (target, player, past, toi) = targets
+        .filter_map(|(entity, player, history)| {
+            let past = history.at(tick)?;
+            let radius = player.radius + BULLET_HALF_WIDTH;
+            let toi = ray_circle_toi(origin, direction, past.position, radius)?;
+            Some((entity, player, past, toi))
+        })
+        .filter(|(_, _, _, toi)| *toi <= distance)
+        .min_by(|(_, _, _, a), (_, _, _, b)| a.total_cmp(b))?;
+
+    let blocked = rapier_context.is_some_and(|rapier_context| {
+        rapier_context
+            .cast_ray(origin, direction, toi, true, QueryFilter::only_fixed())
+            .is_some()
+    });
+
+    match blocked {
+        true => None,
+        false => Some((target, player, past.shielded)),
+    }
+}
+
+/// How far along a ray starting at `origin` it first touches a circle, if it does at all
+fn ray_circle_toi(origin: Vec2, direction: Vec2, center: Vec2, radius: f32) -> Option<f32> {
+    let to_center = center - origin;
+    let along = to_center.dot(direction);
+    let closest_squared = to_center.length_squared() - along * along;
+    let radius_squared = radius * radius;
+
+    if closest_squared > radius_squared {
+        return None;
     }
+
+    let half_chord = (radius_squared - closest_squared).sqrt();
+    if along + half_chord < 0. {
+        return None;
+    }
+
+    Some((along - half_chord).max(0.))
 }
 
-fn handle_player_block_event(
+/// A bullet from `attacker` reached a player, who takes the damage unless
+/// their shield was up
+fn hit_player(
+    target: Entity,
+    player: &Player,
+    shielded: bool,
+    attacker: &str,
+    healths: &mut Query<&mut Health, With<Player>>,
+    game_events: &mut GameEvents,
+) {
+    if shielded {
+        game_events.send(GameEvent::ShieldBlocked {
+            client_id: player.client_id.to_string(),
+            attacker: attacker.to_string(),
+        });
+        return;
+    }
+
+    let mut health = match healths.get_mut(target) {
+        Ok(health) => health,
+        Err(_) => return,
+    };
+    health.0 -= BULLET_DAMAGE;
+
+    game_events.send(GameEvent::PlayerHit {
+        client_id: player.client_id.to_string(),
+        attacker: attacker.to_string(),
+        damage: BULLET_DAMAGE,
+    });
+}
+
+fn handle_player_block(
     mut commands: Commands,
-    mut events: EventReader<PlayerBlockEvent>,
-    mut players: Query<(Entity, &Player, &mut ShieldTimeout)>,
+    mut players: Query<(Entity, &Player, &mut PlayerInput, &mut ShieldTimeout)>,
     config: Res<AppConfig>,
 ) {
-    for event in events.read() {
-        match players
-            .iter_mut()
-            .find(|p| p.1.client_id == event.client_id)
-        {
-            None => continue,
-            Some((entity, player, mut shield_timeout)) => {
-                if !shield_timeout.finished() {
-                    continue;
-                }
+    for (entity, player, mut input, mut shield_timeout) in players.iter_mut() {
+        if !input.block_pressed {
+            continue;
+        }
+        input.block_pressed = false;
 
-                shield_timeout.reset();
-                let radius = player.radius + 10.;
-                let shield = commands
-                    .spawn(ShieldBundle {
-                        shield: Shield {
-                            radius,
-                            ttl: Timer::new(
-                                Duration::from_millis(config.shield_duration),
-                                TimerMode::Once,
-                            ),
-                        },
-                        collider: Collider::ball(radius),
-                        transform: TransformBundle::from_transform(Transform::from_translation(
-                            Vec3::new(0., 0., 0.2),
-                        )),
-                    })
-                    .id();
+        if !shield_timeout.finished() {
+            continue;
+        }
 
-                commands.entity(entity).add_child(shield)
-            }
-        };
+        shield_timeout.reset();
+        let radius = player.radius + 10.;
+        let shield = commands
+            .spawn(ShieldBundle::new(
+                radius,
+                Timer::new(
+                    Duration::from_millis(config.shield_duration),
+                    TimerMode::Once,
+                ),
+            ))
+            .id();
+
+        commands.entity(entity).add_child(shield);
     }
 }
 
@@ -628,46 +1454,102 @@ fn bullets_despawn_on_collision_with_anything(
 
 fn health_decreases_on_collision_with_bullets(
     mut collision_events: EventReader<CollisionEvent>,
-    players: Query<Entity, With<Player>>,
+    players: Query<&Player>,
     mut healths: Query<&mut Health, With<Player>>,
-    bullets: Query<Entity, With<Bullet>>,
+    bullets: Query<&Bullet>,
     shields: Query<&Parent, With<Shield>>,
+    mut game_events: GameEvents,
 ) {
     for collision in collision_events.read() {
         match collision {
             CollisionEvent::Stopped(_, _, _) => continue,
             CollisionEvent::Started(e1, e2, _) => {
-                if bullets.get(*e1).or_else(|_| bullets.get(*e2)).is_err() {
+                let (bullet, other) = match (bullets.get(*e1), bullets.get(*e2)) {
+                    (Ok(bullet), _) => (bullet, *e2),
+                    (_, Ok(bullet)) => (bullet, *e1),
+                    _ => continue,
+                };
+
+                if let Ok(shield_parent) = shields.get(other) {
+                    let target = shield_parent.get();
+                    if let Ok(player) = players.get(target) {
+                        hit_player(
+                            target,
+                            player,
+                            true,
+                            &bullet.shooter,
+                            &mut healths,
+                            &mut game_events,
+                        );
+                    }
                     continue;
                 }
 
-                let player = match players.get(*e1).or_else(|_| players.get(*e2)) {
+                let player = match players.get(other) {
                     Ok(player) => player,
                     Err(_) => continue,
                 };
 
+                // the bullet touching the shield already counts as the block
                 let shield = shields
                     .iter()
-                    .find(|shield_parent| shield_parent.get() == player);
+                    .find(|shield_parent| shield_parent.get() == other);
                 if shield.is_some() {
                     continue;
                 }
 
-                let mut health = match healths.get_mut(player) {
-                    Ok(health) => health,
-                    Err(_) => continue,
-                };
-                health.0 -= 3;
-                // commands.entity(player).insert(Despawn);
+                hit_player(
+                    other,
+                    player,
+                    false,
+                    &bullet.shooter,
+                    &mut healths,
+                    &mut game_events,
+                );
             }
         }
     }
 }
 
-fn despawn_things_with_0_or_less_health(mut commands: Commands, healthy: Query<(Entity, &Health)>) {
-    for (entity, health) in healthy.iter() {
+fn record_position_history(
+    mut players: Query<(Entity, &Transform, &mut PositionHistory)>,
+    shields: Query<&Parent, With<Shield>>,
+    tick: Res<SimulationTick>,
+    config: Res<AppConfig>,
+    fixed_time: Res<Time<Fixed>>,
+) {
+    let max_lag_compensation = Duration::from_millis(config.max_lag_compensation);
+    let ticks_to_keep = (max_lag_compensation.as_secs_f32() / fixed_time.timestep().as_secs_f32())
+        .ceil() as usize
+        + 1;
+
+    for (entity, transform, mut history) in players.iter_mut() {
+        history.push_back(PastPosition {
+            tick: **tick,
+            position: transform.translation.xy(),
+            shielded: shields.iter().any(|parent| parent.get() == entity),
+        });
+
+        while history.len() > ticks_to_keep {
+            history.pop_front();
+        }
+    }
+}
+
+fn despawn_things_with_0_or_less_health(
+    mut commands: Commands,
+    healthy: Query<(Entity, &Health, Option<&Player>), Without<Despawn>>,
+    mut game_events: GameEvents,
+) {
+    for (entity, health, player) in healthy.iter() {
         if health.0 <= 0 {
             commands.entity(entity).insert(Despawn);
+
+            if let Some(player) = player {
+                game_events.send(GameEvent::PlayerDied {
+                    client_id: player.client_id.to_string(),
+                });
+            }
         }
     }
 }
@@ -713,7 +1595,7 @@ fn enable_or_disable_player_jumping(
     }
 }
 
-fn despawn_things_that_need_despawning(
+pub(crate) fn despawn_things_that_need_despawning(
     mut commands: Commands,
     entities: Query<Entity, With<Despawn>>,
 ) {
@@ -721,3 +1603,127 @@ fn despawn_things_that_need_despawning(
         commands.entity(entity).despawn_recursive();
     }
 }
+
+/// Updates the entity index with what was spawned and despawned since it last ran
+fn index_entities() -> SystemConfigs {
+    (index_network_ids, index_players).into_configs()
+}
+
+/// Keeps the network ids in the entity index in step with the entities that have one
+fn index_network_ids(
+    mut index: ResMut<EntityIndex>,
+    added: Query<(Entity, &NetworkId), Added<NetworkId>>,
+    mut removed: RemovedComponents<NetworkId>,
+) {
+    for entity in removed.read() {
+        index.network_ids.remove(entity);
+    }
+
+    for (entity, id) in added.iter() {
+        index.network_ids.insert(*id, entity);
+    }
+}
+
+/// Keeps the client ids in the entity index in step with the players
+fn index_players(
+    mut index: ResMut<EntityIndex>,
+    added: Query<(Entity, &Player), Added<Player>>,
+    mut removed: RemovedComponents<Player>,
+) {
+    for entity in removed.read() {
+        index.client_ids.remove(entity);
+    }
+
+    for (entity, player) in added.iter() {
+        index
+            .client_ids
+            .insert(player.client_id.to_string(), entity);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rays_touch_circles_where_they_first_enter_them() {
+        let toi = |center, radius| ray_circle_toi(Vec2::ZERO, Vec2::X, center, radius);
+
+        assert_eq!(toi(Vec2::new(10., 0.), 2.), Some(8.));
+        assert_eq!(toi(Vec2::new(10., 5.), 2.), None);
+        assert_eq!(toi(Vec2::new(-10., 0.), 2.), None);
+        // just grazing the circle
+        assert_eq!(toi(Vec2::new(10., 2.), 2.), Some(10.));
+        // starting out inside it
+        assert_eq!(toi(Vec2::new(1., 0.), 2.), Some(0.));
+    }
+
+    #[test]
+    fn rewinds_no_further_than_max_lag_compensation() {
+        let timestep = Duration::from_secs_f64(1. / 64.);
+        let rewind = |behind| {
+            lag_compensation_rewind(
+                Duration::from_millis(behind),
+                Duration::from_millis(200),
+                timestep,
+            )
+        };
+
+        assert_eq!(rewind(0), (Duration::ZERO, 0));
+        assert_eq!(rewind(150), (Duration::from_millis(150), 10));
+        assert_eq!(rewind(500), (Duration::from_millis(200), 13));
+    }
+
+    fn player(client_id: &str) -> Player {
+        Player {
+            spawn_id: String::new(),
+            client_id: client_id.to_string(),
+            radius: 10.,
+            color: Color::WHITE,
+        }
+    }
+
+    fn history(positions: &[(u64, Vec2, bool)]) -> PositionHistory {
+        PositionHistory(
+            positions
+                .iter()
+                .map(|&(tick, position, shielded)| PastPosition {
+                    tick,
+                    position,
+                    shielded,
+                })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn lag_compensated_shots_hit_where_targets_were() {
+        let (near, far) = (player("near"), player("far"));
+        let near_history = history(&[
+            (10, Vec2::new(100., 0.), false),
+            (20, Vec2::new(100., 50.), false),
+        ]);
+        let far_history = history(&[
+            (10, Vec2::new(150., 0.), true),
+            (20, Vec2::new(150., 0.), true),
+        ]);
+        let targets = || {
+            [
+                (Entity::from_raw(1), &near, &near_history),
+                (Entity::from_raw(2), &far, &far_history),
+            ]
+            .into_iter()
+        };
+        let hit = |distance, tick| {
+            lag_compensated_hit(Vec2::ZERO, Vec2::X, distance, tick, targets(), None)
+                .map(|(entity, player, shielded)| (entity, player.client_id.as_str(), shielded))
+        };
+
+        assert_eq!(hit(200., 10), Some((Entity::from_raw(1), "near", false)));
+        // the near target has moved out of the way since
+        assert_eq!(hit(200., 20), Some((Entity::from_raw(2), "far", true)));
+        // the bullet would not have got that far yet
+        assert_eq!(hit(50., 10), None);
+        assert_eq!(hit(120., 20), None);
+    }
+}
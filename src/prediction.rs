@@ -0,0 +1,315 @@
+use std::collections::VecDeque;
+
+use bevy::{ecs::schedule::ScheduleLabel, prelude::*};
+use bevy_rapier2d::prelude::*;
+
+use crate::{
+    events::InputState,
+    manage_state::{
+        handle_player_jump, move_players, reset_vertical_impulse, GameStateEvent, Player,
+        PlayerInput, Predicted,
+    },
+    AppConfig, GameState,
+};
+
+/// How many ticks of predictions we keep around while waiting for the server
+/// to acknowledge them. Anything older than this is assumed to be lost.
+const MAX_HISTORY: usize = 256;
+
+/// How far, in pixels, the server may have put our player from where we
+/// predicted before our inputs get replayed from where the server says we were
+const MISPREDICTION_TOLERANCE: f32 = 1.;
+
+/// Moves the local player as soon as we press a key instead of waiting for the
+/// server to tell us where we are. When a game state shows we guessed wrong,
+/// our player goes back to where the server had it after the last input it
+/// simulated, and the inputs it hasn't simulated yet are replayed on top.
+pub struct PredictionPlugin;
+
+impl Plugin for PredictionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PredictionHistory>()
+            .add_systems(
+                First,
+                (
+                    mark_local_player_as_predicted,
+                    reconcile_local_player,
+                    replay_unacknowledged_inputs.run_if(resource_exists::<RapierContext>),
+                )
+                    .chain()
+                    .run_if(in_state(GameState::Round)),
+            )
+            .add_systems(
+                FixedPostUpdate,
+                record_predicted_state.run_if(in_state(GameState::Round)),
+            )
+            // Only what moves our player gets replayed. Shooting, shields and
+            // everyone else's doings would happen a second time otherwise.
+            .configure_sets(
+                ReplayTick,
+                (
+                    PhysicsSet::SyncBackend,
+                    PhysicsSet::StepSimulation,
+                    PhysicsSet::Writeback,
+                )
+                    .chain(),
+            )
+            .add_systems(
+                ReplayTick,
+                (
+                    (reset_vertical_impulse, move_players, handle_player_jump)
+                        .chain()
+                        .before(PhysicsSet::SyncBackend),
+                    RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsSet::SyncBackend)
+                        .in_set(PhysicsSet::SyncBackend),
+                    RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsSet::StepSimulation)
+                        .in_set(PhysicsSet::StepSimulation),
+                    RapierPhysicsPlugin::<NoUserData>::get_systems(PhysicsSet::Writeback)
+                        .in_set(PhysicsSet::Writeback),
+                ),
+            );
+    }
+}
+
+/// Simulates one tick of our player's movement again, in place of the fixed timestep schedules
+#[derive(ScheduleLabel, Clone, Debug, PartialEq, Eq, Hash)]
+struct ReplayTick;
+
+/// What the local player looked like at the end of a tick simulated with `input`
+struct PredictedState {
+    input: InputState,
+    translation: Vec3,
+    velocity: Vec2,
+}
+
+/// Where the server had our player after the last input state it simulated,
+/// when that isn't where we predicted it would be
+struct Misprediction {
+    entity: Entity,
+    translation: Vec3,
+    velocity: Vec2,
+    acked: InputState,
+}
+
+#[derive(Resource, Default)]
+struct PredictionHistory {
+    /// One for every tick we simulated, oldest first
+    states: VecDeque<PredictedState>,
+    misprediction: Option<Misprediction>,
+}
+
+fn mark_local_player_as_predicted(
+    mut commands: Commands,
+    config: Res<AppConfig>,
+    players: Query<(Entity, &Player), Without<Predicted>>,
+) {
+    for (entity, _) in players
+        .iter()
+        .filter(|(_, p)| p.client_id == config.client_id)
+    {
+        commands.entity(entity).insert(Predicted);
+    }
+}
+
+/// Runs after every tick, once the physics has moved our player
+fn record_predicted_state(
+    config: Res<AppConfig>,
+    mut history: ResMut<PredictionHistory>,
+    players: Query<(&Player, &PlayerInput, &Transform, &Velocity), With<Predicted>>,
+) {
+    let (_, input, transform, velocity) = match players
+        .iter()
+        .find(|(p, _, _, _)| p.client_id == config.client_id)
+    {
+        None => return,
+        Some(player) => player,
+    };
+
+    let input = input.state();
+
+    // no new input arrived in time for this tick, so the last one is still in effect
+    if history
+        .states
+        .back()
+        .is_some_and(|s| s.input.tick == input.tick)
+    {
+        history.states.pop_back();
+    }
+
+    history.states.push_back(PredictedState {
+        input,
+        translation: transform.translation,
+        velocity: velocity.linvel,
+    });
+
+    while history.states.len() > MAX_HISTORY {
+        history.states.pop_front();
+    }
+}
+
+fn reconcile_local_player(
+    config: Res<AppConfig>,
+    mut events: EventReader<GameStateEvent>,
+    mut history: ResMut<PredictionHistory>,
+    mut players: Query<(Entity, &Player, &mut Transform, &mut Velocity), With<Predicted>>,
+) {
+    let game_state = match events.read().max_by(|a, b| a.timestamp.cmp(&b.timestamp)) {
+        None => return,
+        Some(game_state) => game_state,
+    };
+
+    let server_state = match game_state
+        .players
+        .iter()
+        .find(|p| p.client_id == config.client_id)
+    {
+        None => return,
+        Some(server_state) => server_state,
+    };
+
+    let (entity, _, mut transform, mut velocity) = match players
+        .iter_mut()
+        .find(|(_, p, _, _)| p.client_id == config.client_id)
+    {
+        None => return,
+        Some(player) => player,
+    };
+
+    let ack = server_state
+        .id
+        .and_then(|id| game_state.input_acks.get(&id))
+        .copied()
+        .unwrap_or(0);
+
+    // forget every prediction the server has caught up with, but keep the
+    // one for the last input it simulated to tell how far off we were
+    while history.states.front().is_some_and(|s| s.input.tick < ack) {
+        history.states.pop_front();
+    }
+
+    let acked = match history.states.front() {
+        Some(acked) if acked.input.tick == ack => acked,
+        _ => {
+            // we have nothing to replay from, so the server knows best
+            transform.translation = server_state.position;
+            velocity.linvel = server_state.velocity;
+            history.states.clear();
+            return;
+        }
+    };
+
+    if acked.translation.distance(server_state.position) <= MISPREDICTION_TOLERANCE {
+        return;
+    }
+
+    history.misprediction = Some(Misprediction {
+        entity,
+        translation: server_state.position,
+        velocity: server_state.velocity,
+        acked: acked.input,
+    });
+}
+
+/// Puts our player where the server had it, and simulates its movement with
+/// every input state the server hasn't simulated yet. Everything else the
+/// replayed ticks moved goes back to where it was.
+fn replay_unacknowledged_inputs(world: &mut World) {
+    let misprediction = match world
+        .resource_mut::<PredictionHistory>()
+        .misprediction
+        .take()
+    {
+        None => return,
+        Some(misprediction) => misprediction,
+    };
+
+    let entity = misprediction.entity;
+    let input = match world.get::<PlayerInput>(entity) {
+        None => return,
+        Some(input) => input.clone(),
+    };
+
+    let mut bodies = world.query::<(Entity, &Transform, &Velocity)>();
+    let moved: Vec<(Entity, Transform, Velocity)> = bodies
+        .iter(world)
+        .filter(|(other, _, _)| *other != entity)
+        .map(|(other, transform, velocity)| (other, *transform, *velocity))
+        .collect();
+    let fixed_time = *world.resource::<Time<Fixed>>();
+
+    // the physics tells about collisions as it steps, and the ones from
+    // replayed ticks mustn't reach the systems acting on them
+    let collision_events = world.remove_resource::<Events<CollisionEvent>>();
+    let contact_force_events = world.remove_resource::<Events<ContactForceEvent>>();
+    world.init_resource::<Events<CollisionEvent>>();
+    world.init_resource::<Events<ContactForceEvent>>();
+
+    world.entity_mut(entity).insert((
+        Transform::from_translation(misprediction.translation),
+        Velocity::linear(misprediction.velocity),
+        PlayerInput::holding(misprediction.acked),
+    ));
+
+    let unacknowledged: Vec<InputState> = world
+        .resource::<PredictionHistory>()
+        .states
+        .iter()
+        .skip(1)
+        .map(|s| s.input)
+        .collect();
+    let mut replayed = VecDeque::new();
+
+    for state in unacknowledged {
+        if let Some(mut input) = world.get_mut::<PlayerInput>(entity) {
+            input.apply(state);
+        }
+
+        let mut fixed_time = world.resource_mut::<Time<Fixed>>();
+        let timestep = fixed_time.timestep();
+        fixed_time.advance_by(timestep);
+        let fixed_time = fixed_time.as_generic();
+        *world.resource_mut::<Time>() = fixed_time;
+
+        world.run_schedule(ReplayTick);
+
+        let (transform, velocity) = match (
+            world.get::<Transform>(entity),
+            world.get::<Velocity>(entity),
+        ) {
+            (Some(transform), Some(velocity)) => (*transform, *velocity),
+            _ => break,
+        };
+        replayed.push_back(PredictedState {
+            input: state,
+            translation: transform.translation,
+            velocity: velocity.linvel,
+        });
+    }
+
+    // the physics moves everything back on the next tick, since it sees these as changed
+    for (other, transform, velocity) in moved {
+        if let Some(mut other) = world.get_entity_mut(other) {
+            other.insert((transform, velocity));
+        }
+    }
+    world.entity_mut(entity).insert(input);
+
+    if let Some(events) = collision_events {
+        world.insert_resource(events);
+    }
+    if let Some(events) = contact_force_events {
+        world.insert_resource(events);
+    }
+
+    *world.resource_mut::<Time<Fixed>>() = fixed_time;
+    let virtual_time = world.resource::<Time<Virtual>>().as_generic();
+    *world.resource_mut::<Time>() = virtual_time;
+
+    let mut history = world.resource_mut::<PredictionHistory>();
+    history.states.truncate(1);
+    if let Some(acked) = history.states.front_mut() {
+        acked.translation = misprediction.translation;
+        acked.velocity = misprediction.velocity;
+    }
+    history.states.append(&mut replayed);
+}
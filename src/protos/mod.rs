@@ -1,14 +1,30 @@
+use std::time::Duration;
+
 use bevy::{prelude::default, transform::components::Transform};
 
-use crate::events::{
-    PlayerBlockEvent, PlayerJumpEvent, PlayerMoveLeftEvent, PlayerMoveRightEvent, PlayerShootEvent,
-    PlayerSpawnEvent,
+use crate::{
+    client::{Roster, RosterEntry},
+    events::{
+        Card, CardPickedEvent, GameEvent, InputState, PickedCardsEvent, PlayerInputEvent,
+        PlayerSpawnEvent, RematchEvent, RoomInfo, RoomRequestEvent, RoundStatusEvent,
+    },
+    manage_state::NetworkId,
+    round::{MatchFormat, RoundPhase, Scoreboard},
 };
 
 pub mod generated {
     include!(concat!(env!("OUT_DIR"), "/protos/mod.rs"));
 }
 
+/// Bump this whenever applesauce.proto changes in a way older builds can't read
+pub(crate) const PROTOCOL_VERSION: u32 = 13;
+
+/// The most characters a chat message may have. The server cuts off the rest.
+pub(crate) const MAX_CHAT_LENGTH: usize = 200;
+
+/// Clients and servers refuse to talk to a different build than their own
+pub(crate) const BUILD_ID: &str = concat!(env!("CARGO_PKG_NAME"), "-", env!("CARGO_PKG_VERSION"));
+
 impl From<bevy::prelude::Vec2> for generated::applesauce::Vec2 {
     fn from(v: bevy::prelude::Vec2) -> Self {
         Self {
@@ -119,10 +135,8 @@ impl Into<protobuf::MessageField<generated::applesauce::Color>> for generated::a
 }
 
 impl From<&PlayerSpawnEvent> for generated::applesauce::Input {
-    fn from(value: &PlayerSpawnEvent) -> Self {
+    fn from(_: &PlayerSpawnEvent) -> Self {
         generated::applesauce::Input {
-            id: value.id.to_string(),
-            client_id: value.client_id.to_string(),
             inner: Some(generated::applesauce::input::Inner::Spawn(
                 generated::applesauce::Spawn::default(),
             )),
@@ -131,96 +145,411 @@ impl From<&PlayerSpawnEvent> for generated::applesauce::Input {
     }
 }
 
-impl From<&PlayerMoveLeftEvent> for generated::applesauce::Input {
-    fn from(value: &PlayerMoveLeftEvent) -> Self {
+impl From<&PlayerInputEvent> for generated::applesauce::Input {
+    fn from(value: &PlayerInputEvent) -> Self {
         generated::applesauce::Input {
-            id: value.id.to_string(),
-            client_id: value.client_id.to_string(),
-            inner: Some(generated::applesauce::input::Inner::MoveLeft(
-                generated::applesauce::MoveLeft::default(),
+            inner: Some(generated::applesauce::input::Inner::State(
+                value.state.into(),
             )),
             special_fields: default(),
         }
     }
 }
 
-impl From<&PlayerMoveRightEvent> for generated::applesauce::Input {
-    fn from(value: &PlayerMoveRightEvent) -> Self {
+impl From<&RematchEvent> for generated::applesauce::Input {
+    fn from(_: &RematchEvent) -> Self {
         generated::applesauce::Input {
-            id: value.id.to_string(),
-            client_id: value.client_id.to_string(),
-            inner: Some(generated::applesauce::input::Inner::MoveRight(
-                generated::applesauce::MoveRight::default(),
+            inner: Some(generated::applesauce::input::Inner::Rematch(
+                generated::applesauce::Rematch::default(),
             )),
             special_fields: default(),
         }
     }
 }
 
-impl From<&PlayerJumpEvent> for generated::applesauce::Input {
-    fn from(value: &PlayerJumpEvent) -> Self {
+impl From<&CardPickedEvent> for generated::applesauce::Input {
+    fn from(value: &CardPickedEvent) -> Self {
         generated::applesauce::Input {
-            id: value.id.to_string(),
-            client_id: value.client_id.to_string(),
-            inner: Some(generated::applesauce::input::Inner::Jump(
-                generated::applesauce::Jump::default(),
+            inner: Some(generated::applesauce::input::Inner::PickCard(
+                generated::applesauce::PickCard {
+                    card: generated::applesauce::Card::from(value.card).into(),
+                    special_fields: default(),
+                },
             )),
             special_fields: default(),
         }
     }
 }
 
-impl From<&PlayerShootEvent> for generated::applesauce::Input {
-    fn from(value: &PlayerShootEvent) -> Self {
-        generated::applesauce::Input {
-            id: value.id.to_string(),
-            client_id: value.client_id.to_string(),
-            inner: Some(generated::applesauce::input::Inner::Shoot(
-                generated::applesauce::Shoot {
-                    aim: generated::applesauce::Vec3::from(value.aim).into(),
-                    special_fields: default(),
-                },
-            )),
+impl From<&RoomRequestEvent> for generated::applesauce::client_message::Inner {
+    fn from(value: &RoomRequestEvent) -> Self {
+        use generated::applesauce::{self, client_message::Inner, join_room::Target};
+
+        let join = |target| {
+            Inner::JoinRoom(applesauce::JoinRoom {
+                target: Some(target),
+                special_fields: default(),
+            })
+        };
+
+        match value {
+            RoomRequestEvent::List => Inner::ListRooms(applesauce::ListRooms::default()),
+            RoomRequestEvent::Create {
+                name,
+                private,
+                level,
+            } => Inner::CreateRoom(applesauce::CreateRoom {
+                name: name.to_string(),
+                private: *private,
+                level: level.clone().unwrap_or_default(),
+                special_fields: default(),
+            }),
+            RoomRequestEvent::JoinById(room_id) => join(Target::RoomId(room_id.to_string())),
+            RoomRequestEvent::JoinByCode(join_code) => {
+                join(Target::JoinCode(join_code.to_string()))
+            }
+        }
+    }
+}
+
+impl From<RoomInfo> for generated::applesauce::RoomInfo {
+    fn from(value: RoomInfo) -> Self {
+        Self {
+            id: value.id,
+            name: value.name,
+            player_count: value.player_count,
             special_fields: default(),
         }
     }
 }
 
-impl From<&PlayerBlockEvent> for generated::applesauce::Input {
-    fn from(value: &PlayerBlockEvent) -> Self {
-        generated::applesauce::Input {
-            id: value.id.to_string(),
-            client_id: value.client_id.to_string(),
-            inner: Some(generated::applesauce::input::Inner::Block(
-                generated::applesauce::Block::default(),
-            )),
+impl From<generated::applesauce::RoomInfo> for RoomInfo {
+    fn from(value: generated::applesauce::RoomInfo) -> Self {
+        Self {
+            id: value.id,
+            name: value.name,
+            player_count: value.player_count,
+        }
+    }
+}
+
+impl From<InputState> for generated::applesauce::InputState {
+    fn from(value: InputState) -> Self {
+        Self {
+            tick: value.tick,
+            move_axis: value.move_axis,
+            jump: value.jump,
+            shoot: value.shoot,
+            block: value.block,
+            aim: generated::applesauce::Vec2::from(value.aim).into(),
+            special_fields: default(),
+        }
+    }
+}
+
+impl From<generated::applesauce::InputState> for InputState {
+    /// Clients can send anything, and NaNs or infinities would end up in the
+    /// physics. Those count as not moving and not aiming anywhere.
+    fn from(value: generated::applesauce::InputState) -> Self {
+        let move_axis = match value.move_axis.is_finite() {
+            true => value.move_axis.clamp(-1., 1.),
+            false => 0.,
+        };
+        let aim: bevy::prelude::Vec2 = value.aim.into_option().map(Into::into).unwrap_or_default();
+
+        Self {
+            tick: value.tick,
+            move_axis,
+            jump: value.jump,
+            shoot: value.shoot,
+            block: value.block,
+            aim: aim.normalize_or_zero(),
+        }
+    }
+}
+
+impl From<&GameEvent> for generated::applesauce::game_event::Inner {
+    fn from(value: &GameEvent) -> Self {
+        use generated::applesauce;
+
+        match value.clone() {
+            GameEvent::BulletFired {
+                bullet_id,
+                client_id,
+            } => Self::BulletFired(applesauce::BulletFired {
+                bullet_id,
+                client_id,
+                special_fields: default(),
+            }),
+            GameEvent::PlayerHit {
+                client_id,
+                attacker,
+                damage,
+            } => Self::PlayerHit(applesauce::PlayerHit {
+                client_id,
+                attacker,
+                damage,
+                special_fields: default(),
+            }),
+            GameEvent::ShieldBlocked {
+                client_id,
+                attacker,
+            } => Self::ShieldBlocked(applesauce::ShieldBlocked {
+                client_id,
+                attacker,
+                special_fields: default(),
+            }),
+            GameEvent::PlayerDied { client_id } => Self::PlayerDied(applesauce::PlayerDied {
+                client_id,
+                special_fields: default(),
+            }),
+            GameEvent::PlayerSpawned {
+                client_id,
+                spawn_id,
+            } => Self::PlayerSpawned(applesauce::PlayerSpawned {
+                client_id,
+                spawn_id,
+                special_fields: default(),
+            }),
+        }
+    }
+}
+
+impl From<generated::applesauce::game_event::Inner> for GameEvent {
+    fn from(value: generated::applesauce::game_event::Inner) -> Self {
+        use generated::applesauce::game_event::Inner;
+
+        match value {
+            Inner::BulletFired(fired) => Self::BulletFired {
+                bullet_id: fired.bullet_id,
+                client_id: fired.client_id,
+            },
+            Inner::PlayerHit(hit) => Self::PlayerHit {
+                client_id: hit.client_id,
+                attacker: hit.attacker,
+                damage: hit.damage,
+            },
+            Inner::ShieldBlocked(blocked) => Self::ShieldBlocked {
+                client_id: blocked.client_id,
+                attacker: blocked.attacker,
+            },
+            Inner::PlayerDied(died) => Self::PlayerDied {
+                client_id: died.client_id,
+            },
+            Inner::PlayerSpawned(spawned) => Self::PlayerSpawned {
+                client_id: spawned.client_id,
+                spawn_id: spawned.spawn_id,
+            },
+        }
+    }
+}
+
+impl From<generated::applesauce::Roster> for Roster {
+    fn from(value: generated::applesauce::Roster) -> Self {
+        Self(
+            value
+                .players
+                .into_iter()
+                .map(|entry| {
+                    (
+                        NetworkId(entry.id),
+                        RosterEntry {
+                            client_id: entry.client_id,
+                            display_name: entry.display_name,
+                            spawn_id: entry.spawn_id,
+                        },
+                    )
+                })
+                .collect(),
+        )
+    }
+}
+
+impl From<RoundPhase> for generated::applesauce::RoundPhase {
+    fn from(value: RoundPhase) -> Self {
+        match value {
+            RoundPhase::Playing => Self::ROUND_PHASE_PLAYING,
+            RoundPhase::Results => Self::ROUND_PHASE_RESULTS,
+            RoundPhase::PickingCards => Self::ROUND_PHASE_PICKING_CARDS,
+            RoundPhase::MatchOver => Self::ROUND_PHASE_MATCH_OVER,
+        }
+    }
+}
+
+impl From<generated::applesauce::RoundPhase> for RoundPhase {
+    fn from(value: generated::applesauce::RoundPhase) -> Self {
+        match value {
+            generated::applesauce::RoundPhase::ROUND_PHASE_PLAYING => Self::Playing,
+            generated::applesauce::RoundPhase::ROUND_PHASE_RESULTS => Self::Results,
+            generated::applesauce::RoundPhase::ROUND_PHASE_PICKING_CARDS => Self::PickingCards,
+            generated::applesauce::RoundPhase::ROUND_PHASE_MATCH_OVER => Self::MatchOver,
+        }
+    }
+}
+
+impl From<Card> for generated::applesauce::Card {
+    fn from(value: Card) -> Self {
+        match value {
+            Card::MoveFaster => Self::CARD_MOVE_FASTER,
+            Card::MoreDamage => Self::CARD_MORE_DAMAGE,
+        }
+    }
+}
+
+impl From<generated::applesauce::Card> for Card {
+    fn from(value: generated::applesauce::Card) -> Self {
+        match value {
+            generated::applesauce::Card::CARD_MOVE_FASTER => Self::MoveFaster,
+            generated::applesauce::Card::CARD_MORE_DAMAGE => Self::MoreDamage,
+        }
+    }
+}
+
+impl From<generated::applesauce::PickedCards> for PickedCardsEvent {
+    fn from(value: generated::applesauce::PickedCards) -> Self {
+        Self {
+            cards: value
+                .players
+                .into_iter()
+                .map(|player| {
+                    let cards = player
+                        .cards
+                        .into_iter()
+                        .map(|card| card.enum_value_or_default().into())
+                        .collect();
+                    (player.client_id, cards)
+                })
+                .collect(),
+        }
+    }
+}
+
+impl From<MatchFormat> for generated::applesauce::MatchFormat {
+    fn from(value: MatchFormat) -> Self {
+        use generated::applesauce::match_format::Inner;
+
+        Self {
+            inner: Some(match value {
+                MatchFormat::FirstTo(rounds) => Inner::FirstTo(rounds),
+                MatchFormat::BestOf(rounds) => Inner::BestOf(rounds),
+            }),
             special_fields: default(),
         }
     }
 }
 
-impl From<generated::applesauce::GameState> for crate::manage_state::GameStateEvent {
-    fn from(value: generated::applesauce::GameState) -> Self {
+impl From<generated::applesauce::MatchFormat> for MatchFormat {
+    fn from(value: generated::applesauce::MatchFormat) -> Self {
+        use generated::applesauce::match_format::Inner;
+
+        match value.inner {
+            Some(Inner::FirstTo(rounds)) => Self::FirstTo(rounds),
+            Some(Inner::BestOf(rounds)) => Self::BestOf(rounds),
+            None => default(),
+        }
+    }
+}
+
+impl From<&RoundStatusEvent> for generated::applesauce::RoundStatus {
+    fn from(value: &RoundStatusEvent) -> Self {
+        Self {
+            phase: generated::applesauce::RoundPhase::from(value.phase).into(),
+            rounds_played: value.scoreboard.rounds_played,
+            standings: value
+                .scoreboard
+                .points
+                .iter()
+                .map(|(client_id, points)| generated::applesauce::Standing {
+                    client_id: client_id.to_string(),
+                    points: *points,
+                    display_name: value
+                        .scoreboard
+                        .display_names
+                        .get(client_id)
+                        .cloned()
+                        .unwrap_or_default(),
+                    special_fields: default(),
+                })
+                .collect(),
+            last_winner: value.scoreboard.last_winner.clone().unwrap_or_default(),
+            format: protobuf::MessageField::some(value.scoreboard.format.into()),
+            match_winner: value.scoreboard.match_winner.clone().unwrap_or_default(),
+            rematch_votes: value.scoreboard.rematch_votes.iter().cloned().collect(),
+            special_fields: default(),
+        }
+    }
+}
+
+impl From<generated::applesauce::RoundStatus> for RoundStatusEvent {
+    fn from(value: generated::applesauce::RoundStatus) -> Self {
+        Self {
+            scoreboard: Scoreboard {
+                format: value.format.unwrap_or_default().into(),
+                rounds_played: value.rounds_played,
+                points: value
+                    .standings
+                    .iter()
+                    .map(|standing| (standing.client_id.to_string(), standing.points))
+                    .collect(),
+                display_names: value
+                    .standings
+                    .into_iter()
+                    .map(|standing| (standing.client_id, standing.display_name))
+                    .collect(),
+                last_winner: match value.last_winner.is_empty() {
+                    true => None,
+                    false => Some(value.last_winner),
+                },
+                match_winner: match value.match_winner.is_empty() {
+                    true => None,
+                    false => Some(value.match_winner),
+                },
+                rematch_votes: value.rematch_votes.into_iter().collect(),
+            },
+            phase: value.phase.enum_value_or_default().into(),
+        }
+    }
+}
+
+impl crate::manage_state::GameStateEvent {
+    /// Players missing from the roster are left out, since we can't tell whose
+    /// they are. The roster is sent whenever players come or go, so they show
+    /// up in a later game state.
+    pub(crate) fn from_proto(value: generated::applesauce::GameState, roster: &Roster) -> Self {
         Self {
             timestamp: value.timestamp,
             players: value
                 .players
                 .into_iter()
-                .map(|player| crate::manage_state::PlayerState {
-                    id: player.id,
-                    client_id: player.client_id,
-                    spawn_id: player.spawn_id,
+                .filter_map(|player| {
+                    let id = NetworkId(player.id);
+                    Some((id, roster.get(&id)?, player))
+                })
+                .map(|(id, entry, player)| crate::manage_state::PlayerState {
+                    id: Some(id),
+                    client_id: entry.client_id.to_string(),
+                    spawn_id: entry.spawn_id.to_string(),
                     radius: player.radius,
                     position: player.position.unwrap().into(),
                     color: player.color.unwrap().into(),
                     velocity: player.velocity.unwrap().into(),
+                    health: player.health,
+                    shield: player.shield.into_option().map(|shield| {
+                        crate::manage_state::ShieldState {
+                            radius: shield.radius,
+                            remaining: Duration::from_millis(shield.remaining),
+                        }
+                    }),
+                    shield_cooldown_elapsed: Duration::from_millis(player.shield_cooldown_elapsed),
+                    bullet_count: player.gun.bullet_count,
+                    bullet_capacity: player.gun.bullet_capacity,
                 })
                 .collect(),
             bullets: value
                 .bullets
                 .into_iter()
                 .map(|bullet| crate::manage_state::BulletState {
-                    id: bullet.id,
+                    id: NetworkId(bullet.id),
                     transform: Transform {
                         translation: bullet.position.unwrap().into(),
                         rotation: bullet.rotation.unwrap().into(),
@@ -229,6 +558,16 @@ impl From<generated::applesauce::GameState> for crate::manage_state::GameStateEv
                     velocity: bullet.velocity.unwrap().into(),
                 })
                 .collect(),
+            input_acks: value
+                .input_acks
+                .into_iter()
+                .map(|(id, ack)| (NetworkId(id), ack))
+                .collect(),
+            round_trip_times: value
+                .round_trip_times
+                .into_iter()
+                .map(|(id, rtt)| (NetworkId(id), rtt))
+                .collect(),
         }
     }
 }
@@ -0,0 +1,125 @@
+use std::{
+    net::{Shutdown, TcpListener, TcpStream},
+    thread,
+};
+
+use bevy::prelude::*;
+use protobuf::{CodedInputStream, Message};
+
+use crate::protos::generated::applesauce;
+
+/// Lets clients play on a server through this process without running any of
+/// the simulation here. Every client that connects to us gets its own
+/// connection to the upstream server, and the messages in both directions are
+/// passed along untouched. That way the upstream server hands out the identity
+/// and keeps binding inputs to the connection they came from. Nothing is
+/// shared between the clients, so the upstream server still sends each of them
+/// their own game states.
+pub struct RelayPlugin {
+    hostname: String,
+    upstream: String,
+}
+
+impl RelayPlugin {
+    pub fn relay(hostname: String, upstream: String) -> Self {
+        Self { hostname, upstream }
+    }
+}
+
+impl Plugin for RelayPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(RelayConfig {
+            hostname: self.hostname.clone(),
+            upstream: self.upstream.clone(),
+        })
+        .add_systems(Startup, relay);
+    }
+}
+
+#[derive(Resource)]
+struct RelayConfig {
+    hostname: String,
+    upstream: String,
+}
+
+fn relay(config: Res<RelayConfig>) {
+    let listener = TcpListener::bind(config.hostname.clone()).unwrap();
+    let upstream = config.upstream.clone();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let upstream = upstream.clone();
+                    thread::spawn(move || handle_connection(stream, upstream));
+                }
+                Err(e) => println!("Failed to accept connection: {}", e),
+            };
+        }
+    });
+}
+
+fn handle_connection(downstream: TcpStream, upstream: String) {
+    let upstream = match TcpStream::connect(upstream.clone()) {
+        Ok(stream) => stream,
+        Err(e) => {
+            println!("Failed to connect to upstream {}: {}", upstream, e);
+            downstream.shutdown(Shutdown::Both).ok();
+            return;
+        }
+    };
+
+    let (downstream_read, upstream_write) = match (downstream.try_clone(), upstream.try_clone()) {
+        (Ok(downstream_read), Ok(upstream_write)) => (downstream_read, upstream_write),
+        _ => {
+            println!("Failed to clone streams for relayed connection");
+            downstream.shutdown(Shutdown::Both).ok();
+            upstream.shutdown(Shutdown::Both).ok();
+            return;
+        }
+    };
+
+    let inputs = thread::spawn(move || {
+        forward::<applesauce::ClientMessage>(downstream_read, upstream_write)
+    });
+
+    // the first message from upstream is the Welcome that carries the client
+    // id, and it goes downstream like everything else
+    forward::<applesauce::ServerMessage>(upstream, downstream);
+
+    inputs.join().ok();
+}
+
+/// Reads messages from one stream and writes them to the other until either
+/// side goes away, then closes both so the opposite direction stops too
+fn forward<M: Message>(mut from: TcpStream, mut to: TcpStream) {
+    let mut coded_stream = CodedInputStream::new(&mut from);
+
+    loop {
+        match coded_stream.eof() {
+            Ok(false) => {}
+            Ok(true) => break,
+            Err(e) => {
+                println!("Failed to read relayed message: {}", e);
+                break;
+            }
+        }
+
+        let message: M = match coded_stream.read_message() {
+            Ok(message) => message,
+            Err(e) => {
+                println!("Failed to read relayed message: {}", e);
+                break;
+            }
+        };
+
+        if let Err(e) = message.write_length_delimited_to_writer(&mut to) {
+            println!("Failed to relay message: {}", e);
+            break;
+        }
+    }
+
+    drop(coded_stream);
+    from.shutdown(Shutdown::Both).ok();
+    to.shutdown(Shutdown::Both).ok();
+}
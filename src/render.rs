@@ -5,11 +5,14 @@ use bevy::{
 };
 
 use crate::{
-    manage_state::{Bullet, Gun, Health, Player, Shield},
+    client::ReportedRoundTripTimes,
+    manage_state::{
+        Bullet, Gun, Health, NetworkId, Player, Shield, BULLET_HALF_LENGTH, BULLET_HALF_WIDTH,
+    },
     AppConfig, GameState,
 };
 
-pub(crate) struct RenderPlugin;
+pub struct RenderPlugin;
 
 #[derive(Component)]
 pub(crate) struct AmmoCountDisplay;
@@ -46,16 +49,23 @@ fn render_ammo_count(mut query: Query<(&Gun, &mut Text), With<AmmoCountDisplay>>
 
 fn render_health(
     mut health_displays: Query<(&mut Text, &Parent), With<HealthDisplay>>,
-    healths: Query<&Health>,
+    healths: Query<(&Health, Option<&NetworkId>)>,
+    round_trip_times: Option<Res<ReportedRoundTripTimes>>,
     config: Res<AppConfig>,
 ) {
     for (mut text, parent) in health_displays.iter_mut() {
-        let health = match healths.get(**parent) {
+        let (health, id) = match healths.get(**parent) {
             Err(_) => continue,
             Ok(health) => health,
         };
 
-        text.sections[0].value = format!("{}/{}", health.0, config.player_health);
+        // only clients know round trip times, and only for players the server gave an id
+        let round_trip_time = id.and_then(|id| round_trip_times.as_ref()?.get(id).copied());
+
+        text.sections[0].value = match round_trip_time {
+            None => format!("{}/{}", health.0, config.player_health),
+            Some(rtt) => format!("{}/{} {}ms", health.0, config.player_health, rtt),
+        };
     }
 }
 
@@ -148,7 +158,12 @@ fn ensure_bullets_render(
 ) {
     for (entity, transform) in bullets.iter() {
         commands.entity(entity).insert(MaterialMesh2dBundle {
-            mesh: meshes.add(Rectangle::new(40., 10.)).into(),
+            mesh: meshes
+                .add(Rectangle::new(
+                    2. * BULLET_HALF_LENGTH,
+                    2. * BULLET_HALF_WIDTH,
+                ))
+                .into(),
             material: materials.add(ColorMaterial::from(Color::WHITE)),
             transform: transform.clone(),
             ..default()
@@ -0,0 +1,1153 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    net::{Shutdown, TcpListener, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use bevy::{
+    app::{FixedMain, RunFixedMainLoop},
+    ecs::system::{RunSystemOnce, SystemState},
+    hierarchy::despawn_with_children_recursive,
+    prelude::*,
+};
+use bevy_rapier2d::{
+    plugin::systems::sync_removals,
+    prelude::*,
+    rapier::prelude::{
+        BroadPhase, CCDSolver, ColliderHandle, ColliderSet, ImpulseJointSet, IslandManager,
+        MultibodyJointSet, NarrowPhase, QueryPipeline, RigidBodyHandle, RigidBodySet,
+    },
+};
+use crossbeam_channel::{Receiver, Sender};
+use protobuf::{CodedInputStream, Message, MessageField};
+use uuid::Uuid;
+
+use crate::{
+    conditioner::{self, NetworkConditions},
+    events::{InputState, PlayerInputEvent, PlayerSpawnEvent},
+    manage_state::{
+        despawn_things_that_need_despawning, spawn_player, spawn_players, Bullet, BulletBundle,
+        Gun, Health, InputsAppliedElsewhere, Player, PlayerInput, PlayerState, PositionHistory,
+        Shield, ShieldBundle, ShieldTimeout, SimulationTick,
+    },
+    protos::{generated::applesauce, BUILD_ID, PROTOCOL_VERSION},
+    AppConfig, GameState,
+};
+
+/// How many frames after we press something it takes effect. The peer gets
+/// that long for our input to reach it before it has to guess.
+const INPUT_DELAY: u64 = 2;
+
+/// How many frames we simulate past the peer's latest input before waiting
+/// for it to catch up
+const MAX_PREDICTION_FRAMES: u64 = 8;
+
+/// How long to wait before trying to reach the hosting peer again
+const CONNECT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Plays a 1v1 match with another peer, with no server in between. Peers only
+/// send each other their inputs. When the other peer's input for a frame
+/// hasn't arrived yet, we guess it is the same as its last one. Whenever a
+/// guess turns out to be wrong, the world goes back to how it was on that
+/// frame and the frames since get simulated again with the real input.
+///
+/// Both peers have to simulate every frame the same way for this to work,
+/// which holds as long as both spawn the same things in the same order.
+/// Things that get respawned by a rollback get new physics handles, which
+/// may make the peers drift apart. To find out, peers send each other a
+/// checksum of their world for every frame neither will simulate again, and
+/// the first frame they differ on gets reported as a desync.
+pub struct RollbackPlugin {
+    peer: Peer,
+}
+
+impl RollbackPlugin {
+    /// Waits for the other peer to connect on this address
+    pub fn host(hostname: String) -> Self {
+        Self {
+            peer: Peer::Host(hostname),
+        }
+    }
+
+    /// Connects to the peer hosting on this address
+    pub fn join(hostname: String) -> Self {
+        Self {
+            peer: Peer::Join(hostname),
+        }
+    }
+}
+
+impl Plugin for RollbackPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(PeerConfig(self.peer.clone()))
+            .init_resource::<NetworkConditions>()
+            .init_resource::<RollbackSession>()
+            // the session applies inputs and spawns on the frame they are for
+            .insert_resource(InputsAppliedElsewhere)
+            .add_systems(Startup, (pick_client_id, connect_to_peer).chain())
+            .add_systems(
+                PreUpdate,
+                record_local_input.run_if(in_state(GameState::Round)),
+            )
+            // the session decides when a frame gets simulated instead of the
+            // fixed timestep, since it may have to simulate some of them again
+            .add_schedule(Schedule::new(RunFixedMainLoop))
+            .add_systems(
+                RunFixedMainLoop,
+                advance_session.run_if(in_state(GameState::Round)),
+            );
+    }
+}
+
+#[derive(Clone)]
+enum Peer {
+    Host(String),
+    Join(String),
+}
+
+#[derive(Resource, Deref)]
+struct PeerConfig(Peer);
+
+/// Everything the peer sends after the handshake, in the order it was sent
+#[derive(Resource, Deref)]
+struct ReceivePeerMessage(Receiver<applesauce::peer_message::Inner>);
+
+#[derive(Resource, Deref)]
+struct SendPeerMessage(Sender<applesauce::peer_message::Inner>);
+
+/// A peer's controls on one frame
+#[derive(Clone, Copy, Default)]
+struct FrameInput {
+    state: InputState,
+    /// The peer wants a player of its own
+    spawn: bool,
+}
+
+impl FrameInput {
+    /// Whether a frame simulated with either input comes out the same, given
+    /// the input on the frame before. Where a player aims only matters on the
+    /// frame it starts shooting.
+    fn same_effect(&self, other: &Self, previous: &Self) -> bool {
+        let (a, b) = (self.state, other.state);
+        let starts_shot = a.shoot && !previous.state.shoot;
+
+        self.spawn == other.spawn
+            && a.move_axis == b.move_axis
+            && a.jump == b.jump
+            && a.shoot == b.shoot
+            && a.block == b.block
+            && (!starts_shot || a.aim == b.aim)
+    }
+}
+
+/// Where the match is at, along with the inputs and snapshots it needs to
+/// simulate frames again
+#[derive(Resource)]
+pub(crate) struct RollbackSession {
+    /// The next frame to simulate
+    frame: u64,
+    /// Time that has passed but wasn't simulated yet
+    accumulated: Duration,
+    /// Unknown until the peer says hello. Nothing gets simulated before then.
+    remote_client_id: Option<String>,
+    local_inputs: HashMap<u64, FrameInput>,
+    remote_inputs: HashMap<u64, FrameInput>,
+    /// The latest frame we have the peer's input for. Inputs arrive in order,
+    /// so we have all of the ones before it as well.
+    confirmed: u64,
+    /// What we guessed the peer's input was on frames we simulated before it arrived
+    predictions: HashMap<u64, FrameInput>,
+    /// The earliest frame we guessed wrong on, which needs simulating again
+    rollback_to: Option<u64>,
+    /// How the world was at the start of each frame we may have to simulate again, oldest first
+    snapshots: VecDeque<WorldSnapshot>,
+    /// The controls we are holding, as of the latest input state
+    local_state: InputState,
+    /// We asked for a player since we last sent an input
+    spawn_requested: bool,
+    /// Our checksum for the end of each frame we simulated, until it gets sent
+    checksums: HashMap<u64, u64>,
+    /// The earliest frame we haven't sent the peer a checksum for
+    next_checksum: u64,
+    /// Checksums of frames neither peer simulates again, until the other
+    /// peer's checksum for the same frame is there to compare them with
+    sent_checksums: HashMap<u64, u64>,
+    remote_checksums: HashMap<u64, u64>,
+    /// The first frame the peers simulated differently on
+    desynced_at: Option<u64>,
+    /// The session can't go on, since we lost what we needed to get back
+    /// in step with the peer
+    stalled: bool,
+}
+
+impl Default for RollbackSession {
+    /// Nobody touches anything on the frames before our inputs start taking
+    /// effect, except to have a player spawned on the first one
+    fn default() -> Self {
+        let initial_inputs = || {
+            (0..INPUT_DELAY)
+                .map(|frame| {
+                    let input = FrameInput {
+                        state: InputState {
+                            tick: frame + 1,
+                            ..default()
+                        },
+                        spawn: frame == 0,
+                    };
+                    (frame, input)
+                })
+                .collect()
+        };
+
+        Self {
+            frame: 0,
+            accumulated: Duration::ZERO,
+            remote_client_id: None,
+            local_inputs: initial_inputs(),
+            remote_inputs: initial_inputs(),
+            confirmed: INPUT_DELAY - 1,
+            predictions: default(),
+            rollback_to: None,
+            snapshots: default(),
+            local_state: default(),
+            spawn_requested: false,
+            checksums: default(),
+            next_checksum: 0,
+            sent_checksums: default(),
+            remote_checksums: default(),
+            desynced_at: None,
+            stalled: false,
+        }
+    }
+}
+
+impl RollbackSession {
+    /// The peer's input on a frame, or our guess at it if it hasn't arrived yet
+    fn remote_input(&self, frame: u64) -> FrameInput {
+        if let Some(input) = self.remote_inputs.get(&frame) {
+            return *input;
+        }
+
+        let latest = self
+            .remote_inputs
+            .get(&self.confirmed)
+            .copied()
+            .unwrap_or_default();
+
+        FrameInput {
+            state: InputState {
+                tick: frame + 1,
+                ..latest.state
+            },
+            spawn: false,
+        }
+    }
+
+    fn receive_remote_input(&mut self, frame: u64, input: FrameInput) {
+        self.remote_inputs.insert(frame, input);
+        self.confirmed = self.confirmed.max(frame);
+
+        // the peer's inputs arrive in order, so the one before is no guess
+        let previous = match frame {
+            0 => FrameInput::default(),
+            frame => self.remote_input(frame - 1),
+        };
+
+        if let Some(prediction) = self.predictions.remove(&frame) {
+            if !prediction.same_effect(&input, &previous) {
+                self.rollback_to = Some(self.rollback_to.map_or(frame, |f| f.min(frame)));
+            }
+        }
+    }
+
+    /// Frames up to the latest confirmed one never get simulated again. The
+    /// peer's input on it stays, since our guesses repeat it.
+    fn forget_confirmed_frames(&mut self) {
+        let confirmed = self.confirmed;
+
+        self.snapshots.retain(|snapshot| snapshot.frame > confirmed);
+        self.local_inputs.retain(|frame, _| *frame > confirmed);
+        self.remote_inputs.retain(|frame, _| *frame >= confirmed);
+    }
+}
+
+/// Peers tell each other apart by client id, and there is no server to hand them out
+fn pick_client_id(mut config: ResMut<AppConfig>) {
+    config.client_id = Uuid::new_v4().to_string();
+}
+
+fn connect_to_peer(
+    mut commands: Commands,
+    peer: Res<PeerConfig>,
+    app_config: Res<AppConfig>,
+    conditions: Res<NetworkConditions>,
+) {
+    // inputs go over TCP, so none of them may get lost
+    let (tx_incoming, rx_incoming) = crossbeam_channel::unbounded();
+    let rx_incoming = conditioner::condition(rx_incoming, conditions.downstream, |_| false);
+
+    let (tx_outgoing, rx_outgoing) = crossbeam_channel::unbounded();
+    let rx_outgoing = conditioner::condition(rx_outgoing, conditions.upstream, |_| false);
+
+    commands.insert_resource(ReceivePeerMessage(rx_incoming));
+    commands.insert_resource(SendPeerMessage(tx_outgoing));
+
+    let hello = applesauce::PeerHello {
+        protocol_version: PROTOCOL_VERSION,
+        build_id: BUILD_ID.to_string(),
+        client_id: app_config.client_id.to_string(),
+        display_name: app_config.display_name.to_string(),
+        special_fields: default(),
+    };
+    let peer = peer.0.clone();
+
+    thread::spawn(move || {
+        let stream = match open_peer_stream(&peer) {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Failed to reach the peer: {}", e);
+                return;
+            }
+        };
+
+        run_peer_connection(stream, hello, tx_incoming, rx_outgoing);
+        println!("Lost the connection to the peer");
+    });
+}
+
+fn open_peer_stream(peer: &Peer) -> std::io::Result<TcpStream> {
+    match peer {
+        Peer::Host(hostname) => {
+            let listener = TcpListener::bind(hostname)?;
+            println!("Waiting for a peer to connect on {}", hostname);
+            listener.accept().map(|(stream, _)| stream)
+        }
+        Peer::Join(hostname) => loop {
+            match TcpStream::connect(hostname) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    println!(
+                        "Failed to connect to the peer at {}: {}. Retrying",
+                        hostname, e
+                    );
+                    thread::sleep(CONNECT_RETRY_DELAY);
+                }
+            }
+        },
+    }
+}
+
+/// Says hello and passes messages between the peer and the session until
+/// the connection is lost
+fn run_peer_connection(
+    mut stream: TcpStream,
+    hello: applesauce::PeerHello,
+    tx_incoming: Sender<applesauce::peer_message::Inner>,
+    rx_outgoing: Receiver<applesauce::peer_message::Inner>,
+) {
+    // inputs are small and due every frame, so they shouldn't wait around to be batched
+    stream.set_nodelay(true).ok();
+
+    let (mut recv_stream, mut send_stream) = match (stream.try_clone(), stream.try_clone()) {
+        (Ok(recv_stream), Ok(send_stream)) => (recv_stream, send_stream),
+        _ => return,
+    };
+
+    let hello = applesauce::PeerMessage {
+        inner: Some(applesauce::peer_message::Inner::Hello(hello)),
+        special_fields: default(),
+    };
+
+    if let Err(e) = hello.write_length_delimited_to_writer(&mut stream) {
+        println!("Failed to say hello to the peer: {}", e);
+        return;
+    }
+
+    let mut coded_stream = CodedInputStream::new(&mut recv_stream);
+
+    let message: applesauce::PeerMessage = match coded_stream.read_message() {
+        Ok(message) => message,
+        Err(e) => {
+            println!("Failed to read the peer's hello: {}", e);
+            return;
+        }
+    };
+
+    let peer_hello = match message.inner {
+        Some(applesauce::peer_message::Inner::Hello(hello)) => hello,
+        _ => {
+            println!("The peer did not say hello");
+            return;
+        }
+    };
+
+    if peer_hello.protocol_version != PROTOCOL_VERSION {
+        println!(
+            "Refusing to play with a peer on protocol version {}. We are on {}",
+            peer_hello.protocol_version, PROTOCOL_VERSION
+        );
+        return;
+    }
+
+    if peer_hello.build_id != BUILD_ID {
+        println!(
+            "Refusing to play with a peer running build {}. We are running {}",
+            peer_hello.build_id, BUILD_ID
+        );
+        return;
+    }
+
+    if tx_incoming
+        .send(applesauce::peer_message::Inner::Hello(peer_hello))
+        .is_err()
+    {
+        return;
+    }
+
+    // told to stop once the connection is gone, rather than taking one more
+    // message off the session only to fail writing it
+    let (tx_stop, rx_stop) = crossbeam_channel::bounded::<()>(0);
+    let send_thread = thread::spawn(move || loop {
+        let inner = crossbeam_channel::select! {
+            recv(rx_outgoing) -> inner => match inner {
+                Err(_) => break,
+                Ok(inner) => inner,
+            },
+            recv(rx_stop) -> _ => break,
+        };
+
+        let message = applesauce::PeerMessage {
+            inner: Some(inner),
+            special_fields: default(),
+        };
+
+        if message
+            .write_length_delimited_to_writer(&mut send_stream)
+            .is_err()
+        {
+            break;
+        }
+    });
+
+    loop {
+        match coded_stream.eof() {
+            Ok(false) => {}
+            Ok(true) => break,
+            Err(e) => {
+                println!("Failed to read from the peer: {}", e);
+                break;
+            }
+        }
+
+        let message: applesauce::PeerMessage = match coded_stream.read_message() {
+            Ok(message) => message,
+            Err(e) => {
+                println!("Failed to read message from the peer: {}", e);
+                break;
+            }
+        };
+
+        let inner = match message.inner {
+            None => continue,
+            Some(inner) => inner,
+        };
+
+        if tx_incoming.send(inner).is_err() {
+            break;
+        }
+    }
+
+    drop(coded_stream);
+    // make sure the send thread notices as well, even while it's writing
+    stream.shutdown(Shutdown::Both).ok();
+    drop(tx_stop);
+    send_thread.join().ok();
+}
+
+/// Our own input events only say what we are doing, the session decides
+/// which frame it happens on
+fn record_local_input(
+    mut session: ResMut<RollbackSession>,
+    config: Res<AppConfig>,
+    mut input_events: EventReader<PlayerInputEvent>,
+    mut spawn_events: EventReader<PlayerSpawnEvent>,
+) {
+    if let Some(event) = input_events
+        .read()
+        .filter(|event| event.client_id == config.client_id)
+        .last()
+    {
+        session.local_state = event.state;
+    }
+
+    if spawn_events
+        .read()
+        .any(|event| event.client_id == config.client_id)
+    {
+        session.spawn_requested = true;
+    }
+}
+
+/// Rolls back to fix the frames we guessed wrong on, then simulates as many
+/// new frames as the time that passed calls for
+fn advance_session(world: &mut World) {
+    world.resource_scope(|world, mut session: Mut<RollbackSession>| {
+        let messages: Vec<applesauce::peer_message::Inner> =
+            world.resource::<ReceivePeerMessage>().try_iter().collect();
+
+        for message in messages {
+            match message {
+                applesauce::peer_message::Inner::Hello(hello) => {
+                    println!(
+                        "Playing against {} ({})",
+                        hello.display_name, hello.client_id
+                    );
+                    session.remote_client_id = Some(hello.client_id);
+                }
+                applesauce::peer_message::Inner::Input(input) => {
+                    let state: InputState = input.state.unwrap_or_default().into();
+                    let frame_input = FrameInput {
+                        state: InputState {
+                            tick: input.frame + 1,
+                            ..state
+                        },
+                        spawn: input.spawn,
+                    };
+                    session.receive_remote_input(input.frame, frame_input);
+                }
+                applesauce::peer_message::Inner::Checksum(checksum) => {
+                    session
+                        .remote_checksums
+                        .insert(checksum.frame, checksum.checksum);
+                }
+            }
+        }
+
+        if session.remote_client_id.is_none() || session.stalled {
+            return;
+        }
+
+        if let Some(frame) = session.rollback_to.take() {
+            roll_back(world, &mut session, frame);
+            if session.stalled {
+                return;
+            }
+        }
+        session.forget_confirmed_frames();
+        exchange_checksums(world, &mut session);
+
+        let timestep = world.resource::<Time<Fixed>>().timestep();
+        session.accumulated += world.resource::<Time<Virtual>>().delta();
+
+        while session.accumulated >= timestep {
+            // too far ahead of the peer, so wait for its inputs to catch up
+            if session.frame > session.confirmed + MAX_PREDICTION_FRAMES {
+                session.accumulated = timestep;
+                break;
+            }
+            session.accumulated -= timestep;
+
+            send_local_input(world, &mut session);
+
+            let frame = session.frame;
+            simulate_frame(world, &mut session, frame);
+            session.frame += 1;
+        }
+    });
+
+    // everything after the fixed timestep schedules goes by the virtual clock again
+    let virtual_time = world.resource::<Time<Virtual>>().as_generic();
+    *world.resource_mut::<Time>() = virtual_time;
+}
+
+/// Sends the peer what we are doing, for the frame it takes effect on
+fn send_local_input(world: &World, session: &mut RollbackSession) {
+    let frame = session.frame + INPUT_DELAY;
+    let input = FrameInput {
+        state: InputState {
+            tick: frame + 1,
+            ..session.local_state
+        },
+        spawn: std::mem::take(&mut session.spawn_requested),
+    };
+    session.local_inputs.insert(frame, input);
+
+    world
+        .resource::<SendPeerMessage>()
+        .send(applesauce::peer_message::Inner::Input(
+            applesauce::PeerInput {
+                frame,
+                state: MessageField::some(input.state.into()),
+                spawn: input.spawn,
+                special_fields: default(),
+            },
+        ))
+        .ok();
+}
+
+/// Puts the world back the way it was at the start of the frame, and
+/// simulates every frame since again
+fn roll_back(world: &mut World, session: &mut RollbackSession, frame: u64) {
+    let index = match session.snapshots.iter().position(|s| s.frame == frame) {
+        None => {
+            // carrying on would only leave us further apart from the peer
+            println!(
+                "Desynced from the peer. Can't roll back to frame {}, which is too long ago. Stopping the session",
+                frame
+            );
+            session.stalled = true;
+            return;
+        }
+        Some(index) => index,
+    };
+
+    // the frames from then on take their snapshots again as they are simulated
+    let snapshot = match session.snapshots.split_off(index).pop_front() {
+        None => return,
+        Some(snapshot) => snapshot,
+    };
+    snapshot.restore(world);
+
+    for frame in frame..session.frame {
+        simulate_frame(world, session, frame);
+    }
+}
+
+/// Runs the fixed timestep schedules once, with both peers' inputs for the
+/// frame applied beforehand
+fn simulate_frame(world: &mut World, session: &mut RollbackSession, frame: u64) {
+    let local = session
+        .local_inputs
+        .get(&frame)
+        .copied()
+        .unwrap_or_default();
+    let remote = session.remote_input(frame);
+    if frame > session.confirmed {
+        session.predictions.insert(frame, remote);
+    }
+
+    session
+        .snapshots
+        .push_back(WorldSnapshot::save(world, frame));
+
+    // both peers need to go through the players in the same order, or they
+    // would hand out the spawns differently
+    let mut inputs = [
+        (world.resource::<AppConfig>().client_id.to_string(), local),
+        (session.remote_client_id.clone().unwrap_or_default(), remote),
+    ];
+    inputs.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let spawning: Vec<String> = inputs
+        .iter()
+        .filter(|(_, input)| input.spawn)
+        .map(|(client_id, _)| client_id.to_string())
+        .collect();
+    if !spawning.is_empty() {
+        world.run_system_once_with(spawning, spawn_players);
+    }
+
+    let mut players = world.query::<(&Player, &mut PlayerInput)>();
+    for (player, mut player_input) in players.iter_mut(world) {
+        if let Some((_, input)) = inputs.iter().find(|(id, _)| *id == player.client_id) {
+            player_input.apply(input.state);
+        }
+    }
+
+    let mut fixed_time = world.resource_mut::<Time<Fixed>>();
+    let timestep = fixed_time.timestep();
+    fixed_time.advance_by(timestep);
+    let fixed_time = fixed_time.as_generic();
+    *world.resource_mut::<Time>() = fixed_time;
+
+    world.run_schedule(FixedMain);
+
+    // things would otherwise be gone or not depending on whether the render
+    // frame ended between two simulated frames
+    world.run_system_once(despawn_things_that_need_despawning);
+
+    session.checksums.insert(frame, checksum(world));
+}
+
+/// Sends the peer a checksum for every frame neither of us simulates again,
+/// and compares them with the ones the peer sent us
+fn exchange_checksums(world: &World, session: &mut RollbackSession) {
+    while session.next_checksum <= session.confirmed && session.next_checksum < session.frame {
+        let frame = session.next_checksum;
+        session.next_checksum += 1;
+
+        let checksum = match session.checksums.remove(&frame) {
+            None => continue,
+            Some(checksum) => checksum,
+        };
+
+        world
+            .resource::<SendPeerMessage>()
+            .send(applesauce::peer_message::Inner::Checksum(
+                applesauce::PeerChecksum {
+                    frame,
+                    checksum,
+                    special_fields: default(),
+                },
+            ))
+            .ok();
+        session.sent_checksums.insert(frame, checksum);
+    }
+
+    let comparable: Vec<u64> = session
+        .sent_checksums
+        .keys()
+        .filter(|frame| session.remote_checksums.contains_key(frame))
+        .copied()
+        .collect();
+
+    for frame in comparable {
+        let (ours, theirs) = match (
+            session.sent_checksums.remove(&frame),
+            session.remote_checksums.remove(&frame),
+        ) {
+            (Some(ours), Some(theirs)) => (ours, theirs),
+            _ => continue,
+        };
+
+        if ours != theirs && session.desynced_at.is_none() {
+            println!(
+                "Desynced from the peer on frame {}. Our checksum: {:x}, theirs: {:x}",
+                frame, ours, theirs
+            );
+            session.desynced_at = Some(frame);
+        }
+    }
+}
+
+/// A hash of everything about the world both peers should agree on. Entities
+/// don't count, since the peers number theirs differently.
+fn checksum(world: &mut World) -> u64 {
+    let mut players = world.query::<(&Player, &Transform, &Velocity, &Health, Option<&Children>)>();
+    let mut bullets = world.query_filtered::<&Transform, With<Bullet>>();
+    let world: &World = world;
+
+    let mut hasher = DefaultHasher::new();
+    (**world.resource::<SimulationTick>()).hash(&mut hasher);
+
+    let mut players: Vec<_> = players.iter(world).collect();
+    players.sort_by(|(a, _, _, _, _), (b, _, _, _, _)| a.client_id.cmp(&b.client_id));
+
+    for (player, transform, velocity, health, children) in players {
+        player.client_id.hash(&mut hasher);
+        transform
+            .translation
+            .xy()
+            .to_array()
+            .map(f32::to_bits)
+            .hash(&mut hasher);
+        velocity
+            .linvel
+            .to_array()
+            .map(f32::to_bits)
+            .hash(&mut hasher);
+        health.0.hash(&mut hasher);
+
+        let children = children
+            .map(|children| children.to_vec())
+            .unwrap_or_default();
+        children
+            .iter()
+            .find_map(|child| world.get::<Gun>(*child))
+            .map(|gun| gun.bullet_count)
+            .hash(&mut hasher);
+        children
+            .iter()
+            .any(|child| world.get::<Shield>(*child).is_some())
+            .hash(&mut hasher);
+    }
+
+    let mut bullets: Vec<[u32; 2]> = bullets
+        .iter(world)
+        .map(|transform| transform.translation.xy().to_array().map(f32::to_bits))
+        .collect();
+    bullets.sort();
+    bullets.hash(&mut hasher);
+
+    hasher.finish()
+}
+
+/// Everything about the world that can change from one frame to the next
+struct WorldSnapshot {
+    frame: u64,
+    tick: u64,
+    fixed_time: Time<Fixed>,
+    physics: Option<PhysicsSnapshot>,
+    players: Vec<PlayerSnapshot>,
+    bullets: Vec<BulletSnapshot>,
+}
+
+struct PlayerSnapshot {
+    entity: Entity,
+    player: Player,
+    transform: Transform,
+    velocity: Velocity,
+    impulse: ExternalImpulse,
+    health: Health,
+    shield_timeout: ShieldTimeout,
+    input: PlayerInput,
+    position_history: PositionHistory,
+    gun: Option<Gun>,
+    shield: Option<(Entity, Shield)>,
+}
+
+struct BulletSnapshot {
+    entity: Entity,
+    bullet: Bullet,
+    transform: Transform,
+    velocity: Velocity,
+}
+
+impl WorldSnapshot {
+    fn save(world: &mut World, frame: u64) -> Self {
+        let mut players = world.query::<(
+            Entity,
+            &Player,
+            &Transform,
+            &Velocity,
+            &ExternalImpulse,
+            &Health,
+            &ShieldTimeout,
+            &PlayerInput,
+            &PositionHistory,
+            Option<&Children>,
+        )>();
+        let mut bullets = world.query::<(Entity, &Bullet, &Transform, &Velocity)>();
+        let world: &World = world;
+
+        let child = |children: Option<&Children>| {
+            children
+                .map(|children| children.to_vec())
+                .unwrap_or_default()
+        };
+
+        Self {
+            frame,
+            tick: **world.resource::<SimulationTick>(),
+            fixed_time: *world.resource::<Time<Fixed>>(),
+            physics: world
+                .get_resource::<RapierContext>()
+                .map(PhysicsSnapshot::save),
+            players: players
+                .iter(world)
+                .map(
+                    |(
+                        entity,
+                        player,
+                        transform,
+                        velocity,
+                        impulse,
+                        health,
+                        shield_timeout,
+                        input,
+                        position_history,
+                        children,
+                    )| {
+                        let children = child(children);
+
+                        PlayerSnapshot {
+                            entity,
+                            player: player.clone(),
+                            transform: *transform,
+                            velocity: *velocity,
+                            impulse: *impulse,
+                            health: health.clone(),
+                            shield_timeout: shield_timeout.clone(),
+                            input: input.clone(),
+                            position_history: position_history.clone(),
+                            gun: children
+                                .iter()
+                                .find_map(|child| world.get::<Gun>(*child).cloned()),
+                            shield: children.iter().find_map(|child| {
+                                Some((*child, world.get::<Shield>(*child)?.clone()))
+                            }),
+                        }
+                    },
+                )
+                .collect(),
+            bullets: bullets
+                .iter(world)
+                .map(|(entity, bullet, transform, velocity)| BulletSnapshot {
+                    entity,
+                    bullet: bullet.clone(),
+                    transform: *transform,
+                    velocity: *velocity,
+                })
+                .collect(),
+        }
+    }
+
+    fn restore(self, world: &mut World) {
+        // Whatever was spawned since can't be in the snapshot, and whatever
+        // got a new physics body or collider since can't be put back into
+        // the old physics. Both go, and come back from the snapshot if they
+        // are in it.
+        let mut current =
+            world.query_filtered::<Entity, Or<(With<Player>, With<Bullet>, With<Shield>)>>();
+        let stale: Vec<Entity> = current
+            .iter(world)
+            .filter(|entity| {
+                !self.contains(*entity)
+                    || self
+                        .physics
+                        .as_ref()
+                        .zip(world.get_resource::<RapierContext>())
+                        .is_some_and(|(physics, context)| {
+                            !physics.has_same_handle(context, *entity)
+                        })
+            })
+            .collect();
+
+        for entity in stale {
+            // shields go along with their players
+            if world.get_entity(entity).is_some() {
+                despawn_with_children_recursive(world, entity);
+            }
+        }
+
+        if let Some(physics) = self.physics {
+            // lets the physics forget what was just despawned before it gets its old state back
+            world.run_system_once(sync_removals);
+            physics.restore(&mut world.resource_mut::<RapierContext>());
+        }
+
+        for snapshot in self.players.iter() {
+            let entity = match world.get_entity(snapshot.entity) {
+                Some(_) => {
+                    world.entity_mut(snapshot.entity).insert((
+                        snapshot.transform,
+                        snapshot.velocity,
+                        snapshot.impulse,
+                        snapshot.health.clone(),
+                        snapshot.shield_timeout.clone(),
+                        snapshot.input.clone(),
+                        snapshot.position_history.clone(),
+                    ));
+                    snapshot.entity
+                }
+                None => snapshot.respawn(world),
+            };
+
+            if let Some(gun) = &snapshot.gun {
+                let children = world
+                    .get::<Children>(entity)
+                    .map(|children| children.to_vec())
+                    .unwrap_or_default();
+
+                for child in children {
+                    if world.get::<Gun>(child).is_some() {
+                        world.entity_mut(child).insert(gun.clone());
+                    }
+                }
+            }
+
+            if let Some((shield_entity, shield)) = &snapshot.shield {
+                match world.get_entity_mut(*shield_entity) {
+                    Some(mut shield_entity) => {
+                        shield_entity.insert(shield.clone());
+                    }
+                    None => {
+                        let shield_entity = world
+                            .spawn(ShieldBundle::new(shield.radius, shield.ttl.clone()))
+                            .id();
+                        world.entity_mut(entity).add_child(shield_entity);
+                    }
+                }
+            }
+        }
+
+        for snapshot in self.bullets.iter() {
+            match world.get_entity_mut(snapshot.entity) {
+                Some(mut entity) => {
+                    entity.insert((snapshot.transform, snapshot.velocity));
+                }
+                None => {
+                    world.spawn((
+                        BulletBundle::new(
+                            snapshot.bullet.clone(),
+                            snapshot.transform,
+                            snapshot.velocity.linvel,
+                        ),
+                        snapshot.velocity,
+                    ));
+                }
+            }
+        }
+
+        **world.resource_mut::<SimulationTick>() = self.tick;
+        *world.resource_mut::<Time<Fixed>>() = self.fixed_time;
+    }
+
+    fn contains(&self, entity: Entity) -> bool {
+        self.bullets.iter().any(|bullet| bullet.entity == entity)
+            || self.players.iter().any(|player| {
+                player.entity == entity
+                    || player
+                        .shield
+                        .as_ref()
+                        .is_some_and(|(shield, _)| *shield == entity)
+            })
+    }
+}
+
+impl PlayerSnapshot {
+    /// Spawns the player again as it was, for when it died after the snapshot was taken
+    fn respawn(&self, world: &mut World) -> Entity {
+        let mut system_state = SystemState::<(Commands, Res<AppConfig>)>::new(world);
+        let (mut commands, config) = system_state.get_mut(world);
+
+        let entity = spawn_player(
+            &mut commands,
+            &PlayerState {
+                id: None,
+                client_id: self.player.client_id.to_string(),
+                spawn_id: self.player.spawn_id.to_string(),
+                radius: self.player.radius,
+                color: self.player.color,
+                position: self.transform.translation,
+                velocity: self.velocity.linvel,
+                health: self.health.0,
+                shield: None,
+                shield_cooldown_elapsed: Duration::ZERO,
+                bullet_count: 0,
+                bullet_capacity: 0,
+            },
+            &config,
+        );
+        commands.entity(entity).insert((
+            self.transform,
+            self.velocity,
+            self.impulse,
+            self.shield_timeout.clone(),
+            self.input.clone(),
+            self.position_history.clone(),
+        ));
+
+        system_state.apply(world);
+        entity
+    }
+}
+
+/// The parts of the physics world that change as it gets simulated
+struct PhysicsSnapshot {
+    islands: IslandManager,
+    broad_phase: BroadPhase,
+    narrow_phase: NarrowPhase,
+    bodies: RigidBodySet,
+    colliders: ColliderSet,
+    impulse_joints: ImpulseJointSet,
+    multibody_joints: MultibodyJointSet,
+    ccd_solver: CCDSolver,
+    query_pipeline: QueryPipeline,
+}
+
+impl PhysicsSnapshot {
+    fn save(context: &RapierContext) -> Self {
+        Self {
+            islands: context.islands.clone(),
+            broad_phase: context.broad_phase.clone(),
+            narrow_phase: context.narrow_phase.clone(),
+            bodies: context.bodies.clone(),
+            colliders: context.colliders.clone(),
+            impulse_joints: context.impulse_joints.clone(),
+            multibody_joints: context.multibody_joints.clone(),
+            ccd_solver: context.ccd_solver.clone(),
+            query_pipeline: context.query_pipeline.clone(),
+        }
+    }
+
+    /// Whether the physics plugin knows the entity by the same body and
+    /// collider it had when the snapshot was taken. Entities without either
+    /// yet have nothing to mix up.
+    fn has_same_handle(&self, context: &RapierContext, entity: Entity) -> bool {
+        let user_data = entity.to_bits() as u128;
+
+        let same_body = context.entity2body().get(&entity).is_none_or(|handle| {
+            self.bodies
+                .get(*handle)
+                .is_some_and(|body| body.user_data == user_data)
+        });
+        let same_collider = context.entity2collider().get(&entity).is_none_or(|handle| {
+            self.colliders
+                .get(*handle)
+                .is_some_and(|collider| collider.user_data == user_data)
+        });
+
+        same_body && same_collider
+    }
+
+    /// Puts the physics back the way it was, minus the bodies and colliders
+    /// the physics plugin no longer knows an entity for. Their entities are
+    /// gone, and get new ones once they are respawned.
+    ///
+    /// The plugin's maps from entities to bodies and colliders can't be
+    /// restored from outside, so the restored sets are made to agree with them
+    /// instead. Every entity still around has the same handles it had in the
+    /// snapshot, or it would have been respawned.
+    fn restore(self, context: &mut RapierContext) {
+        context.islands = self.islands;
+        context.broad_phase = self.broad_phase;
+        context.narrow_phase = self.narrow_phase;
+        context.bodies = self.bodies;
+        context.colliders = self.colliders;
+        context.impulse_joints = self.impulse_joints;
+        context.multibody_joints = self.multibody_joints;
+        context.ccd_solver = self.ccd_solver;
+        context.query_pipeline = self.query_pipeline;
+
+        let entity = |user_data: u128| Entity::from_bits(user_data as u64);
+
+        let gone_colliders: Vec<ColliderHandle> = context
+            .colliders
+            .iter()
+            .filter(|(handle, collider)| {
+                context.entity2collider().get(&entity(collider.user_data)) != Some(handle)
+            })
+            .map(|(handle, _)| handle)
+            .collect();
+        let gone_bodies: Vec<RigidBodyHandle> = context
+            .bodies
+            .iter()
+            .filter(|(handle, body)| {
+                context.entity2body().get(&entity(body.user_data)) != Some(handle)
+            })
+            .map(|(handle, _)| handle)
+            .collect();
+
+        for handle in gone_colliders.iter() {
+            context
+                .colliders
+                .remove(*handle, &mut context.islands, &mut context.bodies, false);
+        }
+
+        for handle in gone_bodies {
+            context.bodies.remove(
+                handle,
+                &mut context.islands,
+                &mut context.colliders,
+                &mut context.impulse_joints,
+                &mut context.multibody_joints,
+                false,
+            );
+        }
+
+        // The next step would tell about the contacts those colliders had
+        // ending, and the physics plugin has no entities for them to tell
+        // about. Ending them now, without a word, avoids that.
+        context.narrow_phase.handle_user_changes(
+            Some(&mut context.islands),
+            &[],
+            &gone_colliders,
+            &mut context.colliders,
+            &mut context.bodies,
+            &(),
+        );
+    }
+}
@@ -0,0 +1,400 @@
+use std::{collections::HashMap, time::Duration};
+
+use bevy::prelude::*;
+use bevy_rapier2d::dynamics::Velocity;
+use crossbeam_channel::{Receiver, Sender};
+
+use crate::{
+    events::{
+        Card, GameEvent, PlayerDisconnectedEvent, PlayerInputEvent, PlayerSpawnEvent, RematchEvent,
+        RoundStatusEvent,
+    },
+    manage_state::{
+        Authoritative, Bullet, EntityIndex, Gun, Health, Latencies, NetworkId, Player, PlayerInput,
+        RestartRoundEvent, Shield, ShieldTimeout, SimulationTick,
+    },
+    protos::generated::applesauce,
+    round::{RoundControllerPlugin, RoundPhase, Scoreboard},
+    AppConfig,
+};
+
+/// The part of the server that belongs to a single room: the players in it,
+/// the inputs they send and the game states they get back. A room only talks
+/// to the rest of the server through its inbox and outbox, so it runs the same
+/// whether it lives in the server's own world or in an app of its own.
+pub(crate) struct RoomPlugin {
+    inbox: Receiver<RoomMessage>,
+    outbox: Sender<applesauce::server_message::Inner>,
+}
+
+impl RoomPlugin {
+    pub(crate) fn new(
+        inbox: Receiver<RoomMessage>,
+        outbox: Sender<applesauce::server_message::Inner>,
+    ) -> Self {
+        Self { inbox, outbox }
+    }
+}
+
+impl Plugin for RoomPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(RoomInbox(self.inbox.clone()))
+            .insert_resource(RoomOutbox(self.outbox.clone()))
+            .insert_resource(Authoritative)
+            .init_resource::<RoomMembers>()
+            .init_resource::<PickedCards>()
+            .add_event::<PlayerDisconnectedEvent>()
+            .add_plugins(RoundControllerPlugin)
+            .add_systems(Startup, start_snapshot_timer)
+            .add_systems(PreUpdate, recv_room_messages)
+            .add_systems(
+                PostUpdate,
+                (
+                    send_game_events,
+                    send_round_status,
+                    send_picked_cards,
+                    send_level,
+                    (send_roster, send_state).chain(),
+                ),
+            );
+    }
+}
+
+/// What the rest of the server tells a room about its clients
+pub(crate) enum RoomMessage {
+    Joined {
+        client_id: String,
+    },
+    Left {
+        client_id: String,
+    },
+    /// The client lost its connection but may still come back, so its player
+    /// stays where it is with its controls let go
+    Suspended {
+        client_id: String,
+    },
+    /// An input from the client whose connection it arrived on
+    Input {
+        client_id: String,
+        input: applesauce::Input,
+    },
+    /// The latest smoothed round trip time to the client
+    RoundTripTime {
+        client_id: String,
+        rtt: Duration,
+    },
+    RestartRound,
+    /// Restarts the round on another level
+    SwitchLevel {
+        level: String,
+    },
+}
+
+#[derive(Resource, Deref)]
+struct RoomInbox(Receiver<RoomMessage>);
+
+/// Everything the room has to say to the clients in it
+#[derive(Resource, Deref)]
+struct RoomOutbox(Sender<applesauce::server_message::Inner>);
+
+/// The `crate::Player` spawned for each client in the room, keyed by client id
+#[derive(Resource, Default, Deref, DerefMut)]
+struct RoomMembers(HashMap<String, Entity>);
+
+/// The cards each client in the room has picked, keyed by client id. They
+/// stay while a client is away, so it gets them back when it resumes.
+#[derive(Resource, Default, Deref, DerefMut)]
+struct PickedCards(HashMap<String, Vec<Card>>);
+
+/// Paces how often game states are sent, independent of the frame rate
+#[derive(Resource, Deref, DerefMut)]
+struct SnapshotTimer(Timer);
+
+fn start_snapshot_timer(mut commands: Commands, config: Res<AppConfig>) {
+    commands.insert_resource(SnapshotTimer(Timer::from_seconds(
+        1. / config.snapshot_rate as f32,
+        TimerMode::Repeating,
+    )));
+}
+
+#[allow(clippy::too_many_arguments)]
+fn recv_room_messages(
+    mut commands: Commands,
+    inbox: Res<RoomInbox>,
+    mut members: ResMut<RoomMembers>,
+    mut picked_cards: ResMut<PickedCards>,
+    phase: Res<RoundPhase>,
+    mut latencies: ResMut<Latencies>,
+    mut spawn_events: EventWriter<PlayerSpawnEvent>,
+    mut input_events: EventWriter<PlayerInputEvent>,
+    mut disconnected_events: EventWriter<PlayerDisconnectedEvent>,
+    mut restart_events: EventWriter<RestartRoundEvent>,
+    mut rematch_events: EventWriter<RematchEvent>,
+    mut config: ResMut<AppConfig>,
+    mut player_inputs: Query<&mut PlayerInput>,
+    index: Res<EntityIndex>,
+) {
+    for message in inbox.try_iter() {
+        match message {
+            RoomMessage::Joined { client_id } => {
+                let entity = commands
+                    .spawn(crate::Player {
+                        client_id: client_id.to_string(),
+                    })
+                    .id();
+
+                members.insert(client_id, entity);
+            }
+            RoomMessage::Left { client_id } => {
+                if let Some(entity) = members.remove(&client_id) {
+                    commands.entity(entity).despawn_recursive();
+                }
+
+                latencies.remove(&client_id);
+                picked_cards.remove(&client_id);
+                disconnected_events.send(PlayerDisconnectedEvent { client_id });
+            }
+            RoomMessage::Suspended { client_id } => {
+                let player = match index.player(&client_id) {
+                    None => continue,
+                    Some(player) => player,
+                };
+
+                if let Ok(mut input) = player_inputs.get_mut(player) {
+                    input.release();
+                }
+            }
+            RoomMessage::Input { client_id, input } => match input.inner {
+                Some(applesauce::input::Inner::Spawn(_)) => {
+                    spawn_events.send(PlayerSpawnEvent { client_id });
+                }
+                Some(applesauce::input::Inner::State(state)) => {
+                    input_events.send(PlayerInputEvent {
+                        client_id,
+                        state: state.into(),
+                    });
+                }
+                Some(applesauce::input::Inner::Rematch(_)) => {
+                    rematch_events.send(RematchEvent { client_id });
+                }
+                Some(applesauce::input::Inner::PickCard(pick)) => {
+                    if *phase != RoundPhase::PickingCards {
+                        continue;
+                    }
+
+                    picked_cards
+                        .entry(client_id)
+                        .or_default()
+                        .push(pick.card.enum_value_or_default().into());
+                }
+                None => {}
+            },
+            RoomMessage::RoundTripTime { client_id, rtt } => {
+                latencies.insert(client_id, rtt);
+            }
+            RoomMessage::RestartRound => {
+                restart_events.send(RestartRoundEvent);
+            }
+            RoomMessage::SwitchLevel { level } => {
+                config.level = level;
+                restart_events.send(RestartRoundEvent);
+            }
+        }
+    }
+}
+
+fn send_game_events(outbox: Res<RoomOutbox>, mut events: EventReader<GameEvent>) {
+    for event in events.read() {
+        outbox
+            .send(applesauce::server_message::Inner::GameEvent(
+                applesauce::GameEvent {
+                    inner: Some(event.into()),
+                    special_fields: default(),
+                },
+            ))
+            .unwrap();
+    }
+}
+
+/// Tells the clients how the rounds are going whenever that changes
+fn send_round_status(outbox: Res<RoomOutbox>, scoreboard: Res<Scoreboard>, phase: Res<RoundPhase>) {
+    if !scoreboard.is_changed() && !phase.is_changed() {
+        return;
+    }
+
+    let status = RoundStatusEvent {
+        scoreboard: scoreboard.clone(),
+        phase: *phase,
+    };
+
+    outbox
+        .send(applesauce::server_message::Inner::RoundStatus(
+            (&status).into(),
+        ))
+        .unwrap();
+}
+
+/// Tells the clients which level the room is on whenever that changes
+fn send_level(outbox: Res<RoomOutbox>, config: Res<AppConfig>, mut sent: Local<Option<String>>) {
+    if sent.as_ref() == Some(&config.level) {
+        return;
+    }
+
+    outbox
+        .send(applesauce::server_message::Inner::Level(
+            applesauce::Level {
+                path: config.level.to_string(),
+                special_fields: default(),
+            },
+        ))
+        .unwrap();
+
+    *sent = Some(config.level.to_string());
+}
+
+/// Tells the clients which cards everyone has whenever someone picks one
+fn send_picked_cards(outbox: Res<RoomOutbox>, picked_cards: Res<PickedCards>) {
+    if !picked_cards.is_changed() {
+        return;
+    }
+
+    outbox
+        .send(applesauce::server_message::Inner::PickedCards(
+            applesauce::PickedCards {
+                players: picked_cards
+                    .iter()
+                    .map(|(client_id, cards)| applesauce::PlayerCards {
+                        client_id: client_id.to_string(),
+                        cards: cards
+                            .iter()
+                            .map(|card| applesauce::Card::from(*card).into())
+                            .collect(),
+                        special_fields: default(),
+                    })
+                    .collect(),
+                special_fields: default(),
+            },
+        ))
+        .unwrap();
+}
+
+/// Tells the clients whose each player is whenever players come or go
+fn send_roster(
+    outbox: Res<RoomOutbox>,
+    players: Query<(&NetworkId, &Player)>,
+    spawned: Query<(), Added<Player>>,
+    mut despawned: RemovedComponents<Player>,
+) {
+    let despawned = despawned.read().count() > 0;
+    if spawned.is_empty() && !despawned {
+        return;
+    }
+
+    outbox
+        .send(applesauce::server_message::Inner::Roster(
+            applesauce::Roster {
+                players: players
+                    .iter()
+                    .map(|(id, player)| applesauce::RosterEntry {
+                        id: **id,
+                        client_id: player.client_id.to_string(),
+                        // filled in by the server, which is the one that knows them
+                        display_name: default(),
+                        spawn_id: player.spawn_id.to_string(),
+                        special_fields: default(),
+                    })
+                    .collect(),
+                special_fields: default(),
+            },
+        ))
+        .unwrap();
+}
+
+#[allow(clippy::too_many_arguments)]
+fn send_state(
+    outbox: Res<RoomOutbox>,
+    players: Query<(
+        &NetworkId,
+        &Player,
+        &Transform,
+        &Velocity,
+        &Health,
+        &ShieldTimeout,
+        &Children,
+    )>,
+    inputs: Query<(&NetworkId, &PlayerInput)>,
+    guns: Query<&Gun>,
+    shields: Query<&Shield>,
+    bullets: Query<(&NetworkId, &Transform, &Velocity), With<Bullet>>,
+    mut timer: ResMut<SnapshotTimer>,
+    tick: Res<SimulationTick>,
+    time: Res<Time>,
+    fixed_time: Res<Time<Fixed>>,
+) {
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    outbox
+        .send(applesauce::server_message::Inner::GameState(
+            applesauce::GameState {
+                timestamp: fixed_time.elapsed().as_millis() as u64,
+                tick: **tick,
+                players: players
+                    .iter()
+                    .map(
+                        |(id, player, transform, velocity, health, shield_timeout, children)| {
+                            let shield = children.iter().find_map(|child| shields.get(*child).ok());
+                            let gun = children.iter().find_map(|child| guns.get(*child).ok());
+
+                            applesauce::Player {
+                                id: **id,
+                                radius: player.radius,
+                                color: applesauce::Color::from(player.color).into(),
+                                position: applesauce::Vec3::from(transform.translation).into(),
+                                velocity: applesauce::Vec2::from(velocity.linvel).into(),
+                                health: health.0,
+                                shield: shield
+                                    .map(|shield| applesauce::Shield {
+                                        radius: shield.radius,
+                                        remaining: shield.ttl.remaining().as_millis() as u64,
+                                        special_fields: default(),
+                                    })
+                                    .into(),
+                                shield_cooldown_elapsed: shield_timeout.elapsed().as_millis()
+                                    as u64,
+                                gun: gun
+                                    .map(|gun| applesauce::Gun {
+                                        bullet_count: gun.bullet_count,
+                                        bullet_capacity: gun.bullet_capacity,
+                                        special_fields: default(),
+                                    })
+                                    .into(),
+                                special_fields: default(),
+                            }
+                        },
+                    )
+                    .collect(),
+                bullets: bullets
+                    .iter()
+                    .map(|(id, transform, velocity)| applesauce::Bullet {
+                        id: **id,
+                        position: applesauce::Vec3::from(transform.translation).into(),
+                        rotation: applesauce::Quat::from(transform.rotation).into(),
+                        velocity: applesauce::Vec2::from(velocity.linvel).into(),
+                        special_fields: default(),
+                    })
+                    .collect(),
+                // an input state counts as processed once a tick has been
+                // simulated with it, which happened before this frame's PostUpdate
+                input_acks: inputs
+                    .iter()
+                    .map(|(id, input)| (**id, input.state().tick))
+                    .collect(),
+                // filled in by the server, which is the one measuring them
+                round_trip_times: default(),
+                special_fields: default(),
+            },
+        ))
+        .unwrap();
+}
@@ -0,0 +1,691 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    events::{
+        GameEvent, PlayerDisconnectedEvent, PlayerSpawnEvent, RematchEvent, RoomJoinedEvent,
+        RoundStatusEvent,
+    },
+    manage_state::{Despawn, Health, Paused, Player},
+    AppConfig, GameState,
+};
+
+/// Where a room is in its rounds
+#[derive(Resource, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub(crate) enum RoundPhase {
+    #[default]
+    Playing,
+    /// The round is over and everyone is looking at the results
+    Results,
+    /// Everyone is picking a card for the next round
+    PickingCards,
+    /// Someone won the match. It stays over until everyone asks for a rematch.
+    MatchOver,
+}
+
+/// How many rounds it takes to win a match
+#[derive(Reflect, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MatchFormat {
+    /// The first to win this many rounds wins the match
+    FirstTo(u32),
+    /// Whoever wins most of this many rounds wins the match. A tie after the
+    /// last of them is played out until someone is ahead.
+    BestOf(u32),
+}
+
+impl Default for MatchFormat {
+    fn default() -> Self {
+        Self::FirstTo(3)
+    }
+}
+
+impl MatchFormat {
+    /// Who won the match with these points, if anyone did yet
+    fn winner<'a>(&self, points: &'a HashMap<String, u32>, rounds_played: u32) -> Option<&'a str> {
+        let standings = standings(points);
+        let (leader, leader_points) = *standings.first()?;
+        let runner_up_points = standings.get(1).map(|(_, points)| *points).unwrap_or(0);
+
+        if leader_points == runner_up_points {
+            return None;
+        }
+
+        let won = match *self {
+            Self::FirstTo(rounds) => leader_points >= rounds,
+            Self::BestOf(rounds) => leader_points > rounds / 2 || rounds_played >= rounds,
+        };
+
+        match won {
+            true => Some(leader),
+            false => None,
+        }
+    }
+}
+
+impl fmt::Display for MatchFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FirstTo(rounds) => write!(f, "First to {}", rounds),
+            Self::BestOf(rounds) => write!(f, "Best of {}", rounds),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ParseMatchFormatError {
+    /// Must be first-to-N or best-of-N, like best-of-5
+    UnknownFormat,
+    /// The number of rounds must be a whole number above 0
+    InvalidRounds,
+}
+
+impl FromStr for MatchFormat {
+    type Err = ParseMatchFormatError;
+
+    /// Also takes what Display makes of it, like "Best of 5"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_lowercase().replace(' ', "-");
+
+        let (format, rounds): (fn(u32) -> Self, &str) =
+            match (s.strip_prefix("first-to-"), s.strip_prefix("best-of-")) {
+                (Some(rounds), _) => (Self::FirstTo, rounds),
+                (_, Some(rounds)) => (Self::BestOf, rounds),
+                _ => return Err(ParseMatchFormatError::UnknownFormat),
+            };
+
+        match rounds.parse() {
+            Ok(0) | Err(_) => Err(ParseMatchFormatError::InvalidRounds),
+            Ok(rounds) => Ok(format(rounds)),
+        }
+    }
+}
+
+/// How many rounds each player has won. The server keeps score, clients only get told.
+#[derive(Resource, Clone, Default)]
+pub(crate) struct Scoreboard {
+    pub(crate) format: MatchFormat,
+    pub(crate) rounds_played: u32,
+    /// Rounds won, keyed by client id. Everyone who played in a round is in it, even without a win.
+    pub(crate) points: HashMap<String, u32>,
+    /// The client whose player was left standing at the end of the last round. None for a draw.
+    pub(crate) last_winner: Option<String>,
+    /// None until the match is over
+    pub(crate) match_winner: Option<String>,
+    /// The clients that asked for a rematch since the match ended
+    pub(crate) rematch_votes: HashSet<String>,
+    /// Keyed by client id. Only clients have these, the server fills them in on the way out.
+    pub(crate) display_names: HashMap<String, String>,
+}
+
+impl Scoreboard {
+    /// Who a client is, by name when they have one
+    pub(crate) fn display_name<'a>(&'a self, client_id: &'a str) -> &'a str {
+        match self.display_names.get(client_id) {
+            Some(name) if !name.is_empty() => name,
+            _ => client_id,
+        }
+    }
+
+    /// The clients that win the match if they win the next round. Clients
+    /// work this out for themselves, from the same points the server has.
+    pub(crate) fn match_point(&self) -> Vec<&str> {
+        if self.match_winner.is_some() {
+            return vec![];
+        }
+
+        let mut match_point: Vec<&str> = self
+            .points
+            .keys()
+            .filter(|client_id| {
+                let mut points = self.points.clone();
+                *points.entry(client_id.to_string()).or_default() += 1;
+
+                self.format.winner(&points, self.rounds_played + 1) == Some(client_id.as_str())
+            })
+            .map(|client_id| client_id.as_str())
+            .collect();
+
+        match_point.sort();
+        match_point
+    }
+
+    /// Everyone back to no points, for a rematch in the given format
+    fn reset(&mut self, format: MatchFormat) {
+        self.format = format;
+        self.rounds_played = 0;
+        self.points.values_mut().for_each(|points| *points = 0);
+        self.last_winner = None;
+        self.match_winner = None;
+        self.rematch_votes.clear();
+    }
+}
+
+/// Most points first, ties by client id so everyone lists them the same
+fn standings(points: &HashMap<String, u32>) -> Vec<(&str, u32)> {
+    let mut standings: Vec<(&str, u32)> = points
+        .iter()
+        .map(|(client_id, points)| (client_id.as_str(), *points))
+        .collect();
+
+    standings
+        .sort_by(|(a_id, a_points), (b_id, b_points)| b_points.cmp(a_points).then(a_id.cmp(b_id)));
+    standings
+}
+
+/// Ends the round once a single player is left standing and gives them the
+/// point, then takes everyone through the results and back to picking cards
+/// before the next round starts on a freshly loaded level. Once someone wins
+/// the match, it waits for everyone to ask for a rematch. Runs in the
+/// server's rooms, whose word on the score is the one that counts.
+pub(crate) struct RoundControllerPlugin;
+
+impl Plugin for RoundControllerPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<RematchEvent>()
+            .init_resource::<Scoreboard>()
+            .init_resource::<RoundPhase>()
+            .init_resource::<RoundController>()
+            .add_systems(Startup, start_match)
+            .add_systems(OnEnter(GameState::Round), respawn_contenders)
+            .add_systems(
+                Update,
+                (
+                    track_contenders,
+                    end_round_when_one_player_is_left
+                        .run_if(in_state(GameState::Round))
+                        .run_if(resource_equals(RoundPhase::Playing)),
+                    start_rematch_once_everyone_asks.run_if(resource_equals(RoundPhase::MatchOver)),
+                    advance_round_phase,
+                )
+                    .chain(),
+            );
+    }
+}
+
+/// Shows the results between rounds and at the end of the match, and follows
+/// the server from one round to the next
+pub struct ScoreboardPlugin;
+
+impl Plugin for ScoreboardPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<RoundStatusEvent>()
+            .add_event::<RoomJoinedEvent>()
+            .add_event::<RematchEvent>()
+            .init_resource::<Scoreboard>()
+            .init_resource::<RoundPhase>()
+            .add_systems(
+                Update,
+                (follow_round_status, show_results, ask_for_rematch).chain(),
+            );
+    }
+}
+
+#[derive(Resource, Default)]
+struct RoundController {
+    /// Everyone who played in this round, dead or alive. They all get to play in the next one.
+    contenders: HashSet<String>,
+    /// Counts down the results, and then the card picking
+    timer: Option<Timer>,
+}
+
+#[derive(Component)]
+struct ResultsScreen;
+
+#[derive(Component)]
+struct RematchButton;
+
+const BUTTON_COLOR: Color = Color::rgb(0.15, 0.15, 0.15);
+const HOVERED_BUTTON_COLOR: Color = Color::rgb(0.25, 0.25, 0.25);
+
+fn start_match(mut scoreboard: ResMut<Scoreboard>, config: Res<AppConfig>) {
+    scoreboard.format = config.match_format;
+}
+
+fn track_contenders(
+    mut controller: ResMut<RoundController>,
+    mut game_events: EventReader<GameEvent>,
+    mut disconnected_events: EventReader<PlayerDisconnectedEvent>,
+) {
+    for event in game_events.read() {
+        if let GameEvent::PlayerSpawned { client_id, .. } = event {
+            controller.contenders.insert(client_id.to_string());
+        }
+    }
+
+    for event in disconnected_events.read() {
+        controller.contenders.remove(&event.client_id);
+    }
+}
+
+fn end_round_when_one_player_is_left(
+    mut commands: Commands,
+    mut controller: ResMut<RoundController>,
+    mut scoreboard: ResMut<Scoreboard>,
+    mut phase: ResMut<RoundPhase>,
+    players: Query<(&Player, &Health), Without<Despawn>>,
+    config: Res<AppConfig>,
+) {
+    // there is nobody to beat on your own
+    if controller.contenders.len() < 2 {
+        return;
+    }
+
+    let standing: Vec<&Player> = players
+        .iter()
+        .filter(|(_, health)| health.0 > 0)
+        .map(|(player, _)| player)
+        .collect();
+
+    if standing.len() > 1 {
+        return;
+    }
+
+    let winner = standing.first().map(|player| player.client_id.to_string());
+
+    scoreboard.rounds_played += 1;
+    for client_id in controller.contenders.iter() {
+        scoreboard.points.entry(client_id.to_string()).or_default();
+    }
+    if let Some(winner) = &winner {
+        *scoreboard.points.entry(winner.to_string()).or_default() += 1;
+    }
+    scoreboard.last_winner = winner;
+
+    match &scoreboard.last_winner {
+        None => println!("Round {} is a draw", scoreboard.rounds_played),
+        Some(winner) => println!(
+            "Round {} goes to client-id: {}",
+            scoreboard.rounds_played, winner
+        ),
+    }
+
+    scoreboard.match_winner = scoreboard
+        .format
+        .winner(&scoreboard.points, scoreboard.rounds_played)
+        .map(str::to_string);
+
+    if let Some(match_winner) = &scoreboard.match_winner {
+        println!("The match goes to client-id: {}", match_winner);
+
+        *phase = RoundPhase::MatchOver;
+        controller.timer = None;
+        // nobody gets to play on until the rematch
+        commands.insert_resource(Paused);
+        return;
+    }
+
+    for client_id in scoreboard.match_point() {
+        println!("Match point for client-id: {}", client_id);
+    }
+
+    *phase = RoundPhase::Results;
+    controller.timer = Some(Timer::new(
+        Duration::from_millis(config.results_duration),
+        TimerMode::Once,
+    ));
+}
+
+/// Everyone who played the last round has to ask. Whoever left since doesn't
+/// hold the rest up.
+fn start_rematch_once_everyone_asks(
+    mut commands: Commands,
+    mut controller: ResMut<RoundController>,
+    mut scoreboard: ResMut<Scoreboard>,
+    mut phase: ResMut<RoundPhase>,
+    mut state: ResMut<NextState<GameState>>,
+    mut events: EventReader<RematchEvent>,
+    config: Res<AppConfig>,
+) {
+    for event in events.read() {
+        if !controller.contenders.contains(&event.client_id)
+            || scoreboard.rematch_votes.contains(&event.client_id)
+        {
+            continue;
+        }
+
+        println!("Client {} asked for a rematch", event.client_id);
+        scoreboard.rematch_votes.insert(event.client_id.to_string());
+    }
+
+    if controller.contenders.is_empty()
+        || !controller
+            .contenders
+            .iter()
+            .all(|client_id| scoreboard.rematch_votes.contains(client_id))
+    {
+        return;
+    }
+
+    println!("Starting a rematch");
+
+    // the players that left get no place in the standings of the rematch
+    let contenders = controller.contenders.clone();
+    scoreboard
+        .points
+        .retain(|client_id, _| contenders.contains(client_id));
+    // the host may have changed the format since the last match started
+    scoreboard.reset(config.match_format);
+    commands.remove_resource::<Paused>();
+
+    *phase = RoundPhase::PickingCards;
+    controller.timer = Some(Timer::new(
+        Duration::from_millis(config.pick_card_duration),
+        TimerMode::Once,
+    ));
+    state.set(GameState::PickCard);
+}
+
+fn advance_round_phase(
+    mut controller: ResMut<RoundController>,
+    mut phase: ResMut<RoundPhase>,
+    mut state: ResMut<NextState<GameState>>,
+    config: Res<AppConfig>,
+    time: Res<Time>,
+) {
+    let timer = match controller.timer.as_mut() {
+        None => return,
+        Some(timer) => timer,
+    };
+
+    if !timer.tick(time.delta()).finished() {
+        return;
+    }
+
+    match *phase {
+        RoundPhase::Playing | RoundPhase::MatchOver => controller.timer = None,
+        RoundPhase::Results => {
+            *phase = RoundPhase::PickingCards;
+            controller.timer = Some(Timer::new(
+                Duration::from_millis(config.pick_card_duration),
+                TimerMode::Once,
+            ));
+            state.set(GameState::PickCard);
+        }
+        RoundPhase::PickingCards => {
+            *phase = RoundPhase::Playing;
+            controller.timer = None;
+            state.set(GameState::Round);
+        }
+    }
+}
+
+/// Brings back everyone who played in the last round, with full health and
+/// ammo. They get counted again as they spawn, so spawning nobody is no draw.
+fn respawn_contenders(
+    mut controller: ResMut<RoundController>,
+    mut spawn_events: EventWriter<PlayerSpawnEvent>,
+) {
+    // handled next frame, once the level's spawns are in place
+    for client_id in controller.contenders.drain() {
+        spawn_events.send(PlayerSpawnEvent { client_id });
+    }
+}
+
+/// Moves us along with the server from round to round
+fn follow_round_status(
+    mut commands: Commands,
+    mut events: EventReader<RoundStatusEvent>,
+    mut room_joined_events: EventReader<RoomJoinedEvent>,
+    mut scoreboard: ResMut<Scoreboard>,
+    mut phase: ResMut<RoundPhase>,
+    mut state: ResMut<NextState<GameState>>,
+    mut heard_from_room: Local<bool>,
+) {
+    if room_joined_events.read().count() > 0 {
+        *heard_from_room = false;
+    }
+
+    let status = match events.read().last() {
+        None => return,
+        Some(status) => status,
+    };
+
+    // the first status only tells us where the room is at. Whoever just came
+    // in still gets to pick a card before playing.
+    if *heard_from_room && status.phase != *phase {
+        match status.phase {
+            RoundPhase::Playing => state.set(GameState::Round),
+            RoundPhase::PickingCards => state.set(GameState::PickCard),
+            RoundPhase::Results | RoundPhase::MatchOver => {}
+        }
+    }
+
+    // our own simulation stands still along with the server's
+    match status.phase {
+        RoundPhase::MatchOver => commands.insert_resource(Paused),
+        _ => commands.remove_resource::<Paused>(),
+    }
+
+    *heard_from_room = true;
+    *scoreboard = status.scoreboard.clone();
+    *phase = status.phase;
+}
+
+fn show_results(
+    mut commands: Commands,
+    phase: Res<RoundPhase>,
+    scoreboard: Res<Scoreboard>,
+    screens: Query<Entity, With<ResultsScreen>>,
+) {
+    if !phase.is_changed() && !scoreboard.is_changed() {
+        return;
+    }
+
+    for screen in screens.iter() {
+        commands.entity(screen).despawn_recursive();
+    }
+
+    let headline = match (*phase, &scoreboard.match_winner, &scoreboard.last_winner) {
+        (RoundPhase::MatchOver, Some(winner), _) => {
+            format!("{} wins the match", scoreboard.display_name(winner))
+        }
+        (RoundPhase::Results, _, Some(winner)) => format!(
+            "{} wins round {}",
+            scoreboard.display_name(winner),
+            scoreboard.rounds_played
+        ),
+        (RoundPhase::Results, _, None) => format!("Round {} is a draw", scoreboard.rounds_played),
+        _ => return,
+    };
+
+    let announcements: Vec<String> = match *phase {
+        RoundPhase::MatchOver => vec![
+            "Final standings".to_string(),
+            format!("{} want a rematch", scoreboard.rematch_votes.len()),
+        ],
+        _ => scoreboard
+            .match_point()
+            .into_iter()
+            .map(|client_id| format!("Match point for {}", scoreboard.display_name(client_id)))
+            .collect(),
+    };
+
+    let style = TextStyle {
+        font_size: 20.,
+        color: Color::WHITE,
+        ..default()
+    };
+
+    commands
+        .spawn((
+            ResultsScreen,
+            NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    width: Val::Percent(100.),
+                    height: Val::Percent(100.),
+                    flex_direction: FlexDirection::Column,
+                    align_items: AlignItems::Center,
+                    justify_content: JustifyContent::Center,
+                    row_gap: Val::Px(10.),
+                    ..default()
+                },
+                background_color: Color::rgba(0., 0., 0., 0.7).into(),
+                z_index: ZIndex::Global(5),
+                ..default()
+            },
+        ))
+        .with_children(|parent| {
+            parent.spawn(TextBundle::from_section(
+                headline,
+                TextStyle {
+                    font_size: 32.,
+                    ..style.clone()
+                },
+            ));
+
+            parent.spawn(TextBundle::from_section(
+                scoreboard.format.to_string(),
+                TextStyle {
+                    color: Color::GRAY,
+                    ..style.clone()
+                },
+            ));
+
+            for announcement in announcements {
+                parent.spawn(TextBundle::from_section(
+                    announcement,
+                    TextStyle {
+                        color: Color::GOLD,
+                        ..style.clone()
+                    },
+                ));
+            }
+
+            for (client_id, points) in standings(&scoreboard.points) {
+                parent.spawn(TextBundle::from_section(
+                    format!("{}   {}", scoreboard.display_name(client_id), points),
+                    style.clone(),
+                ));
+            }
+
+            if *phase != RoundPhase::MatchOver {
+                return;
+            }
+
+            parent
+                .spawn((
+                    RematchButton,
+                    ButtonBundle {
+                        style: Style {
+                            padding: UiRect::all(Val::Px(10.)),
+                            ..default()
+                        },
+                        background_color: BUTTON_COLOR.into(),
+                        ..default()
+                    },
+                ))
+                .with_children(|button| {
+                    button.spawn(TextBundle::from_section("Rematch", style.clone()));
+                });
+        });
+}
+
+/// The server starts the rematch once everyone has clicked
+fn ask_for_rematch(
+    mut buttons: Query<(&Interaction, &RematchButton, &mut BackgroundColor), Changed<Interaction>>,
+    mut events: EventWriter<RematchEvent>,
+    config: Res<AppConfig>,
+) {
+    for (interaction, _, mut color) in buttons.iter_mut() {
+        match interaction {
+            Interaction::Hovered => *color = HOVERED_BUTTON_COLOR.into(),
+            Interaction::None => *color = BUTTON_COLOR.into(),
+            Interaction::Pressed => {
+                events.send(RematchEvent {
+                    client_id: config.client_id.to_string(),
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn points(points: &[(&str, u32)]) -> HashMap<String, u32> {
+        points
+            .iter()
+            .map(|(client_id, points)| (client_id.to_string(), *points))
+            .collect()
+    }
+
+    #[test]
+    fn first_to_is_won_by_reaching_the_rounds() {
+        let format = MatchFormat::FirstTo(3);
+
+        assert_eq!(format.winner(&points(&[]), 0), None);
+        assert_eq!(format.winner(&points(&[("a", 2), ("b", 1)]), 3), None);
+        assert_eq!(format.winner(&points(&[("a", 3), ("b", 1)]), 4), Some("a"));
+    }
+
+    #[test]
+    fn best_of_is_won_by_a_majority_or_a_lead_after_the_last_round() {
+        let format = MatchFormat::BestOf(5);
+
+        assert_eq!(format.winner(&points(&[("a", 2), ("b", 1)]), 3), None);
+        assert_eq!(format.winner(&points(&[("a", 3), ("b", 0)]), 3), Some("a"));
+        // draws leave nobody with a majority by the last round
+        assert_eq!(format.winner(&points(&[("a", 2), ("b", 1)]), 5), Some("a"));
+    }
+
+    #[test]
+    fn best_of_tied_after_the_last_round_goes_on_until_someone_is_ahead() {
+        let format = MatchFormat::BestOf(4);
+
+        assert_eq!(format.winner(&points(&[("a", 2), ("b", 2)]), 4), None);
+        assert_eq!(format.winner(&points(&[("a", 2), ("b", 2)]), 5), None);
+        assert_eq!(format.winner(&points(&[("a", 2), ("b", 3)]), 6), Some("b"));
+    }
+
+    #[test]
+    fn parses_what_it_displays() {
+        for format in [MatchFormat::FirstTo(3), MatchFormat::BestOf(5)] {
+            assert_eq!(format.to_string().parse::<MatchFormat>().ok(), Some(format));
+        }
+
+        assert_eq!(
+            "best-of-7".parse::<MatchFormat>().ok(),
+            Some(MatchFormat::BestOf(7))
+        );
+        assert_eq!(
+            " First To 1 ".parse::<MatchFormat>().ok(),
+            Some(MatchFormat::FirstTo(1))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_formats() {
+        let parse = |s: &str| s.parse::<MatchFormat>();
+
+        assert!(matches!(
+            parse(""),
+            Err(ParseMatchFormatError::UnknownFormat)
+        ));
+        assert!(matches!(
+            parse("most-of-3"),
+            Err(ParseMatchFormatError::UnknownFormat)
+        ));
+        assert!(matches!(
+            parse("best-of-0"),
+            Err(ParseMatchFormatError::InvalidRounds)
+        ));
+        assert!(matches!(
+            parse("first-to-"),
+            Err(ParseMatchFormatError::InvalidRounds)
+        ));
+        assert!(matches!(
+            parse("first-to--2"),
+            Err(ParseMatchFormatError::InvalidRounds)
+        ));
+        assert!(matches!(
+            parse("best-of-two"),
+            Err(ParseMatchFormatError::InvalidRounds)
+        ));
+    }
+}
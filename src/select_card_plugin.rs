@@ -1,17 +1,29 @@
-use crate::{AppConfig, GameState};
+use crate::{
+    events::{Card, CardPickedEvent, PickedCardsEvent},
+    AppConfig, GameState,
+};
 use bevy::prelude::*;
 
 #[derive(Component, Reflect)]
 struct SelectCardUi;
 
 #[derive(Component, Reflect)]
-struct MoveFaster;
+struct CardButton(Card);
 
-pub(crate) struct SelectCardPlugin;
+/// The cards we picked. The server has the final say, and gives them back
+/// when we resume after losing the connection.
+#[derive(Resource, Default, Deref, DerefMut)]
+struct Hand(Vec<Card>);
+
+pub struct SelectCardPlugin;
 
 impl Plugin for SelectCardPlugin {
     fn build(&self, app: &mut App) {
         // This stage allows both players to pick a card. The card modifies various abilities of the player.
+        app.add_event::<CardPickedEvent>()
+            .add_event::<PickedCardsEvent>()
+            .init_resource::<Hand>()
+            .add_systems(PreUpdate, follow_picked_cards);
         app.add_systems(OnEnter(GameState::PickCard), setup);
         app.add_systems(
             Update,
@@ -37,55 +49,35 @@ fn setup(mut commands: Commands) {
             SelectCardUi,
         ))
         .with_children(|parent| {
-            parent
-                .spawn(ButtonBundle {
-                    style: Style {
-                        height: Val::Px(100.),
-                        width: Val::Px(200.),
-                        border: UiRect::all(Val::Px(5.0)),
-                        justify_content: JustifyContent::Center,
-                        align_items: AlignItems::Center,
-                        ..Default::default()
-                    },
-                    border_color: BorderColor(Color::BLACK),
-                    background_color: NORMAL_BUTTON.into(),
-                    ..Default::default()
-                })
-                .with_children(|button| {
-                    button.spawn(TextBundle::from_section(
-                        "Move Faster",
-                        TextStyle {
-                            font_size: 20.,
-                            color: Color::WHITE,
+            for card in [Card::MoveFaster, Card::MoreDamage] {
+                parent
+                    .spawn((
+                        ButtonBundle {
+                            style: Style {
+                                height: Val::Px(100.),
+                                width: Val::Px(200.),
+                                border: UiRect::all(Val::Px(5.0)),
+                                justify_content: JustifyContent::Center,
+                                align_items: AlignItems::Center,
+                                ..Default::default()
+                            },
+                            border_color: BorderColor(Color::BLACK),
+                            background_color: NORMAL_BUTTON.into(),
                             ..Default::default()
                         },
-                    ));
-                });
-
-            parent
-                .spawn(ButtonBundle {
-                    style: Style {
-                        height: Val::Px(100.),
-                        width: Val::Px(200.),
-                        border: UiRect::all(Val::Px(5.0)),
-                        justify_content: JustifyContent::Center,
-                        align_items: AlignItems::Center,
-                        ..Default::default()
-                    },
-                    border_color: BorderColor(Color::BLACK),
-                    background_color: NORMAL_BUTTON.into(),
-                    ..Default::default()
-                })
-                .with_children(|button| {
-                    button.spawn(TextBundle::from_section(
-                        "More Damage",
-                        TextStyle {
-                            font_size: 20.,
-                            color: Color::WHITE,
-                            ..Default::default()
-                        },
-                    ));
-                });
+                        CardButton(card),
+                    ))
+                    .with_children(|button| {
+                        button.spawn(TextBundle::from_section(
+                            card.name(),
+                            TextStyle {
+                                font_size: 20.,
+                                color: Color::WHITE,
+                                ..Default::default()
+                            },
+                        ));
+                    });
+            }
         });
 }
 
@@ -109,31 +101,27 @@ const HOVERED_BUTTON: Color = Color::rgb(0.25, 0.25, 0.25);
 const PRESSED_BUTTON: Color = Color::rgb(0.35, 0.75, 0.35);
 
 fn button_system(
-    mut commands: Commands,
-    config: Res<AppConfig>,
     mut interaction_query: Query<
-        (&Interaction, &mut BackgroundColor, &mut BorderColor),
-        (Changed<Interaction>, With<Button>),
+        (
+            &Interaction,
+            &CardButton,
+            &mut BackgroundColor,
+            &mut BorderColor,
+        ),
+        Changed<Interaction>,
     >,
-    players: Query<(Entity, &crate::Player)>,
+    mut hand: ResMut<Hand>,
+    mut events: EventWriter<CardPickedEvent>,
     mut state: ResMut<NextState<GameState>>,
 ) {
-    for (interaction, mut color, mut border_color) in &mut interaction_query {
+    for (interaction, card_button, mut color, mut border_color) in &mut interaction_query {
         match *interaction {
             Interaction::Pressed => {
-                let player = players
-                    .iter()
-                    .find(|(_, p)| p.client_id == config.client_id);
-
-                match player {
-                    None => println!("We could not find ourselves: {}", config.client_id),
-                    Some((entity, _)) => {
-                        commands.entity(entity).with_children(|parent| {
-                            parent.spawn(MoveFaster);
-                        });
-                        state.set(GameState::Round);
-                    }
-                };
+                hand.push(card_button.0);
+                events.send(CardPickedEvent {
+                    card: card_button.0,
+                });
+                state.set(GameState::Round);
 
                 *color = PRESSED_BUTTON.into();
                 border_color.0 = Color::RED;
@@ -149,3 +137,17 @@ fn button_system(
         }
     }
 }
+
+fn follow_picked_cards(
+    config: Res<AppConfig>,
+    mut events: EventReader<PickedCardsEvent>,
+    mut hand: ResMut<Hand>,
+) {
+    if let Some(event) = events.read().last() {
+        hand.0 = event
+            .cards
+            .get(&config.client_id)
+            .cloned()
+            .unwrap_or_default();
+    }
+}
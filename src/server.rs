@@ -1,225 +1,1552 @@
 use std::{
-    net::{TcpListener, TcpStream},
+    collections::{HashMap, VecDeque},
+    net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs},
     thread,
+    time::Duration,
 };
 
-use bevy::prelude::*;
-use bevy_rapier2d::dynamics::Velocity;
+use bevy::{app::ScheduleRunnerPlugin, prelude::*};
 use crossbeam_channel::{Receiver, Sender};
 use protobuf::{CodedInputStream, Message};
+use serde_json::json;
 use uuid::Uuid;
 
 use crate::{
-    events::{
-        PlayerBlockEvent, PlayerJumpEvent, PlayerMoveLeftEvent, PlayerMoveRightEvent,
-        PlayerShootEvent, PlayerSpawnEvent,
-    },
-    manage_state::{Bullet, Player},
-    protos::generated::applesauce,
-    AppConfig,
+    admin::{self, AdminCommand, AdminRequest},
+    conditioner::{self, LinkConditions, NetworkConditions},
+    discovery::{DiscoveryListener, DISCOVERY_PORT},
+    level::{self, PlayerSpawn},
+    protos::{generated::applesauce, BUILD_ID, MAX_CHAT_LENGTH, PROTOCOL_VERSION},
+    room::{RoomMessage, RoomPlugin},
+    AppConfig, GameState, ManageStatePlugin,
 };
 
-pub(crate) struct ServerPlugin {
+/// The room every client starts out in. It runs in the server's own world.
+const LOBBY_ID: &str = "lobby";
+
+/// How long a new connection gets to say Hello before we hang up on it
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often we measure the round trip time to each client
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many chat messages a client may send within CHAT_RATE_WINDOW
+const CHAT_RATE_LIMIT: usize = 5;
+const CHAT_RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// Who notices from the server are from. No player may call themselves this.
+const SERVER_DISPLAY_NAME: &str = "server";
+
+pub struct ServerPlugin {
     hostname: String,
+    admin_hostname: Option<String>,
+    /// What clients looking for servers on the LAN see us as
+    name: String,
 }
 
 impl ServerPlugin {
-    pub(crate) fn serve_on(hostname: String) -> Self {
-        Self { hostname }
+    pub fn serve_on(hostname: String) -> Self {
+        Self {
+            name: format!("Server on {}", hostname),
+            hostname,
+            admin_hostname: None,
+        }
+    }
+
+    /// The name clients looking for servers on the LAN see
+    pub fn named(mut self, name: String) -> Self {
+        self.name = name;
+        self
+    }
+
+    /// Also takes admin commands on this port, which has to be on localhost.
+    /// Commands are always read from stdin.
+    pub fn admin_on(mut self, hostname: String) -> Self {
+        self.admin_hostname = Some(hostname);
+        self
     }
 }
 
 impl Plugin for ServerPlugin {
     fn build(&self, app: &mut App) {
+        let (tx_inbox, rx_inbox) = crossbeam_channel::unbounded::<RoomMessage>();
+        let (tx_outbox, rx_outbox) =
+            crossbeam_channel::unbounded::<applesauce::server_message::Inner>();
+
         app.insert_resource(ServerConfig {
             hostname: self.hostname.clone(),
+            admin_hostname: self.admin_hostname.clone(),
+            name: self.name.clone(),
+            server_id: Uuid::new_v4().to_string(),
         })
+        .insert_resource(Rooms(HashMap::from([(
+            LOBBY_ID.to_string(),
+            Room {
+                name: "Lobby".to_string(),
+                join_code: None,
+                inbox: tx_inbox,
+                outbox: rx_outbox,
+                roster: default(),
+                round_status: default(),
+                picked_cards: default(),
+                level: default(),
+            },
+        )])))
+        .insert_non_send_resource(RoomApps::default())
+        .init_resource::<ClientSessions>()
+        .init_resource::<RoundTripTimes>()
+        .init_resource::<RecentChatMessages>()
+        .init_resource::<NetworkConditions>()
+        .add_plugins(RoomPlugin::new(rx_inbox, tx_outbox))
         .add_systems(Startup, assign_client_id)
-        .add_systems(Startup, serve)
-        .add_systems(PreUpdate, handle_identity)
-        .add_systems(PreUpdate, recv_input)
-        .add_systems(PostUpdate, send_state);
+        .add_systems(
+            Startup,
+            (
+                serve,
+                listen_for_admin_commands,
+                listen_for_discovery_queries,
+            ),
+        )
+        .add_systems(
+            PreUpdate,
+            (
+                handle_handshake,
+                handle_room_requests,
+                recv_input,
+                handle_disconnect,
+                expire_suspended_sessions,
+                close_empty_rooms,
+            )
+                .chain(),
+        )
+        .add_systems(
+            PreUpdate,
+            (
+                recv_pongs,
+                broadcast_chat_messages,
+                handle_admin_requests,
+                answer_discovery_queries,
+            ),
+        )
+        .add_systems(Update, (update_rooms, send_pings))
+        .add_systems(Last, route_room_messages);
     }
 }
 
 #[derive(Resource)]
 struct ServerConfig {
     hostname: String,
+    admin_hostname: Option<String>,
+    name: String,
+    /// Lets clients tell our answers to discovery queries apart from other servers'
+    server_id: String,
+}
+
+/// A message for some of the connected clients
+#[derive(Clone)]
+struct Outgoing {
+    client_ids: Vec<String>,
+    message: applesauce::ServerMessage,
 }
 
-// #[derive(Resource)]
-// struct InputEventReceiver(Receiver<applesauce::GameState>);
+#[derive(Resource, Deref)]
+struct OutgoingSender(Sender<Outgoing>);
+
+impl OutgoingSender {
+    fn send_to(&self, client_id: &str, inner: applesauce::server_message::Inner) {
+        self.send(Outgoing {
+            client_ids: vec![client_id.to_string()],
+            message: applesauce::ServerMessage {
+                inner: Some(inner),
+                special_fields: default(),
+            },
+        })
+        .unwrap();
+    }
+}
+
+#[derive(Resource, Deref)]
+struct InputReceiver(Receiver<ConnectionInput>);
+
+/// An input along with the client id of the connection it arrived on
+#[derive(Clone)]
+struct ConnectionInput {
+    client_id: String,
+    input: applesauce::Input,
+}
+
+#[derive(Resource, Deref)]
+struct RoomRequestReceiver(Receiver<ConnectionRoomRequest>);
+
+/// A request to list, create or join rooms, along with the client id of the
+/// connection it arrived on
+#[derive(Clone)]
+struct ConnectionRoomRequest {
+    client_id: String,
+    request: RoomRequest,
+}
+
+#[derive(Clone)]
+enum RoomRequest {
+    List,
+    Create(applesauce::CreateRoom),
+    Join(applesauce::JoinRoom),
+}
+
+#[derive(Resource, Deref)]
+struct AdminRequestReceiver(Receiver<AdminRequest>);
+
+#[derive(Resource, Deref)]
+struct DiscoveryQueries(DiscoveryListener);
+
+#[derive(Resource, Deref)]
+struct PongReceiver(Receiver<(String, applesauce::Pong)>);
+
+/// Chat messages along with the client id of the connection they arrived on
+#[derive(Resource, Deref)]
+struct ChatReceiver(Receiver<(String, applesauce::ChatMessage)>);
+
+/// When each client sent its latest chat messages, keyed by client id
+#[derive(Resource, Default, Deref, DerefMut)]
+struct RecentChatMessages(HashMap<String, VecDeque<Duration>>);
+
+/// The smoothed round trip time to every connected client in milliseconds, keyed by client id
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct RoundTripTimes(HashMap<String, f32>);
+
+#[derive(Resource, Deref, DerefMut)]
+struct PingTimer(Timer);
 
 #[derive(Resource, Deref)]
-struct GameStateSender(Sender<applesauce::GameState>);
+struct HandshakeReceiver(Receiver<Handshake>);
+
+/// A client that sent an acceptable Hello and is waiting to be welcomed
+struct Handshake {
+    hello: applesauce::Hello,
+    connection_id: String,
+    /// Where to write messages for this client
+    stream: TcpStream,
+    /// Where to send the client id the connection was given
+    reply: Sender<String>,
+}
 
+/// Receives every connection that was closed or errored out. The same
+/// connection may be reported more than once, since both the read and the
+/// write side of a connection report it.
 #[derive(Resource, Deref)]
-struct InputReceiver(Receiver<applesauce::Input>);
+struct DisconnectReceiver(Receiver<Disconnect>);
 
+struct Disconnect {
+    client_id: String,
+    /// Tells a dropped connection apart from the one that replaced it when the client resumed
+    connection_id: String,
+}
+
+/// Hands the broadcast thread the stream of every connection that was welcomed
 #[derive(Resource, Deref)]
-struct IdentityReceiver(Receiver<applesauce::Identity>);
+struct StreamSender(Sender<(String, String, TcpStream)>);
+
+/// Every client currently connected to this server, keyed by client id
+#[derive(Resource, Default, Deref, DerefMut)]
+pub(crate) struct ClientSessions(HashMap<String, ClientSession>);
+
+pub(crate) struct ClientSession {
+    display_name: String,
+    /// The room this client is playing in
+    room_id: String,
+    resume_token: String,
+    /// The connection we currently talk to this client through
+    connection_id: String,
+    /// When the connection was lost, in server time. The player stays in its
+    /// room until the resume grace period runs out.
+    disconnected_at: Option<Duration>,
+    /// Spectators watch the room they are in without a player of their own
+    spectator: bool,
+}
 
-fn serve(mut commands: Commands, config: Res<ServerConfig>) {
+/// Every open room, keyed by room id
+#[derive(Resource, Deref, DerefMut)]
+struct Rooms(HashMap<String, Room>);
+
+struct Room {
+    name: String,
+    /// Private rooms are not listed and can only be joined with this code
+    join_code: Option<String>,
+    inbox: Sender<RoomMessage>,
+    outbox: Receiver<applesauce::server_message::Inner>,
+    /// The latest roster the room sent, for clients that come in after it
+    roster: applesauce::Roster,
+    /// Likewise for how the rounds are going
+    round_status: applesauce::RoundStatus,
+    /// And for the cards everyone has picked
+    picked_cards: applesauce::PickedCards,
+    /// And for the level it is playing on
+    level: applesauce::Level,
+}
+
+/// The apps running every room but the lobby, keyed by room id. Each one has
+/// its own world, so nothing leaks between rooms.
+#[derive(Default, Deref, DerefMut)]
+struct RoomApps(HashMap<String, App>);
+
+/// The ends of the channels a connection thread uses to talk to the rest of the server
+#[derive(Clone)]
+struct ConnectionChannels {
+    /// How every message a client sends us gets mistreated on its way
+    upstream: LinkConditions,
+    tx_handshake: Sender<Handshake>,
+    tx_input: Sender<ConnectionInput>,
+    tx_room_request: Sender<ConnectionRoomRequest>,
+    tx_pong: Sender<(String, applesauce::Pong)>,
+    tx_chat: Sender<(String, applesauce::ChatMessage)>,
+    tx_disconnect: Sender<Disconnect>,
+}
+
+fn serve(mut commands: Commands, config: Res<ServerConfig>, conditions: Res<NetworkConditions>) {
     let listener = TcpListener::bind(config.hostname.clone()).unwrap();
 
-    let (tx_game_state, rx_game_state) = crossbeam_channel::unbounded::<applesauce::GameState>();
-    commands.insert_resource(GameStateSender(tx_game_state));
+    // snapshots and input states are superseded by the next one, so they are
+    // the messages we could afford to send over an unreliable transport
+    let (tx_outgoing, rx_outgoing) = crossbeam_channel::unbounded::<Outgoing>();
+    let rx_outgoing = conditioner::condition(rx_outgoing, conditions.downstream, |outgoing| {
+        matches!(
+            outgoing.message.inner,
+            Some(applesauce::server_message::Inner::GameState(_))
+        )
+    });
+    commands.insert_resource(OutgoingSender(tx_outgoing));
 
-    let (tx_input, rx_input) = crossbeam_channel::unbounded::<applesauce::Input>();
+    let (tx_input, rx_input) = crossbeam_channel::unbounded::<ConnectionInput>();
     commands.insert_resource(InputReceiver(rx_input));
 
-    let (tx_identity, rx_identity) = crossbeam_channel::unbounded::<applesauce::Identity>();
-    commands.insert_resource(IdentityReceiver(rx_identity));
+    let (tx_room_request, rx_room_request) =
+        crossbeam_channel::unbounded::<ConnectionRoomRequest>();
+    commands.insert_resource(RoomRequestReceiver(rx_room_request));
 
-    let (tx_stream, rx_stream) = crossbeam_channel::unbounded::<TcpStream>();
+    let (tx_pong, rx_pong) = crossbeam_channel::unbounded::<(String, applesauce::Pong)>();
+    commands.insert_resource(PongReceiver(rx_pong));
+    commands.insert_resource(PingTimer(Timer::new(PING_INTERVAL, TimerMode::Repeating)));
 
-    thread::spawn(move || {
-        for stream in listener.incoming() {
-            let mut stream = stream.unwrap();
+    let (tx_chat, rx_chat) = crossbeam_channel::unbounded::<(String, applesauce::ChatMessage)>();
+    commands.insert_resource(ChatReceiver(rx_chat));
 
-            let recv_stream = stream.try_clone().unwrap();
-            let tx_input = tx_input.clone();
+    let (tx_handshake, rx_handshake) = crossbeam_channel::unbounded::<Handshake>();
+    commands.insert_resource(HandshakeReceiver(rx_handshake));
 
-            tx_stream.send(stream.try_clone().unwrap()).unwrap();
-            thread::spawn(move || read_network_input_events(recv_stream, tx_input));
+    let (tx_disconnect, rx_disconnect) = crossbeam_channel::unbounded::<Disconnect>();
+    commands.insert_resource(DisconnectReceiver(rx_disconnect));
 
-            let identity = applesauce::Identity {
-                client_id: Uuid::new_v4().to_string(),
-                ..Default::default()
-            };
+    let (tx_stream, rx_stream) = crossbeam_channel::unbounded::<(String, String, TcpStream)>();
+    commands.insert_resource(StreamSender(tx_stream));
 
-            tx_identity.send(identity.clone()).unwrap();
+    let channels = ConnectionChannels {
+        upstream: conditions.upstream,
+        tx_handshake,
+        tx_input,
+        tx_room_request,
+        tx_pong,
+        tx_chat,
+        tx_disconnect: tx_disconnect.clone(),
+    };
 
-            identity
-                .write_length_delimited_to_writer(&mut stream)
-                .unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let channels = channels.clone();
+                    thread::spawn(move || handle_connection(stream, channels));
+                }
+                Err(e) => println!("Failed to accept connection: {}", e),
+            };
         }
     });
 
     thread::spawn(move || {
-        let mut streams: Vec<TcpStream> = vec![];
+        // the connection id and stream of every client, keyed by client id
+        let mut streams: HashMap<String, (String, TcpStream)> = HashMap::new();
 
-        for game_state in rx_game_state.iter() {
-            for stream in rx_stream.try_iter() {
-                streams.push(stream);
+        for Outgoing {
+            client_ids,
+            message,
+        } in rx_outgoing.iter()
+        {
+            for (client_id, connection_id, stream) in rx_stream.try_iter() {
+                // a resuming client may show up before we noticed its old
+                // connection was gone
+                if let Some((_, old_stream)) = streams.insert(client_id, (connection_id, stream)) {
+                    old_stream.shutdown(Shutdown::Both).ok();
+                }
             }
 
-            for mut stream in streams.iter() {
-                game_state
-                    .write_length_delimited_to_writer(&mut stream)
-                    .unwrap();
+            for client_id in client_ids {
+                let (connection_id, stream) = match streams.get_mut(&client_id) {
+                    None => continue,
+                    Some(stream) => stream,
+                };
+
+                if let Err(e) = message.write_length_delimited_to_writer(stream) {
+                    println!("Dropping connection to client {}: {}", client_id, e);
+                    stream.shutdown(Shutdown::Both).ok();
+                    tx_disconnect
+                        .send(Disconnect {
+                            client_id: client_id.to_string(),
+                            connection_id: connection_id.to_string(),
+                        })
+                        .ok();
+                    streams.remove(&client_id);
+                    continue;
+                }
+
+                // we are done talking to a client once we turned it away
+                if matches!(
+                    message.inner,
+                    Some(applesauce::server_message::Inner::Reject(_))
+                ) {
+                    stream.shutdown(Shutdown::Both).ok();
+                    streams.remove(&client_id);
+                }
             }
         }
     });
 }
 
-fn read_network_input_events(mut stream: TcpStream, tx_input: Sender<applesauce::Input>) {
+fn handle_connection(mut stream: TcpStream, channels: ConnectionChannels) {
+    let mut send_stream = match stream.try_clone() {
+        Ok(send_stream) => send_stream,
+        Err(e) => {
+            println!("Failed to clone stream for new connection: {}", e);
+            return;
+        }
+    };
+
+    // a connection that never says Hello would otherwise hold on to this thread forever
+    if let Err(e) = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)) {
+        println!("Failed to set the handshake timeout: {}", e);
+        return;
+    }
+
     let mut coded_stream = CodedInputStream::new(&mut stream);
 
+    let hello = match read_hello(&mut coded_stream) {
+        Ok(hello) => hello,
+        Err(reason) => {
+            println!("Rejecting connection: {}", reason);
+            reject(&mut send_stream, reason);
+            return;
+        }
+    };
+
+    // both ends of the clone share the socket, and with it the timeout.
+    // Clients that are in may stay quiet as long as they like.
+    if let Err(e) = send_stream.set_read_timeout(None) {
+        println!("Failed to clear the handshake timeout: {}", e);
+        return;
+    }
+
+    // whether this is a new client or one coming back is up to the server,
+    // which lets us know which client id we are reading for
+    let connection_id = Uuid::new_v4().to_string();
+    let (tx_client_id, rx_client_id) = crossbeam_channel::bounded::<String>(1);
+    channels
+        .tx_handshake
+        .send(Handshake {
+            hello,
+            connection_id: connection_id.to_string(),
+            stream: send_stream,
+            reply: tx_client_id,
+        })
+        .unwrap();
+
+    let client_id = match rx_client_id.recv() {
+        Ok(client_id) => client_id,
+        Err(_) => return,
+    };
+
+    // everything the client sends goes through the same link, so messages
+    // of different kinds keep their order like they would on the wire
+    let (tx_message, rx_message) =
+        crossbeam_channel::unbounded::<applesauce::client_message::Inner>();
+    let rx_message = conditioner::condition(rx_message, channels.upstream, |inner| {
+        matches!(
+            inner,
+            applesauce::client_message::Inner::Input(applesauce::Input {
+                inner: Some(applesauce::input::Inner::State(_)),
+                ..
+            })
+        )
+    });
+
+    let dispatch_thread = {
+        let client_id = client_id.clone();
+        let channels = channels.clone();
+        thread::spawn(move || dispatch_client_messages(rx_message, &client_id, &channels))
+    };
+
+    read_client_messages(&mut coded_stream, &client_id, &tx_message);
+
+    // whatever is still on its way arrives before the disconnect does
+    drop(tx_message);
+    dispatch_thread.join().ok();
+
+    drop(coded_stream);
+    // make sure the broadcast thread stops writing to this client as well
+    stream.shutdown(Shutdown::Both).ok();
+    channels
+        .tx_disconnect
+        .send(Disconnect {
+            client_id,
+            connection_id,
+        })
+        .ok();
+}
+
+/// Reads the client's Hello and checks that we can talk to it
+fn read_hello(coded_stream: &mut CodedInputStream) -> Result<applesauce::Hello, String> {
+    let message: applesauce::ClientMessage = coded_stream
+        .read_message()
+        .map_err(|e| format!("Could not read handshake: {}", e))?;
+
+    let hello = match message.inner {
+        Some(applesauce::client_message::Inner::Hello(hello)) => hello,
+        _ => return Err("Expected Hello as the first message".to_string()),
+    };
+
+    if hello.protocol_version != PROTOCOL_VERSION {
+        return Err(format!(
+            "Protocol version mismatch. server: {}, client: {}",
+            PROTOCOL_VERSION, hello.protocol_version
+        ));
+    }
+
+    if hello.build_id != BUILD_ID {
+        return Err(format!(
+            "Build mismatch. server: {}, client: {}",
+            BUILD_ID, hello.build_id
+        ));
+    }
+
+    if hello
+        .display_name
+        .trim()
+        .eq_ignore_ascii_case(SERVER_DISPLAY_NAME)
+    {
+        return Err(format!(
+            "The display name {} is reserved",
+            SERVER_DISPLAY_NAME
+        ));
+    }
+
+    Ok(hello)
+}
+
+fn reject(stream: &mut TcpStream, reason: String) {
+    let message = applesauce::ServerMessage {
+        inner: Some(applesauce::server_message::Inner::Reject(
+            applesauce::Reject {
+                reason,
+                special_fields: default(),
+            },
+        )),
+        special_fields: default(),
+    };
+
+    message.write_length_delimited_to_writer(stream).ok();
+    stream.shutdown(Shutdown::Both).ok();
+}
+
+fn read_client_messages(
+    coded_stream: &mut CodedInputStream,
+    client_id: &str,
+    tx_message: &Sender<applesauce::client_message::Inner>,
+) {
     loop {
-        if coded_stream.eof().unwrap() {
-            break;
+        match coded_stream.eof() {
+            Ok(false) => {}
+            Ok(true) => break,
+            Err(e) => {
+                println!("Failed to read from client {}: {}", client_id, e);
+                break;
+            }
         }
 
-        let input: applesauce::Input = coded_stream.read_message().unwrap();
-        tx_input.send(input).unwrap();
+        let message: applesauce::ClientMessage = match coded_stream.read_message() {
+            Ok(message) => message,
+            Err(e) => {
+                println!("Failed to read message from client {}: {}", client_id, e);
+                break;
+            }
+        };
+
+        let inner = match message.inner {
+            None => continue,
+            Some(inner) => inner,
+        };
+
+        if tx_message.send(inner).is_err() {
+            break;
+        }
     }
 }
 
-fn recv_input(
-    receiver: Res<InputReceiver>,
-    mut spawn_events: EventWriter<PlayerSpawnEvent>,
-    mut move_left_events: EventWriter<PlayerMoveLeftEvent>,
-    mut move_right_events: EventWriter<PlayerMoveRightEvent>,
-    mut jump_events: EventWriter<PlayerJumpEvent>,
-    mut shoot_events: EventWriter<PlayerShootEvent>,
-    mut block_events: EventWriter<PlayerBlockEvent>,
+/// Hands every message a client sent to the system that deals with its kind
+fn dispatch_client_messages(
+    rx_message: Receiver<applesauce::client_message::Inner>,
+    client_id: &str,
+    channels: &ConnectionChannels,
 ) {
-    receiver.try_iter().for_each(|input| match input.inner {
-        Some(applesauce::input::Inner::Spawn(_)) => {
-            spawn_events.send(PlayerSpawnEvent {
-                id: input.id,
-                client_id: input.client_id,
-            });
-        }
-        Some(applesauce::input::Inner::MoveLeft(_)) => {
-            move_left_events.send(PlayerMoveLeftEvent {
-                id: input.id,
-                client_id: input.client_id,
-            });
-        }
-        Some(applesauce::input::Inner::MoveRight(_)) => {
-            move_right_events.send(PlayerMoveRightEvent {
-                id: input.id,
-                client_id: input.client_id,
-            });
-        }
-        Some(applesauce::input::Inner::Jump(_)) => {
-            jump_events.send(PlayerJumpEvent {
-                id: input.id,
-                client_id: input.client_id,
-            });
-        }
-        Some(applesauce::input::Inner::Shoot(shoot)) => {
-            shoot_events.send(PlayerShootEvent {
-                id: input.id,
-                client_id: input.client_id,
-                aim: shoot.aim.unwrap().into(),
-            });
-        }
-        Some(applesauce::input::Inner::Block(_)) => {
-            block_events.send(PlayerBlockEvent {
-                id: input.id,
-                client_id: input.client_id,
-            });
-        }
-        None => {}
-    });
+    for inner in rx_message.iter() {
+        let request = match inner {
+            applesauce::client_message::Inner::Input(input) => {
+                let input = ConnectionInput {
+                    client_id: client_id.to_string(),
+                    input,
+                };
+
+                if channels.tx_input.send(input).is_err() {
+                    break;
+                }
+                continue;
+            }
+            applesauce::client_message::Inner::ListRooms(_) => RoomRequest::List,
+            applesauce::client_message::Inner::CreateRoom(create) => RoomRequest::Create(create),
+            applesauce::client_message::Inner::JoinRoom(join) => RoomRequest::Join(join),
+            applesauce::client_message::Inner::Pong(pong) => {
+                if channels
+                    .tx_pong
+                    .send((client_id.to_string(), pong))
+                    .is_err()
+                {
+                    break;
+                }
+                continue;
+            }
+            applesauce::client_message::Inner::ChatMessage(chat_message) => {
+                if channels
+                    .tx_chat
+                    .send((client_id.to_string(), chat_message))
+                    .is_err()
+                {
+                    break;
+                }
+                continue;
+            }
+            applesauce::client_message::Inner::Hello(_) => {
+                println!("Ignoring repeated Hello from client {}", client_id);
+                continue;
+            }
+        };
+
+        let request = ConnectionRoomRequest {
+            client_id: client_id.to_string(),
+            request,
+        };
+
+        if channels.tx_room_request.send(request).is_err() {
+            break;
+        }
+    }
 }
 
-fn send_state(
-    sender: Res<GameStateSender>,
-    players: Query<(&Player, &Transform, &Velocity)>,
-    bullets: Query<(&Bullet, &Transform, &Velocity)>,
-    time: Res<Time>,
+fn recv_input(receiver: Res<InputReceiver>, sessions: Res<ClientSessions>, rooms: Res<Rooms>) {
+    receiver
+        .try_iter()
+        .for_each(|ConnectionInput { client_id, input }| {
+            // spectators only watch
+            if sessions
+                .get(&client_id)
+                .is_some_and(|session| session.spectator)
+            {
+                return;
+            }
+
+            let room = match sessions
+                .get(&client_id)
+                .and_then(|session| rooms.get(&session.room_id))
+            {
+                None => return,
+                Some(room) => room,
+            };
+
+            room.inbox
+                .send(RoomMessage::Input { client_id, input })
+                .ok();
+        });
+}
+
+fn handle_handshake(
+    receiver: Res<HandshakeReceiver>,
+    mut sessions: ResMut<ClientSessions>,
+    rooms: Res<Rooms>,
+    streams: Res<StreamSender>,
+    outgoing: Res<OutgoingSender>,
 ) {
-    sender
-        .send(applesauce::GameState {
-            timestamp: time.elapsed().as_millis() as u64,
-            players: players
-                .iter()
-                .map(|(player, transform, velocity)| applesauce::Player {
-                    id: player.id.to_string(),
-                    client_id: player.client_id.to_string(),
-                    spawn_id: player.spawn_id.to_string(),
-                    radius: player.radius,
-                    color: applesauce::Color::from(player.color).into(),
-                    position: applesauce::Vec3::from(transform.translation).into(),
-                    velocity: applesauce::Vec2::from(velocity.linvel).into(),
+    for Handshake {
+        hello,
+        connection_id,
+        stream,
+        reply,
+    } in receiver.try_iter()
+    {
+        let resumed = match hello.resume_token.as_str() {
+            "" => None,
+            token => sessions
+                .iter_mut()
+                .find(|(_, session)| session.resume_token == token),
+        };
+
+        let (identity, resumed) = match resumed {
+            Some((client_id, session)) => {
+                println!(
+                    "Client resumed: {}. client-id: {}",
+                    hello.display_name, client_id
+                );
+
+                session.connection_id = connection_id.to_string();
+                session.disconnected_at = None;
+
+                let identity = applesauce::Identity {
+                    client_id: client_id.to_string(),
+                    resume_token: session.resume_token.to_string(),
                     special_fields: default(),
-                })
-                .collect(),
-            bullets: bullets
-                .iter()
-                .map(|(bullet, transform, velocity)| applesauce::Bullet {
-                    id: bullet.id.to_string(),
-                    position: applesauce::Vec3::from(transform.translation).into(),
-                    rotation: applesauce::Quat::from(transform.rotation).into(),
-                    velocity: applesauce::Vec2::from(velocity.linvel).into(),
+                };
+
+                (identity, true)
+            }
+            None => {
+                let identity = applesauce::Identity {
+                    client_id: Uuid::new_v4().to_string(),
+                    resume_token: Uuid::new_v4().to_string(),
                     special_fields: default(),
-                })
-                .collect(),
+                };
+
+                println!(
+                    "{} connected: {}. client-id: {}",
+                    match hello.spectator {
+                        true => "Spectator",
+                        false => "Client",
+                    },
+                    hello.display_name,
+                    identity.client_id
+                );
+
+                sessions.insert(
+                    identity.client_id.to_string(),
+                    ClientSession {
+                        display_name: hello.display_name,
+                        room_id: LOBBY_ID.to_string(),
+                        resume_token: identity.resume_token.to_string(),
+                        connection_id: connection_id.to_string(),
+                        disconnected_at: None,
+                        spectator: hello.spectator,
+                    },
+                );
+
+                (identity, false)
+            }
+        };
+
+        let client_id = identity.client_id.to_string();
+
+        // the welcome must be the first message the client sees, so the
+        // stream can't be handed over any earlier than this
+        streams
+            .send((client_id.to_string(), connection_id, stream))
+            .unwrap();
+        outgoing.send_to(
+            &client_id,
+            applesauce::server_message::Inner::Welcome(applesauce::Welcome {
+                identity: protobuf::MessageField::some(identity),
+                protocol_version: PROTOCOL_VERSION,
+                build_id: BUILD_ID.to_string(),
+                special_fields: default(),
+            }),
+        );
+
+        match resumed {
+            // the player never left its room, so the client only needs to hear where it is
+            true => announce_room(
+                &client_id,
+                &sessions[&client_id].room_id,
+                &sessions,
+                &rooms,
+                &outgoing,
+            ),
+            false => enter_room(&client_id, LOBBY_ID, &sessions, &rooms, &outgoing),
+        }
+
+        reply.send(client_id).ok();
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_room_requests(
+    receiver: Res<RoomRequestReceiver>,
+    mut sessions: ResMut<ClientSessions>,
+    mut rooms: ResMut<Rooms>,
+    mut apps: NonSendMut<RoomApps>,
+    outgoing: Res<OutgoingSender>,
+    config: Res<AppConfig>,
+    fixed_time: Res<Time<Fixed>>,
+) {
+    for ConnectionRoomRequest { client_id, request } in receiver.try_iter() {
+        if !sessions.contains_key(&client_id) {
+            continue;
+        }
+
+        let room_id = match request {
+            RoomRequest::List => {
+                let rooms = rooms
+                    .iter()
+                    .filter(|(_, room)| room.join_code.is_none())
+                    .map(|(room_id, room)| room_info(room_id, room, &sessions))
+                    .collect();
+
+                outgoing.send_to(
+                    &client_id,
+                    applesauce::server_message::Inner::RoomList(applesauce::RoomList {
+                        rooms,
+                        special_fields: default(),
+                    }),
+                );
+                continue;
+            }
+            RoomRequest::Create(create) => {
+                let level = match room_level(&create.level, &config) {
+                    Ok(level) => level,
+                    Err(reason) => {
+                        outgoing.send_to(
+                            &client_id,
+                            applesauce::server_message::Inner::JoinRoomFailed(
+                                applesauce::JoinRoomFailed {
+                                    reason,
+                                    special_fields: default(),
+                                },
+                            ),
+                        );
+                        continue;
+                    }
+                };
+
+                let room_id = Uuid::new_v4().to_string();
+                let join_code = create.private.then(|| new_join_code(&rooms));
+                let (room, app) = open_room(
+                    create.name,
+                    join_code,
+                    level,
+                    &config,
+                    fixed_time.timestep(),
+                );
+
+                println!("Client {} opened room {}", client_id, room.name);
+                rooms.insert(room_id.to_string(), room);
+                apps.insert(room_id.to_string(), app);
+                room_id
+            }
+            RoomRequest::Join(join) => {
+                let room_id = match join.target {
+                    Some(applesauce::join_room::Target::RoomId(room_id)) => rooms
+                        .get(&room_id)
+                        .filter(|room| room.join_code.is_none())
+                        .map(|_| room_id),
+                    Some(applesauce::join_room::Target::JoinCode(join_code)) => rooms
+                        .iter()
+                        .find(|(_, room)| room.join_code.as_ref() == Some(&join_code))
+                        .map(|(room_id, _)| room_id.to_string()),
+                    None => None,
+                };
+
+                match room_id {
+                    None => {
+                        outgoing.send_to(
+                            &client_id,
+                            applesauce::server_message::Inner::JoinRoomFailed(
+                                applesauce::JoinRoomFailed {
+                                    reason: "There is no such room".to_string(),
+                                    special_fields: default(),
+                                },
+                            ),
+                        );
+                        continue;
+                    }
+                    Some(room_id) => room_id,
+                }
+            }
+        };
+
+        let session = sessions.get_mut(&client_id).unwrap();
+        if session.room_id != room_id {
+            if let Some(room) = rooms.get(&session.room_id) {
+                room.inbox
+                    .send(RoomMessage::Left {
+                        client_id: client_id.to_string(),
+                    })
+                    .ok();
+            }
+            session.room_id = room_id.to_string();
+        }
+
+        enter_room(&client_id, &room_id, &sessions, &rooms, &outgoing);
+    }
+}
+
+/// Adds the client to the room's simulation and tells it where it ended up
+fn enter_room(
+    client_id: &str,
+    room_id: &str,
+    sessions: &ClientSessions,
+    rooms: &Rooms,
+    outgoing: &OutgoingSender,
+) {
+    let room = match rooms.get(room_id) {
+        None => return,
+        Some(room) => room,
+    };
+
+    // spectators have no player for the room to keep track of
+    if !sessions
+        .get(client_id)
+        .is_some_and(|session| session.spectator)
+    {
+        room.inbox
+            .send(RoomMessage::Joined {
+                client_id: client_id.to_string(),
+            })
+            .ok();
+    }
+
+    announce_room(client_id, room_id, sessions, rooms, outgoing);
+}
+
+/// Tells the client which room it is playing in
+fn announce_room(
+    client_id: &str,
+    room_id: &str,
+    sessions: &ClientSessions,
+    rooms: &Rooms,
+    outgoing: &OutgoingSender,
+) {
+    let room = match rooms.get(room_id) {
+        None => return,
+        Some(room) => room,
+    };
+
+    outgoing.send_to(
+        client_id,
+        applesauce::server_message::Inner::RoomJoined(applesauce::RoomJoined {
+            room: protobuf::MessageField::some(room_info(room_id, room, sessions)),
+            join_code: room.join_code.clone().unwrap_or_default(),
             special_fields: default(),
+        }),
+    );
+
+    // the room only sends its roster when players come or go
+    outgoing.send_to(
+        client_id,
+        applesauce::server_message::Inner::Roster(room.roster.clone()),
+    );
+
+    outgoing.send_to(
+        client_id,
+        applesauce::server_message::Inner::RoundStatus(room.round_status.clone()),
+    );
+
+    outgoing.send_to(
+        client_id,
+        applesauce::server_message::Inner::PickedCards(room.picked_cards.clone()),
+    );
+
+    outgoing.send_to(
+        client_id,
+        applesauce::server_message::Inner::Level(room.level.clone()),
+    );
+}
+
+fn room_info(room_id: &str, room: &Room, sessions: &ClientSessions) -> applesauce::RoomInfo {
+    crate::events::RoomInfo {
+        id: room_id.to_string(),
+        name: room.name.to_string(),
+        player_count: sessions
+            .values()
+            .filter(|session| session.room_id == room_id && !session.spectator)
+            .count() as u32,
+    }
+    .into()
+}
+
+/// A join code no other room has. They are short enough to read out loud,
+/// so two rooms getting the same one is unlikely but not impossible.
+fn new_join_code(rooms: &Rooms) -> String {
+    loop {
+        let join_code = Uuid::new_v4().simple().to_string()[..6].to_uppercase();
+
+        if !rooms
+            .values()
+            .any(|room| room.join_code.as_ref() == Some(&join_code))
+        {
+            return join_code;
+        }
+    }
+}
+
+/// Where the level a client asked for is. Clients only get to pick from the
+/// levels next to the server's own, by file name, and only ones that load.
+fn room_level(name: &str, config: &AppConfig) -> Result<String, String> {
+    if name.is_empty() {
+        return Ok(config.level.to_string());
+    }
+
+    let file_name = std::path::Path::new(name).file_name();
+    if file_name != Some(std::ffi::OsStr::new(name)) {
+        return Err(format!("There is no level called {}", name));
+    }
+
+    let level = std::path::Path::new(&config.level)
+        .with_file_name(name)
+        .to_string_lossy()
+        .to_string();
+
+    match level::check_level(&level, config.width, config.height) {
+        Ok(()) => Ok(level),
+        Err(e) => Err(format!("Can't play on level {}: {}", name, e)),
+    }
+}
+
+/// Builds a room with a simulation of its own, apart from every other room
+fn open_room(
+    name: String,
+    join_code: Option<String>,
+    level: String,
+    config: &AppConfig,
+    timestep: Duration,
+) -> (Room, App) {
+    let (tx_inbox, rx_inbox) = crossbeam_channel::unbounded::<RoomMessage>();
+    let (tx_outbox, rx_outbox) =
+        crossbeam_channel::unbounded::<applesauce::server_message::Inner>();
+
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins.build().disable::<ScheduleRunnerPlugin>())
+        .add_plugins((TransformPlugin, HierarchyPlugin))
+        .insert_resource(AppConfig {
+            level,
+            ..config.clone()
+        })
+        .insert_resource(Time::<Fixed>::from_duration(timestep))
+        .insert_state(GameState::Round)
+        .add_plugins(ManageStatePlugin::with_physics(true))
+        .add_plugins(RoomPlugin::new(rx_inbox, tx_outbox));
+
+    // the server steps this app itself instead of handing it to a runner
+    app.finish();
+    app.cleanup();
+
+    let room = Room {
+        name,
+        join_code,
+        inbox: tx_inbox,
+        outbox: rx_outbox,
+        roster: default(),
+        round_status: default(),
+        picked_cards: default(),
+        level: default(),
+    };
+
+    (room, app)
+}
+
+/// Advances every room that runs in an app of its own. The lobby advances
+/// along with the server's own world.
+fn update_rooms(mut apps: NonSendMut<RoomApps>) {
+    for app in apps.values_mut() {
+        app.update();
+    }
+}
+
+/// Sends what each room has to say to the clients playing in it
+fn route_room_messages(
+    mut rooms: ResMut<Rooms>,
+    sessions: Res<ClientSessions>,
+    round_trip_times: Res<RoundTripTimes>,
+    outgoing: Res<OutgoingSender>,
+    app_config: Res<AppConfig>,
+) {
+    for (room_id, room) in rooms.iter_mut() {
+        let client_ids: Vec<String> = sessions
+            .iter()
+            .filter(|(_, session)| session.room_id == *room_id)
+            .map(|(client_id, _)| client_id.to_string())
+            .collect();
+
+        let display_name = |client_id: &str| match sessions.get(client_id) {
+            Some(session) => session.display_name.to_string(),
+            // only the player on the server itself has no session
+            None if client_id == app_config.client_id => app_config.display_name.to_string(),
+            None => default(),
+        };
+
+        for mut inner in room.outbox.try_iter() {
+            match &mut inner {
+                // the roster tells which player is whose
+                applesauce::server_message::Inner::GameState(game_state) => {
+                    game_state.round_trip_times = room
+                        .roster
+                        .players
+                        .iter()
+                        .filter_map(|entry| {
+                            let rtt = round_trip_times.get(&entry.client_id)?;
+                            Some((entry.id, rtt.round() as u32))
+                        })
+                        .collect();
+                }
+                applesauce::server_message::Inner::Roster(roster) => {
+                    for entry in roster.players.iter_mut() {
+                        entry.display_name = display_name(&entry.client_id);
+                    }
+
+                    room.roster = roster.clone();
+                }
+                applesauce::server_message::Inner::RoundStatus(round_status) => {
+                    for standing in round_status.standings.iter_mut() {
+                        standing.display_name = display_name(&standing.client_id);
+                    }
+
+                    room.round_status = round_status.clone();
+                }
+                applesauce::server_message::Inner::PickedCards(picked_cards) => {
+                    room.picked_cards = picked_cards.clone();
+                }
+                applesauce::server_message::Inner::Level(level) => {
+                    room.level = level.clone();
+                }
+                _ => {}
+            }
+
+            outgoing
+                .send(Outgoing {
+                    client_ids: client_ids.clone(),
+                    message: applesauce::ServerMessage {
+                        inner: Some(inner),
+                        special_fields: default(),
+                    },
+                })
+                .unwrap();
+        }
+    }
+}
+
+fn handle_disconnect(
+    receiver: Res<DisconnectReceiver>,
+    mut sessions: ResMut<ClientSessions>,
+    rooms: Res<Rooms>,
+    time: Res<Time>,
+) {
+    for Disconnect {
+        client_id,
+        connection_id,
+    } in receiver.try_iter()
+    {
+        let session = match sessions.get_mut(&client_id) {
+            None => continue,
+            Some(session) => session,
+        };
+
+        // the client already came back on another connection, or we heard
+        // about this one before
+        if session.connection_id != connection_id || session.disconnected_at.is_some() {
+            continue;
+        }
+
+        println!(
+            "Client disconnected: {}. client-id: {}. Holding on to its player in case it comes back",
+            session.display_name, client_id
+        );
+        session.disconnected_at = Some(time.elapsed());
+
+        if let Some(room) = rooms.get(&session.room_id) {
+            room.inbox.send(RoomMessage::Suspended { client_id }).ok();
+        }
+    }
+}
+
+/// Lets go of the players of clients that didn't come back in time
+fn expire_suspended_sessions(
+    mut sessions: ResMut<ClientSessions>,
+    mut round_trip_times: ResMut<RoundTripTimes>,
+    mut recent_chat_messages: ResMut<RecentChatMessages>,
+    rooms: Res<Rooms>,
+    config: Res<AppConfig>,
+    time: Res<Time>,
+) {
+    let grace_period = Duration::from_millis(config.resume_grace_period);
+
+    sessions.retain(|client_id, session| {
+        let expired = session
+            .disconnected_at
+            .is_some_and(|disconnected_at| time.elapsed() - disconnected_at > grace_period);
+
+        if expired {
+            println!(
+                "Client did not come back: {}. client-id: {}",
+                session.display_name, client_id
+            );
+            round_trip_times.remove(client_id);
+            recent_chat_messages.remove(client_id);
+
+            if let Some(room) = rooms.get(&session.room_id) {
+                room.inbox
+                    .send(RoomMessage::Left {
+                        client_id: client_id.to_string(),
+                    })
+                    .ok();
+            }
+        }
+
+        !expired
+    });
+}
+
+/// Shuts down every room but the lobby once the last client has left it
+fn close_empty_rooms(
+    mut rooms: ResMut<Rooms>,
+    mut apps: NonSendMut<RoomApps>,
+    sessions: Res<ClientSessions>,
+) {
+    rooms.retain(|room_id, room| {
+        let keep =
+            room_id == LOBBY_ID || sessions.values().any(|session| session.room_id == *room_id);
+
+        if !keep {
+            println!("Closing empty room {}", room.name);
+            apps.remove(room_id);
+        }
+
+        keep
+    });
+}
+
+fn send_pings(
+    mut timer: ResMut<PingTimer>,
+    sessions: Res<ClientSessions>,
+    outgoing: Res<OutgoingSender>,
+    time: Res<Time<Real>>,
+) {
+    if !timer.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    let client_ids = sessions
+        .iter()
+        .filter(|(_, session)| session.disconnected_at.is_none())
+        .map(|(client_id, _)| client_id.to_string())
+        .collect();
+
+    outgoing
+        .send(Outgoing {
+            client_ids,
+            message: applesauce::ServerMessage {
+                inner: Some(applesauce::server_message::Inner::Ping(applesauce::Ping {
+                    sent_at: time.elapsed().as_millis() as u64,
+                    special_fields: default(),
+                })),
+                special_fields: default(),
+            },
         })
         .unwrap();
 }
 
-fn handle_identity(mut commands: Commands, receiver: Res<IdentityReceiver>) {
-    receiver.try_iter().for_each(|identity| {
-        commands.spawn(crate::Player {
-            client_id: identity.client_id,
+fn recv_pongs(
+    receiver: Res<PongReceiver>,
+    sessions: Res<ClientSessions>,
+    rooms: Res<Rooms>,
+    mut round_trip_times: ResMut<RoundTripTimes>,
+    time: Res<Time<Real>>,
+) {
+    let now = time.elapsed().as_millis() as u64;
+
+    for (client_id, pong) in receiver.try_iter() {
+        let session = match sessions.get(&client_id) {
+            None => continue,
+            Some(session) => session,
+        };
+
+        let sample = now.saturating_sub(pong.sent_at) as f32;
+
+        // smoothed the same way TCP smooths its round trip time
+        let rtt = *round_trip_times
+            .entry(client_id.to_string())
+            .and_modify(|rtt| *rtt += (sample - *rtt) / 8.)
+            .or_insert(sample);
+
+        // the room needs it to see the world the way the client saw it when shooting
+        if let Some(room) = rooms.get(&session.room_id) {
+            room.inbox
+                .send(RoomMessage::RoundTripTime {
+                    client_id,
+                    rtt: Duration::from_millis(rtt as u64),
+                })
+                .ok();
+        }
+    }
+}
+
+/// Passes chat messages on to everyone in the sender's room, unless the sender
+/// has been talking too much lately
+fn broadcast_chat_messages(
+    receiver: Res<ChatReceiver>,
+    sessions: Res<ClientSessions>,
+    mut recent: ResMut<RecentChatMessages>,
+    outgoing: Res<OutgoingSender>,
+    time: Res<Time<Real>>,
+) {
+    let now = time.elapsed();
+
+    for (client_id, chat_message) in receiver.try_iter() {
+        let session = match sessions.get(&client_id) {
+            None => continue,
+            Some(session) => session,
+        };
+
+        let text: String = chat_message
+            .text
+            .trim()
+            .chars()
+            .filter(|c| !c.is_control())
+            .take(MAX_CHAT_LENGTH)
+            .collect();
+        if text.is_empty() {
+            continue;
+        }
+
+        let sent = recent.entry(client_id.to_string()).or_default();
+        while sent
+            .front()
+            .is_some_and(|sent_at| now.saturating_sub(*sent_at) > CHAT_RATE_WINDOW)
+        {
+            sent.pop_front();
+        }
+
+        if sent.len() >= CHAT_RATE_LIMIT {
+            outgoing.send_to(
+                &client_id,
+                applesauce::server_message::Inner::ChatMessage(applesauce::ChatMessage {
+                    display_name: SERVER_DISPLAY_NAME.to_string(),
+                    text: "You are sending messages too fast".to_string(),
+                    notice: true,
+                    ..default()
+                }),
+            );
+            continue;
+        }
+        sent.push_back(now);
+
+        let client_ids: Vec<String> = sessions
+            .iter()
+            .filter(|(_, other)| other.room_id == session.room_id)
+            .map(|(client_id, _)| client_id.to_string())
+            .collect();
+
+        outgoing
+            .send(Outgoing {
+                client_ids,
+                message: applesauce::ServerMessage {
+                    inner: Some(applesauce::server_message::Inner::ChatMessage(
+                        applesauce::ChatMessage {
+                            client_id: client_id.to_string(),
+                            display_name: session.display_name.to_string(),
+                            text,
+                            notice: false,
+                            special_fields: default(),
+                        },
+                    )),
+                    special_fields: default(),
+                },
+            })
+            .unwrap();
+    }
+}
+
+fn listen_for_discovery_queries(mut commands: Commands) {
+    match DiscoveryListener::bind() {
+        Ok(listener) => commands.insert_resource(DiscoveryQueries(listener)),
+        Err(e) => println!(
+            "Not answering LAN discovery queries on port {}: {}",
+            DISCOVERY_PORT, e
+        ),
+    }
+}
+
+/// Tells clients looking for servers on the LAN who we are and how full the lobby is
+fn answer_discovery_queries(
+    queries: Option<Res<DiscoveryQueries>>,
+    config: Res<ServerConfig>,
+    app_config: Res<AppConfig>,
+    rooms: Res<Rooms>,
+    sessions: Res<ClientSessions>,
+    spawns: Query<&PlayerSpawn>,
+) {
+    let queries = match queries {
+        None => return,
+        Some(queries) => queries,
+    };
+
+    let mut answer: Option<applesauce::DiscoveryAnswer> = None;
+
+    for from in queries.queries() {
+        let answer = answer.get_or_insert_with(|| applesauce::DiscoveryAnswer {
+            name: config.name.to_string(),
+            player_count: rooms
+                .get(LOBBY_ID)
+                .map(|lobby| room_info(LOBBY_ID, lobby, &sessions).player_count)
+                .unwrap_or_default(),
+            // the lobby runs in our own world, so its spawns are ours
+            max_players: spawns.iter().count() as u32,
+            level: app_config.level.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            port: config
+                .hostname
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addresses| addresses.next())
+                .map(|address| address.port() as u32)
+                .unwrap_or_default(),
+            server_id: config.server_id.to_string(),
+            special_fields: default(),
         });
-    })
+
+        queries.answer(from, answer);
+    }
+}
+
+fn listen_for_admin_commands(mut commands: Commands, config: Res<ServerConfig>) {
+    commands.insert_resource(AdminRequestReceiver(admin::listen(
+        config.admin_hostname.clone(),
+    )));
+}
+
+/// Answers what the host asks through the admin console or the admin port
+#[allow(clippy::too_many_arguments)]
+fn handle_admin_requests(
+    receiver: Res<AdminRequestReceiver>,
+    mut sessions: ResMut<ClientSessions>,
+    mut round_trip_times: ResMut<RoundTripTimes>,
+    mut recent_chat_messages: ResMut<RecentChatMessages>,
+    rooms: Res<Rooms>,
+    mut apps: NonSendMut<RoomApps>,
+    outgoing: Res<OutgoingSender>,
+    mut config: ResMut<AppConfig>,
+) {
+    for AdminRequest { command, reply } in receiver.try_iter() {
+        let response = match command {
+            AdminCommand::Clients => {
+                let clients: Vec<serde_json::Value> = sessions
+                    .iter()
+                    .map(|(client_id, session)| {
+                        json!({
+                            "client_id": client_id,
+                            "display_name": session.display_name,
+                            "room_id": session.room_id,
+                            "spectator": session.spectator,
+                            "connected": session.disconnected_at.is_none(),
+                            "rtt_ms": round_trip_times.get(client_id).map(|rtt| rtt.round() as u32),
+                        })
+                    })
+                    .collect();
+
+                admin::ok(json!({ "clients": clients }))
+            }
+            AdminCommand::Rooms => {
+                let rooms: Vec<serde_json::Value> = rooms
+                    .iter()
+                    .map(|(room_id, room)| {
+                        json!({
+                            "room_id": room_id,
+                            "name": room.name,
+                            "private": room.join_code.is_some(),
+                            "player_count": room_info(room_id, room, &sessions).player_count,
+                        })
+                    })
+                    .collect();
+
+                admin::ok(json!({ "rooms": rooms }))
+            }
+            AdminCommand::Config => admin::ok(json!({ "config": admin::config_to_json(&config) })),
+            AdminCommand::Kick { client_id } => match sessions.remove(&client_id) {
+                None => admin::error(format!("No client with id {}", client_id)),
+                Some(session) => {
+                    println!(
+                        "Kicking client: {}. client-id: {}",
+                        session.display_name, client_id
+                    );
+                    outgoing.send_to(
+                        &client_id,
+                        applesauce::server_message::Inner::Reject(applesauce::Reject {
+                            reason: "Kicked by the server".to_string(),
+                            special_fields: default(),
+                        }),
+                    );
+                    round_trip_times.remove(&client_id);
+                    recent_chat_messages.remove(&client_id);
+
+                    if let Some(room) = rooms.get(&session.room_id) {
+                        room.inbox
+                            .send(RoomMessage::Left {
+                                client_id: client_id.to_string(),
+                            })
+                            .ok();
+                    }
+
+                    admin::ok(json!({ "kicked": client_id }))
+                }
+            },
+            AdminCommand::Restart { room_id } => {
+                let room_id = room_id.unwrap_or(LOBBY_ID.to_string());
+                match rooms.get(&room_id) {
+                    None => admin::error(format!("No room with id {}", room_id)),
+                    Some(room) => {
+                        room.inbox.send(RoomMessage::RestartRound).ok();
+                        admin::ok(json!({ "restarted": room_id }))
+                    }
+                }
+            }
+            AdminCommand::Level { level, room_id } => {
+                let room_id = room_id.unwrap_or(LOBBY_ID.to_string());
+                match rooms.get(&room_id) {
+                    None => admin::error(format!("No room with id {}", room_id)),
+                    Some(room) => {
+                        // the room would only find out once it tries to load it
+                        match level::check_level(&level, config.width, config.height) {
+                            Err(e) => admin::error(format!("Can't load level {}: {}", level, e)),
+                            Ok(()) => {
+                                room.inbox
+                                    .send(RoomMessage::SwitchLevel {
+                                        level: level.to_string(),
+                                    })
+                                    .ok();
+                                admin::ok(json!({ "room_id": room_id, "level": level }))
+                            }
+                        }
+                    }
+                }
+            }
+            AdminCommand::Set { field, value } => {
+                match admin::set_config_field(&mut config, &field, &value) {
+                    Err(e) => admin::error(e),
+                    Ok(()) => {
+                        // every other room has a config of its own
+                        for app in apps.values_mut() {
+                            let mut room_config = app.world.resource_mut::<AppConfig>();
+                            admin::set_config_field(&mut room_config, &field, &value).ok();
+                        }
+
+                        println!("Set {} to {}", field, value);
+                        admin::ok(json!({ "config": admin::config_to_json(&config) }))
+                    }
+                }
+            }
+            AdminCommand::Help => admin::help(),
+        };
+
+        reply.send(response).ok();
+    }
 }
 
 fn assign_client_id(mut commands: Commands, mut app_config: ResMut<AppConfig>) {
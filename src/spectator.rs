@@ -0,0 +1,160 @@
+use bevy::prelude::*;
+
+use crate::{chat::not_typing, client::Roster, manage_state::Player, GameState};
+
+/// How fast the free camera moves, in pixels per second
+const FREE_CAMERA_SPEED: f32 = 600.;
+
+/// How quickly the camera catches up with the player it follows. Higher is snappier.
+const FOLLOW_STIFFNESS: f32 = 8.;
+
+/// Lets a spectator look around. Tab follows the next player, and after the
+/// last one goes back to a free camera that moves with WASD or the arrow keys.
+pub struct SpectatorPlugin;
+
+impl Plugin for SpectatorPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SpectatorCamera>()
+            .add_systems(Startup, setup)
+            .add_systems(
+                Update,
+                (cycle_followed_player, move_camera, render_spectator_label)
+                    .chain()
+                    .run_if(in_state(GameState::Round))
+                    .run_if(not_typing),
+            );
+    }
+}
+
+/// The client id of the player the camera follows. The camera is free without one.
+#[derive(Resource, Default)]
+struct SpectatorCamera {
+    following: Option<String>,
+}
+
+#[derive(Component)]
+struct SpectatorLabel;
+
+fn setup(mut commands: Commands) {
+    commands.spawn((
+        SpectatorLabel,
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font_size: 20.,
+                color: Color::WHITE,
+                ..default()
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(10.),
+            left: Val::Px(10.),
+            ..default()
+        }),
+    ));
+}
+
+fn cycle_followed_player(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut camera: ResMut<SpectatorCamera>,
+    players: Query<&Player>,
+) {
+    let mut client_ids: Vec<&str> = players.iter().map(|p| p.client_id.as_str()).collect();
+    client_ids.sort();
+
+    // the player we were following is gone, so look around on our own
+    if camera
+        .following
+        .as_ref()
+        .is_some_and(|following| !client_ids.contains(&following.as_str()))
+    {
+        camera.following = None;
+    }
+
+    if !keyboard_input.just_pressed(KeyCode::Tab) {
+        return;
+    }
+
+    let next = match &camera.following {
+        None => client_ids.first(),
+        Some(following) => client_ids
+            .iter()
+            .skip_while(|client_id| **client_id != following)
+            .nth(1),
+    };
+
+    camera.following = next.map(|client_id| client_id.to_string());
+}
+
+fn move_camera(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    camera: Res<SpectatorCamera>,
+    time: Res<Time>,
+    players: Query<(&Player, &Transform)>,
+    mut cameras: Query<&mut Transform, (With<Camera2d>, Without<Player>)>,
+) {
+    let mut camera_transform = match cameras.get_single_mut() {
+        Err(_) => return,
+        Ok(camera_transform) => camera_transform,
+    };
+
+    let followed = camera.following.as_ref().and_then(|following| {
+        players
+            .iter()
+            .find(|(p, _)| p.client_id == *following)
+            .map(|(_, transform)| transform.translation.xy())
+    });
+
+    match followed {
+        Some(target) => {
+            let position = camera_transform.translation.xy();
+            let step = (FOLLOW_STIFFNESS * time.delta_seconds()).min(1.);
+            let position = position.lerp(target, step);
+            camera_transform.translation.x = position.x;
+            camera_transform.translation.y = position.y;
+        }
+        None => {
+            let mut direction = Vec2::ZERO;
+            if keyboard_input.any_pressed([KeyCode::KeyA, KeyCode::ArrowLeft]) {
+                direction.x -= 1.;
+            }
+            if keyboard_input.any_pressed([KeyCode::KeyD, KeyCode::ArrowRight]) {
+                direction.x += 1.;
+            }
+            if keyboard_input.any_pressed([KeyCode::KeyS, KeyCode::ArrowDown]) {
+                direction.y -= 1.;
+            }
+            if keyboard_input.any_pressed([KeyCode::KeyW, KeyCode::ArrowUp]) {
+                direction.y += 1.;
+            }
+
+            let offset = direction.normalize_or_zero() * FREE_CAMERA_SPEED * time.delta_seconds();
+            camera_transform.translation += offset.extend(0.);
+        }
+    }
+}
+
+fn render_spectator_label(
+    camera: Res<SpectatorCamera>,
+    roster: Option<Res<Roster>>,
+    mut labels: Query<&mut Text, With<SpectatorLabel>>,
+) {
+    let roster_changed = roster.as_ref().is_some_and(|roster| roster.is_changed());
+    if !camera.is_changed() && !roster_changed {
+        return;
+    }
+
+    for mut text in labels.iter_mut() {
+        text.sections[0].value = match &camera.following {
+            None => "Spectating. Free camera, Tab to follow a player".to_string(),
+            Some(client_id) => format!(
+                "Spectating {}. Tab for the next player",
+                roster
+                    .as_ref()
+                    .and_then(|roster| roster.display_name(client_id))
+                    .unwrap_or(client_id)
+            ),
+        };
+    }
+}